@@ -0,0 +1,156 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, SystemTime},
+};
+
+use crate::Balance;
+
+/// How far back traded volume counts towards a user's fee tier
+const VOLUME_WINDOW: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Fee rates in basis points of what the user receives. A negative maker
+/// rate is a rebate, paid out of what the taker is charged.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FeeTier {
+    /// Trailing volume (in the market's quote currency) needed for this tier
+    pub min_volume: Balance,
+    pub maker_bps: i64,
+    pub taker_bps: i64,
+}
+
+/// A market's fee tiers, lowest `min_volume` first
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct FeeSchedule {
+    tiers: Vec<FeeTier>,
+}
+
+/// Why fee tiers can't make a schedule
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FeeError {
+    /// Takers pay between nothing and all they receive
+    TakerRate,
+    /// Makers pay no more than all they receive, and are rebated no more
+    /// than the taker pays
+    MakerRate,
+    /// Each tier needs more volume than the one before
+    TierOrder,
+}
+
+impl FeeSchedule {
+    pub fn new(tiers: Vec<FeeTier>) -> Result<Self, FeeError> {
+        for tier in &tiers {
+            if !(0..=10_000).contains(&tier.taker_bps) {
+                return Err(FeeError::TakerRate);
+            }
+            if !(-tier.taker_bps..=10_000).contains(&tier.maker_bps) {
+                return Err(FeeError::MakerRate);
+            }
+        }
+        if tiers.windows(2).any(|w| w[0].min_volume >= w[1].min_volume) {
+            return Err(FeeError::TierOrder);
+        }
+        Ok(Self { tiers })
+    }
+
+    /// The rate in basis points for a user with `trailing_volume` traded
+    pub fn rate(&self, trailing_volume: Balance, is_maker: bool) -> i64 {
+        let Some(tier) = self
+            .tiers
+            .iter()
+            .rev()
+            .find(|t| t.min_volume <= trailing_volume)
+        else {
+            return 0;
+        };
+        if is_maker {
+            tier.maker_bps
+        } else {
+            tier.taker_bps
+        }
+    }
+}
+
+/// Fee on `amount` at `bps`, rounded towards zero. Negative for rebates.
+pub fn fee(amount: Balance, bps: i64) -> i128 {
+    i128::from(amount.inner()) * i128::from(bps) / 10_000
+}
+
+/// Volume traded over the last 30 days
+#[derive(Default)]
+pub struct TrailingVolume {
+    trades: VecDeque<(SystemTime, Balance)>,
+    total: Balance,
+}
+
+impl TrailingVolume {
+    pub fn record(&mut self, now: SystemTime, volume: Balance) {
+        self.trades.push_back((now, volume));
+        self.total += volume;
+    }
+
+    pub fn total(&mut self, now: SystemTime) -> Balance {
+        while let Some(&(at, volume)) = self.trades.front() {
+            if at + VOLUME_WINDOW > now {
+                break;
+            }
+            self.total -= volume;
+            self.trades.pop_front();
+        }
+        self.total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tier(min_volume: u64, maker_bps: i64, taker_bps: i64) -> FeeTier {
+        FeeTier {
+            min_volume: Balance::new(min_volume),
+            maker_bps,
+            taker_bps,
+        }
+    }
+
+    #[test]
+    fn test_tiers() {
+        let schedule = FeeSchedule::new(vec![tier(0, 10, 20), tier(1_000_000, -2, 15)]).unwrap();
+        assert_eq!(schedule.rate(Balance::new(999_999), true), 10);
+        assert_eq!(schedule.rate(Balance::new(999_999), false), 20);
+        assert_eq!(schedule.rate(Balance::new(1_000_000), true), -2);
+        assert_eq!(FeeSchedule::default().rate(Balance::new(0), false), 0);
+
+        assert_eq!(fee(Balance::new(12_345), 20), 24);
+        assert_eq!(fee(Balance::new(12_345), -2), -2);
+    }
+
+    #[test]
+    fn test_schedule_validation() {
+        for (tiers, error) in [
+            (vec![tier(0, 10, -1)], FeeError::TakerRate),
+            (vec![tier(0, 10, 10_001)], FeeError::TakerRate),
+            (vec![tier(0, 10_001, 20)], FeeError::MakerRate),
+            // the rebate would cost more than the taker pays
+            (vec![tier(0, -21, 20)], FeeError::MakerRate),
+            (
+                vec![tier(100, 10, 20), tier(100, 5, 10)],
+                FeeError::TierOrder,
+            ),
+        ] {
+            assert_eq!(FeeSchedule::new(tiers), Err(error));
+        }
+        assert!(FeeSchedule::new(vec![tier(0, -20, 20), tier(1, 10_000, 10_000)]).is_ok());
+    }
+
+    #[test]
+    fn test_trailing_volume() {
+        let start = SystemTime::UNIX_EPOCH;
+        let day = Duration::from_secs(24 * 60 * 60);
+        let mut volume = TrailingVolume::default();
+        volume.record(start, Balance::new(100));
+        volume.record(start + day * 10, Balance::new(50));
+        assert_eq!(volume.total(start + day * 29), Balance::new(150));
+        assert_eq!(volume.total(start + day * 30), Balance::new(50));
+        assert_eq!(volume.total(start + day * 40), Balance::new(0));
+    }
+}
@@ -0,0 +1,311 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Write},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{transfers::Transfer, Balance, ClientOrderId, Currency, OrderId, Trade, UserId};
+
+/// Somewhere money can sit
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum LedgerAccount {
+    /// Outside the exchange - deposits come from here and withdrawals go back.
+    /// Its balance is minus everything users have paid in.
+    External,
+    Available(UserId),
+    /// Committed to live orders
+    Held(UserId),
+    /// Locked for withdrawals custody hasn't finished with
+    Withdrawing(UserId),
+    /// Fees collected by the exchange, less rebates paid out
+    Fees,
+}
+
+impl LedgerAccount {
+    // user funds can't be overdrawn, the exchange's own accounts can
+    fn may_go_negative(self) -> bool {
+        matches!(self, LedgerAccount::External | LedgerAccount::Fees)
+    }
+
+    pub fn user(self) -> Option<UserId> {
+        match self {
+            LedgerAccount::Available(user_id)
+            | LedgerAccount::Held(user_id)
+            | LedgerAccount::Withdrawing(user_id) => Some(user_id),
+            LedgerAccount::External | LedgerAccount::Fees => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EntryKind {
+    Deposit,
+    Withdraw,
+    Trade,
+    Fee,
+    Hold,
+    Release,
+    /// A transfer moving along without any funds moving with it
+    Transfer,
+}
+
+/// Moves `amount` out of `from` (the debit) and into `to` (the credit), so
+/// every leg balances on its own
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Leg {
+    pub currency: Currency,
+    pub from: LedgerAccount,
+    pub to: LedgerAccount,
+    pub amount: Balance,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct JournalEntry {
+    pub kind: EntryKind,
+    /// The order the entry was made for, if any
+    pub order_id: Option<OrderId>,
+    /// The client's own id for the order a `Hold` entry placed, so retries
+    /// are still spotted after a restart
+    pub client_order_id: Option<ClientOrderId>,
+    /// The trade a `Trade` entry settled, so the public record can be rebuilt
+    pub trade: Option<Trade>,
+    /// The transfer the entry was made for, as it stood afterwards, so
+    /// transfers can be rebuilt too
+    pub transfer: Option<Transfer>,
+    pub legs: Vec<Leg>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, derive_more::Display)]
+pub enum LedgerError {
+    /// Posting would leave a user account below zero
+    #[display("{account:?} would go below zero in {currency}")]
+    InsufficientFunds {
+        account: LedgerAccount,
+        currency: Currency,
+    },
+    /// The cached balances no longer match the journal
+    #[display("{account:?} doesn't match the journal in {currency}")]
+    BalanceMismatch {
+        account: LedgerAccount,
+        currency: Currency,
+    },
+    /// What users hold doesn't add up to what was deposited less what was
+    /// withdrawn
+    #[display("{currency} doesn't add up to net deposits")]
+    Unbalanced { currency: Currency },
+}
+
+/// Append-only journal of every balance movement. The journal is the source
+/// of truth - balances are cached from it as entries are posted and can be
+/// rebuilt (and checked) from scratch with `check`.
+#[derive(Default)]
+pub struct Ledger {
+    journal: Vec<JournalEntry>,
+    // signed, as the exchange's own accounts can go negative
+    balances: HashMap<(LedgerAccount, Currency), i128>,
+}
+
+impl Ledger {
+    pub fn balance(&self, account: LedgerAccount, currency: Currency) -> i128 {
+        self.balances
+            .get(&(account, currency))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn available(&self, user_id: UserId, currency: Currency) -> Balance {
+        self.user_balance(LedgerAccount::Available(user_id), currency)
+    }
+
+    pub fn held(&self, user_id: UserId, currency: Currency) -> Balance {
+        self.user_balance(LedgerAccount::Held(user_id), currency)
+    }
+
+    pub fn withdrawing(&self, user_id: UserId, currency: Currency) -> Balance {
+        self.user_balance(LedgerAccount::Withdrawing(user_id), currency)
+    }
+
+    /// Every non-zero balance held for orders, by user and currency
+    pub fn held_balances(&self) -> impl Iterator<Item = (UserId, Currency, Balance)> + '_ {
+        self.balances
+            .iter()
+            .filter_map(|(&(account, currency), &balance)| match account {
+                LedgerAccount::Held(user_id) if balance > 0 => {
+                    Some((user_id, currency, Balance::new(balance.try_into().unwrap())))
+                }
+                _ => None,
+            })
+    }
+
+    /// Every balance the user has, including empty ones, by account and
+    /// currency
+    pub fn user_balances(
+        &self,
+        user_id: UserId,
+    ) -> impl Iterator<Item = (LedgerAccount, Currency, Balance)> + '_ {
+        self.balances
+            .iter()
+            .filter(move |((account, _), _)| account.user() == Some(user_id))
+            .map(|(&(account, currency), &balance)| {
+                (account, currency, Balance::new(balance.try_into().unwrap()))
+            })
+    }
+
+    fn user_balance(&self, account: LedgerAccount, currency: Currency) -> Balance {
+        let balance = self.balance(account, currency);
+        Balance::new(balance.try_into().expect("negative user balance"))
+    }
+
+    pub fn journal(&self) -> &[JournalEntry] {
+        &self.journal
+    }
+
+    /// Record an entry, all or nothing. Fails if any user account would go
+    /// negative.
+    pub fn post(&mut self, entry: JournalEntry) -> Result<(), LedgerError> {
+        let mut changes: HashMap<(LedgerAccount, Currency), i128> = HashMap::new();
+        for leg in &entry.legs {
+            let amount = i128::from(leg.amount.inner());
+            *changes.entry((leg.from, leg.currency)).or_default() -= amount;
+            *changes.entry((leg.to, leg.currency)).or_default() += amount;
+        }
+        for (&(account, currency), change) in &changes {
+            if !account.may_go_negative() && self.balance(account, currency) + change < 0 {
+                return Err(LedgerError::InsufficientFunds { account, currency });
+            }
+        }
+        for (key, change) in changes {
+            *self.balances.entry(key).or_default() += change;
+        }
+        self.journal.push(entry);
+        Ok(())
+    }
+
+    /// Replay the journal and check it against the cached balances, and that
+    /// for each currency everything held inside the exchange equals net
+    /// external deposits
+    pub fn check(&self) -> Result<(), LedgerError> {
+        let mut replayed: HashMap<(LedgerAccount, Currency), i128> = HashMap::new();
+        let mut net_deposits: HashMap<Currency, i128> = HashMap::new();
+        for entry in &self.journal {
+            for leg in &entry.legs {
+                let amount = i128::from(leg.amount.inner());
+                *replayed.entry((leg.from, leg.currency)).or_default() -= amount;
+                *replayed.entry((leg.to, leg.currency)).or_default() += amount;
+                let net = net_deposits.entry(leg.currency).or_default();
+                match entry.kind {
+                    EntryKind::Deposit => *net += amount,
+                    EntryKind::Withdraw => *net -= amount,
+                    _ => {}
+                }
+            }
+        }
+        for &(account, currency) in self.balances.keys().chain(replayed.keys()) {
+            let balance = replayed
+                .get(&(account, currency))
+                .copied()
+                .unwrap_or_default();
+            if balance != self.balance(account, currency)
+                || (balance < 0 && !account.may_go_negative())
+            {
+                return Err(LedgerError::BalanceMismatch { account, currency });
+            }
+        }
+        let mut internal: HashMap<Currency, i128> = HashMap::new();
+        for (&(account, currency), &balance) in &replayed {
+            if account != LedgerAccount::External {
+                *internal.entry(currency).or_default() += balance;
+            }
+        }
+        for &currency in internal.keys().chain(net_deposits.keys()) {
+            let net = net_deposits.get(&currency).copied().unwrap_or_default();
+            if internal.get(&currency).copied().unwrap_or_default() != net {
+                return Err(LedgerError::Unbalanced { currency });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Append `entries` to a journal, one JSON line each
+pub fn write_journal(out: &mut impl Write, entries: &[JournalEntry]) -> io::Result<()> {
+    for entry in entries {
+        serde_json::to_writer(&mut *out, entry)?;
+        out.write_all(b"\n")?;
+    }
+    out.flush()
+}
+
+/// Read back a journal written by `write_journal`, oldest entry first
+pub fn read_journal(journal: impl BufRead) -> io::Result<Vec<JournalEntry>> {
+    journal
+        .lines()
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USD: Currency = Currency::new("USD");
+
+    fn entry(kind: EntryKind, from: LedgerAccount, to: LedgerAccount, amount: u64) -> JournalEntry {
+        JournalEntry {
+            kind,
+            order_id: None,
+            client_order_id: None,
+            trade: None,
+            transfer: None,
+            legs: vec![Leg {
+                currency: USD,
+                from,
+                to,
+                amount: Balance::new(amount),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_post_and_check() {
+        use LedgerAccount::*;
+        let (alice, bob) = (UserId::new(1), UserId::new(2));
+        let mut ledger = Ledger::default();
+        ledger
+            .post(entry(EntryKind::Deposit, External, Available(alice), 100))
+            .unwrap();
+        ledger
+            .post(entry(EntryKind::Hold, Available(alice), Held(alice), 60))
+            .unwrap();
+        ledger
+            .post(entry(EntryKind::Trade, Held(alice), Available(bob), 50))
+            .unwrap();
+        ledger
+            .post(entry(EntryKind::Withdraw, Available(bob), External, 20))
+            .unwrap();
+        assert_eq!(
+            ledger.post(entry(EntryKind::Withdraw, Available(bob), External, 31)),
+            Err(LedgerError::InsufficientFunds {
+                account: Available(bob),
+                currency: USD
+            })
+        );
+        assert_eq!(ledger.journal().len(), 4);
+        assert_eq!(ledger.available(alice, USD), Balance::new(40));
+        assert_eq!(ledger.held(alice, USD), Balance::new(10));
+        assert_eq!(ledger.available(bob, USD), Balance::new(30));
+        assert_eq!(ledger.balance(External, USD), -80);
+        assert_eq!(ledger.check(), Ok(()));
+
+        // tamper with the cache
+        *ledger.balances.get_mut(&(Held(alice), USD)).unwrap() += 1;
+        assert_eq!(
+            ledger.check(),
+            Err(LedgerError::BalanceMismatch {
+                account: Held(alice),
+                currency: USD
+            })
+        );
+    }
+}
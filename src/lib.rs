@@ -1,14 +1,29 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::Write,
+    sync::Mutex,
+    time::SystemTime,
+};
 
 use crossbeam_channel::{Receiver, Sender};
 
-use order_book::{Match, MatchType, Order};
+use fees::{FeeSchedule, TrailingVolume};
+use ledger::{write_journal, EntryKind, JournalEntry, Ledger, LedgerAccount, LedgerError, Leg};
+use order_book::{
+    BookEvent, CancelReason, Match, MatchType, Order, PostOnly, SelfTradePrevention, TimeInForce,
+};
+use transfers::{Transfer, TransferKind, TransferStatus};
 
 pub use order_book::run_orderbook_event_loop;
-pub use order_book::{OrderBook, Quote};
+pub use order_book::{Cancellation, OrderBook, Quote};
 
+mod fees;
+mod ledger;
+mod market;
+mod market_data;
 mod order_book;
 pub mod server;
+mod transfers;
 
 mod newtypes {
     use rust_decimal::Decimal;
@@ -33,6 +48,8 @@ mod newtypes {
                 derive_more::SubAssign,
                 derive_more::Constructor,
                 derive_more::Display,
+                serde::Serialize,
+                serde::Deserialize,
             )]
             pub struct $newtype(u64);
             impl std::fmt::Debug for $newtype {
@@ -62,28 +79,102 @@ mod newtypes {
     newtype!(UserId);
     newtype!(OrderId);
     newtype!(Balance);
+    // ties the outcomes of a request back to the request
+    newtype!(CorrelationId);
+    // the client's own name for an order, so retries can be spotted
+    newtype!(ClientOrderId);
+    newtype!(TransferId);
+    // public trades, numbered in the order they happened across every market
+    newtype!(TradeId);
 
-    const PRICE_SCALING_FACTOR: u32 = 1_000;
-    const VOLUME_SCALING_FACTOR: u32 = 1_000;
+    impl OrderId {
+        /// Never issued to an order - for book requests that aren't orders,
+        /// like snapshots
+        pub const NONE: OrderId = OrderId(0);
+        /// Never issued to an order - marks cancelled quotes in a level
+        pub const TOMBSTONE: OrderId = OrderId(u64::MAX);
+    }
+
+    impl Price {
+        /// What `volume` costs at this price, in the quote currency's
+        /// smallest units. `None` if that's too much to account for.
+        pub fn notional(self, volume: Volume) -> Option<Balance> {
+            self.0.checked_mul(volume.0).map(Balance)
+        }
+    }
+
+    /// Why a decimal can't be represented with a given number of decimal places
+    #[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum DecimalError {
+        Negative,
+        /// More decimal places than we keep - rounding would lose some of it
+        TooPrecise,
+        TooLarge,
+        /// Nothing, where it has to be something
+        Zero,
+    }
 
-    macro_rules! from_decimal {
-        ($typ: ty, $factor: expr) => {
-            impl TryFrom<rust_decimal::Decimal> for $typ {
-                type Error = ();
+    // Each market decides how many decimal places its prices and volumes
+    // have, so the scaling is passed in rather than fixed per type
+    macro_rules! decimal_conversion {
+        ($typ: ty) => {
+            impl $typ {
+                pub fn from_decimal(value: Decimal, decimals: u32) -> Result<Self, DecimalError> {
+                    if value.is_sign_negative() && !value.is_zero() {
+                        return Err(DecimalError::Negative);
+                    }
+                    let value = value.normalize();
+                    if value.scale() > decimals {
+                        return Err(DecimalError::TooPrecise);
+                    }
+                    10i128
+                        .checked_pow(decimals - value.scale())
+                        .and_then(|factor| value.mantissa().checked_mul(factor))
+                        .and_then(|it| u64::try_from(it).ok())
+                        .map(Self)
+                        .ok_or(DecimalError::TooLarge)
+                }
 
-                fn try_from(mut value: rust_decimal::Decimal) -> Result<Self, Self::Error> {
-                    value *= Decimal::from($factor);
-                    let it: u64 = value.try_into().map_err(|_| ())?;
-                    Ok(Self(it))
+                pub fn to_decimal(self, decimals: u32) -> Decimal {
+                    Decimal::from_i128_with_scale(self.0.into(), decimals).normalize()
                 }
             }
         };
     }
-    from_decimal!(Price, PRICE_SCALING_FACTOR);
-    from_decimal!(Volume, VOLUME_SCALING_FACTOR);
+    decimal_conversion!(Price);
+    decimal_conversion!(Volume);
+    decimal_conversion!(Balance);
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_decimal_conversion() {
+            let d = |s: &str| s.parse::<Decimal>().unwrap();
+            assert_eq!(Price::from_decimal(d("1.25"), 2), Ok(Price(125)));
+            assert_eq!(Price::from_decimal(d("1.2500"), 2), Ok(Price(125)));
+            assert_eq!(Price::from_decimal(d("150"), 0), Ok(Price(150)));
+            assert_eq!(Volume::from_decimal(d("0.00000001"), 8), Ok(Volume(1)));
+            assert_eq!(
+                Price::from_decimal(d("1.255"), 2),
+                Err(DecimalError::TooPrecise)
+            );
+            assert_eq!(Price::from_decimal(d("-1"), 2), Err(DecimalError::Negative));
+            assert_eq!(
+                Volume::from_decimal(d("200000000000"), 8),
+                Err(DecimalError::TooLarge)
+            );
+            assert_eq!(Price(125).to_decimal(2), d("1.25"));
+            assert_eq!(Price(100).to_decimal(2).to_string(), "1");
+        }
+    }
 }
 
-pub use newtypes::{Balance, OrderId, Price, UserId, Volume};
+pub use newtypes::{
+    Balance, ClientOrderId, CorrelationId, DecimalError, OrderId, Price, TradeId, TransferId,
+    UserId, Volume,
+};
 
 #[derive(
     PartialEq,
@@ -94,12 +185,38 @@ pub use newtypes::{Balance, OrderId, Price, UserId, Volume};
     Default,
     PartialOrd,
     Ord,
+    Debug,
     derive_more::Constructor,
     derive_more::Display,
 )]
 pub struct Currency(&'static str);
 
-struct Symbol {
+impl serde::Serialize for Currency {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Currency {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // codes read back are kept for good, so each is only leaked once
+        static CODES: Mutex<BTreeSet<&'static str>> = Mutex::new(BTreeSet::new());
+        let code = String::deserialize(deserializer)?;
+        let mut codes = CODES.lock().unwrap();
+        let code = match codes.get(code.as_str()) {
+            Some(&code) => code,
+            None => {
+                let code: &'static str = Box::leak(code.into_boxed_str());
+                codes.insert(code);
+                code
+            }
+        };
+        Ok(Currency(code))
+    }
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Symbol {
     base: Currency,
     quote: Currency,
 }
@@ -107,48 +224,380 @@ struct Symbol {
 #[derive(Default)]
 struct Accounts {
     accounts: HashMap<UserId, UserAccount>,
-    live_orders: HashMap<OrderId, (UserId, AccountOrder)>,
+    live_orders: HashMap<OrderId, LiveOrder>,
+    ledger: Ledger,
+    // markets without one are free to trade
+    fee_schedules: HashMap<Symbol, FeeSchedule>,
+    order_ids: OrderIds,
+    // orders placed with a client order id, so retries get the same order
+    client_orders: HashMap<(UserId, ClientOrderId), OrderId>,
+    transfers: HashMap<TransferId, Transfer>,
+    last_transfer_id: TransferId,
+    last_trade_id: TradeId,
+    // what couldn't be settled, for operators to sort out
+    faults: Vec<SettlementFault>,
+}
+
+impl Accounts {
+    /// Pick up where a previous run left off. The books don't survive a
+    /// restart, so nothing is live any more and whatever was still held for
+    /// orders goes back to its owner. Transfers do, so withdrawals custody
+    /// hasn't finished stay locked, and so do client order ids, so a retry
+    /// still gets the order it placed. Trailing volumes for fee tiers are
+    /// rebuilt from the trades that still count for them at `now`.
+    fn resume(journal: Vec<JournalEntry>, now: SystemTime) -> Result<Self, LedgerError> {
+        let mut accounts = Accounts::default();
+        for entry in journal {
+            if let Some(order_id) = entry.order_id {
+                accounts.order_ids.seen(order_id);
+                // the hold placing it says whose it was
+                let owner = entry.legs.first().and_then(|leg| leg.from.user());
+                if let Some((user_id, client_order_id)) = owner.zip(entry.client_order_id) {
+                    accounts
+                        .client_orders
+                        .insert((user_id, client_order_id), order_id);
+                }
+            }
+            if let Some(trade) = &entry.trade {
+                accounts.last_trade_id = trade.id;
+                // it settled, so it fits - and each leg is one side paying
+                // the other out of what it held
+                if let Some(notional) = trade.price.notional(trade.volume) {
+                    for user_id in entry.legs.iter().filter_map(|leg| leg.from.user()) {
+                        let acct = accounts.accounts.entry(user_id).or_default();
+                        let traded = acct.traded.entry(trade.symbol).or_default();
+                        traded.record(trade.at, notional);
+                    }
+                }
+            }
+            if let Some(transfer) = entry.transfer {
+                accounts.last_transfer_id = accounts.last_transfer_id.max(transfer.id);
+                accounts.transfers.insert(transfer.id, transfer);
+            }
+            for leg in &entry.legs {
+                for user_id in [leg.from, leg.to]
+                    .into_iter()
+                    .filter_map(LedgerAccount::user)
+                {
+                    accounts.accounts.entry(user_id).or_default();
+                }
+            }
+            accounts.ledger.post(entry)?;
+        }
+        let held: Vec<_> = accounts.ledger.held_balances().collect();
+        for (user_id, currency, amount) in held {
+            let entry = journal_entry(
+                EntryKind::Release,
+                None,
+                currency,
+                LedgerAccount::Held(user_id),
+                LedgerAccount::Available(user_id),
+                amount,
+            );
+            accounts.ledger.post(entry)?;
+        }
+        // anything older has dropped out of the window already
+        for acct in accounts.accounts.values_mut() {
+            for traded in acct.traded.values_mut() {
+                traded.total(now);
+            }
+        }
+        accounts.ledger.check()?;
+        Ok(accounts)
+    }
+}
+
+/// Something a book reported that the engine couldn't settle. Nothing moves
+/// for it, so the books and the ledger disagree until an operator sorts it
+/// out.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, derive_more::Display)]
+pub enum SettlementFault {
+    /// A fill for an order that isn't live
+    #[display("fill for unknown order {order_id}")]
+    UnknownOrder { order_id: OrderId },
+    /// Settling for the order would have broken the ledger
+    #[display("order {order_id}: {error}")]
+    Ledger {
+        order_id: OrderId,
+        error: LedgerError,
+    },
+    /// What the order pays or holds is too much to account for
+    #[display("order {order_id}: amount too large to settle")]
+    Overflow { order_id: OrderId },
+}
+
+// Post an entry for an order the books have already dealt with, so it can't
+// be rejected - record why if the ledger won't take it
+fn settle(accounts: &mut Accounts, order_id: OrderId, entry: JournalEntry) -> bool {
+    match accounts.ledger.post(entry) {
+        Ok(()) => true,
+        Err(error) => {
+            let fault = SettlementFault::Ledger { order_id, error };
+            accounts.faults.push(fault);
+            false
+        }
+    }
+}
+
+// Issues order ids - unique across every market, and increasing so they also
+// order placements. Ids are only used up by orders that are accepted, so the
+// journal always holds the last one issued.
+#[derive(Default)]
+struct OrderIds {
+    last: OrderId,
+}
+
+impl OrderIds {
+    // the id the next accepted order gets
+    fn peek(&self) -> OrderId {
+        let next = OrderId::new(self.last.inner() + 1);
+        assert_ne!(next, OrderId::TOMBSTONE, "order ids exhausted");
+        next
+    }
+
+    fn issue(&mut self) -> OrderId {
+        self.last = self.peek();
+        self.last
+    }
+
+    fn seen(&mut self, order_id: OrderId) {
+        self.last = std::cmp::max(self.last, order_id);
+    }
+}
+
+// An order the book may still report fills for
+struct LiveOrder {
+    correlation_id: CorrelationId,
+    user_id: UserId,
+    order: AccountOrder,
+    // kept for amendments, which can cross the book too
+    stp: Option<SelfTradePrevention>,
+    // what's left of the balance held when the order was placed, in the
+    // currency the order spends
+    held: Balance,
+    // base volume still to trade, for orders placed with one
+    remaining: Volume,
+    placed_at: SystemTime,
+    updated_at: SystemTime,
+}
+
+impl LiveOrder {
+    // Market orders don't rest on the book, so only limit orders and stops
+    // still waiting to trigger are open
+    fn open_order(&self) -> Option<OpenOrder> {
+        let (typ, volume) = match self.order.typ {
+            AccountOrderType::LimitBuy { price, volume, .. }
+            | AccountOrderType::LimitSell { price, volume, .. } => {
+                (OpenOrderType::Limit { price }, volume)
+            }
+            AccountOrderType::StopMarket {
+                stop_price, volume, ..
+            } => (OpenOrderType::StopMarket { stop_price }, volume),
+            AccountOrderType::StopLimit {
+                stop_price,
+                limit_price,
+                volume,
+                ..
+            } => (
+                OpenOrderType::StopLimit {
+                    stop_price,
+                    limit_price,
+                },
+                volume,
+            ),
+            _ => return None,
+        };
+        Some(OpenOrder {
+            order_id: self.order.id,
+            symbol: self.order.symbol,
+            side: self.order.typ.side(),
+            typ,
+            volume,
+            remaining_volume: self.remaining,
+            placed_at: self.placed_at,
+            updated_at: self.updated_at,
+        })
+    }
+
+    fn traded(&mut self, volume: Volume, now: SystemTime) {
+        self.remaining = Volume::new(self.remaining.inner().saturating_sub(volume.inner()));
+        self.updated_at = now;
+    }
+
+    // What this order pays the other side of a match worth `notional`
+    fn payment(&self, to: UserId, match_ev: &Match, notional: Balance) -> Leg {
+        let amount = match self.order.typ.side() {
+            Side::Buy => notional,
+            Side::Sell => Balance::new(match_ev.volume.inner()),
+        };
+        Leg {
+            currency: self.order.spend_currency(),
+            from: LedgerAccount::Held(self.user_id),
+            to: LedgerAccount::Available(to),
+            amount,
+        }
+    }
 }
 
 #[derive(Default)]
 struct UserAccount {
     live_orders: Vec<OrderId>,
-    balances: HashMap<Currency, Balance>,
+    // quote currency traded per market, for working out fee tiers
+    traded: HashMap<Symbol, TrailingVolume>,
+}
+
+// A single movement of funds
+fn journal_entry(
+    kind: EntryKind,
+    order_id: Option<OrderId>,
+    currency: Currency,
+    from: LedgerAccount,
+    to: LedgerAccount,
+    amount: Balance,
+) -> JournalEntry {
+    JournalEntry {
+        kind,
+        order_id,
+        client_order_id: None,
+        trade: None,
+        transfer: None,
+        legs: vec![Leg {
+            currency,
+            from,
+            to,
+            amount,
+        }],
+    }
 }
 
 pub struct AccountEvent {
+    correlation_id: CorrelationId,
     user_id: UserId,
     event: AccountEventType,
 }
 
 pub enum AccountEventType {
+    /// Nothing is credited until custody completes the deposit
     Deposit {
         currency: Currency,
         balance: Balance,
     },
+    /// Locks the funds until custody completes or rejects the withdrawal
     Withdraw {
         currency: Currency,
         balance: Balance,
     },
-    PlaceOrder(AccountOrder),
+    /// Custody moving a transfer along. This comes from custody rather than
+    /// a user, so the event's user is ignored.
+    UpdateTransfer {
+        transfer_id: TransferId,
+        status: TransferStatus,
+    },
+    PlaceOrder(NewOrder),
+    /// Take one of the user's orders off the book in `symbol`
+    CancelOrder {
+        symbol: Symbol,
+        order_id: OrderId,
+    },
+    /// Move one of the user's resting limit orders to `new_price` with
+    /// `new_volume` left open
+    AmendOrder {
+        symbol: Symbol,
+        order_id: OrderId,
+        new_price: Price,
+        new_volume: Volume,
+    },
+    GetBalances,
+    GetOpenOrders,
+    /// Check the ledger, and report anything that couldn't be settled. For
+    /// operators, so the event's user is ignored.
+    Audit,
+}
+
+/// An order as the user asks for it, before the engine gives it an id
+pub struct NewOrder {
+    /// Placing again with the same client order id gets back the order
+    /// placed the first time instead of a second one
+    client_order_id: Option<ClientOrderId>,
+    symbol: Symbol,
+    typ: AccountOrderType,
+    /// What to do if the order would trade with one of the user's own
+    /// resting orders. `None` lets them trade.
+    stp: Option<SelfTradePrevention>,
 }
 
 enum AccountOrderType {
-    MarketBuy { base_qty: Volume },
-    MarketSell { base_qty: Volume },
-    MarketBuyQ { quote_qty: Volume },
-    MarketSellQ { quote_qty: Volume },
-    LimitBuy { volume: Volume, price: Price },
-    LimitSell { volume: Volume, price: Price },
+    /// Holds no more than `max_quote`, what the caller expects `base_qty` to
+    /// cost (see `OrderBook::cost_to_buy`). The order is cancelled rather
+    /// than spend more.
+    MarketBuy {
+        base_qty: Volume,
+        max_quote: Balance,
+    },
+    MarketSell {
+        base_qty: Volume,
+    },
+    MarketBuyQ {
+        quote_qty: Balance,
+    },
+    /// Holds no more than `max_base`, what the caller expects selling for
+    /// `quote_qty` to take (see `OrderBook::volume_to_sell_for`)
+    MarketSellQ {
+        quote_qty: Balance,
+        max_base: Volume,
+    },
+    LimitBuy {
+        volume: Volume,
+        price: Price,
+        time_in_force: TimeInForce,
+        post_only: Option<PostOnly>,
+        display_volume: Option<Volume>,
+    },
+    LimitSell {
+        volume: Volume,
+        price: Price,
+        time_in_force: TimeInForce,
+        post_only: Option<PostOnly>,
+        display_volume: Option<Volume>,
+    },
+    /// Becomes a market order once the market trades through `stop_price`
+    /// (see `order_book::OrderType::StopMarket`). A buy holds what its
+    /// volume costs at the stop price, and buys only what that pays for.
+    StopMarket {
+        side: Side,
+        stop_price: Price,
+        volume: Volume,
+    },
+    /// Becomes a good-till-cancel limit order at `limit_price` once the
+    /// market trades through `stop_price`
+    StopLimit {
+        side: Side,
+        stop_price: Price,
+        limit_price: Price,
+        volume: Volume,
+    },
 }
 
-#[derive(PartialEq)]
-enum Side {
+#[derive(Copy, Clone, PartialEq, Eq, Debug, serde::Serialize, serde::Deserialize)]
+pub enum Side {
     Buy,
     Sell,
 }
 
 impl AccountOrderType {
+    // quote orders don't know how much they'll buy or sell up front
+    fn base_volume(&self) -> Option<Volume> {
+        match *self {
+            AccountOrderType::MarketBuy { base_qty, .. }
+            | AccountOrderType::MarketSell { base_qty } => Some(base_qty),
+            AccountOrderType::LimitBuy { volume, .. }
+            | AccountOrderType::LimitSell { volume, .. }
+            | AccountOrderType::StopMarket { volume, .. }
+            | AccountOrderType::StopLimit { volume, .. } => Some(volume),
+            AccountOrderType::MarketBuyQ { .. } | AccountOrderType::MarketSellQ { .. } => None,
+        }
+    }
+
     fn side(&self) -> Side {
         match self {
             AccountOrderType::MarketBuy { .. }
@@ -157,8 +606,57 @@ impl AccountOrderType {
             AccountOrderType::MarketSell { .. }
             | AccountOrderType::MarketSellQ { .. }
             | AccountOrderType::LimitSell { .. } => Side::Sell,
+            AccountOrderType::StopMarket { side, .. }
+            | AccountOrderType::StopLimit { side, .. } => *side,
         }
     }
+
+    // What a stop order goes into the book as once it's triggered. A buy
+    // spends no more than was `held` for it.
+    fn triggered(&self, held: Balance) -> Option<Self> {
+        let typ = match *self {
+            AccountOrderType::StopMarket {
+                side: Side::Buy,
+                volume,
+                ..
+            } => AccountOrderType::MarketBuy {
+                base_qty: volume,
+                max_quote: held,
+            },
+            AccountOrderType::StopMarket {
+                side: Side::Sell,
+                volume,
+                ..
+            } => AccountOrderType::MarketSell { base_qty: volume },
+            AccountOrderType::StopLimit {
+                side,
+                limit_price: price,
+                volume,
+                ..
+            } => {
+                let (time_in_force, post_only, display_volume) =
+                    (TimeInForce::GoodTillCancel, None, None);
+                match side {
+                    Side::Buy => AccountOrderType::LimitBuy {
+                        volume,
+                        price,
+                        time_in_force,
+                        post_only,
+                        display_volume,
+                    },
+                    Side::Sell => AccountOrderType::LimitSell {
+                        volume,
+                        price,
+                        time_in_force,
+                        post_only,
+                        display_volume,
+                    },
+                }
+            }
+            _ => return None,
+        };
+        Some(typ)
+    }
 }
 
 pub struct AccountOrder {
@@ -167,123 +665,2167 @@ pub struct AccountOrder {
     typ: AccountOrderType,
 }
 
+impl AccountOrder {
+    // what gets held while the order is live
+    fn spend_currency(&self) -> Currency {
+        match self.typ.side() {
+            Side::Buy => self.symbol.quote,
+            Side::Sell => self.symbol.base,
+        }
+    }
+}
+
+/// Everything the account engine reports back. Outcomes caused by the book
+/// (fills, cancels and triggers, and the balances they move) carry the
+/// correlation id of the order's placement.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum AccountOutcome {
+    Accepted {
+        correlation_id: CorrelationId,
+        user_id: UserId,
+        order_id: OrderId,
+    },
+    Rejected {
+        correlation_id: CorrelationId,
+        user_id: UserId,
+        reason: RejectReason,
+    },
+    /// A cancel or amendment has gone to the order's book. What comes of it
+    /// follows, under the correlation id of the order's placement.
+    Forwarded {
+        correlation_id: CorrelationId,
+        user_id: UserId,
+        order_id: OrderId,
+    },
+    Fill {
+        correlation_id: CorrelationId,
+        user_id: UserId,
+        order_id: OrderId,
+        symbol: Symbol,
+        price: Price,
+        volume: Volume,
+        maker: bool,
+        /// Nothing is left of the order to fill
+        filled: bool,
+        /// Charged in what the user received, negative for rebates
+        fee: i128,
+        fee_currency: Currency,
+    },
+    Cancelled {
+        correlation_id: CorrelationId,
+        user_id: UserId,
+        order_id: OrderId,
+        symbol: Symbol,
+        remaining_volume: Volume,
+        reason: CancelReason,
+    },
+    /// A stop order was released into the book by a trade at `last_price`
+    Triggered {
+        correlation_id: CorrelationId,
+        user_id: UserId,
+        order_id: OrderId,
+        symbol: Symbol,
+        last_price: Price,
+    },
+    /// Where the user's balance in one currency stands, after something
+    /// moved it. Follows whatever did.
+    Balance {
+        correlation_id: CorrelationId,
+        user_id: UserId,
+        balance: CurrencyBalance,
+    },
+    /// A transfer was requested or moved along, by the request with
+    /// `correlation_id`
+    Transfer {
+        correlation_id: CorrelationId,
+        user_id: UserId,
+        transfer: Transfer,
+    },
+    /// Every currency the user has had any of, in currency order
+    Balances {
+        correlation_id: CorrelationId,
+        user_id: UserId,
+        balances: Vec<CurrencyBalance>,
+    },
+    /// The user's limit orders still on a book and stop orders still waiting
+    /// to trigger, oldest first
+    OpenOrders {
+        correlation_id: CorrelationId,
+        user_id: UserId,
+        orders: Vec<OpenOrder>,
+    },
+    /// A trade for the public record. Follows the fills, and is attributed
+    /// to the taker.
+    Trade {
+        correlation_id: CorrelationId,
+        user_id: UserId,
+        trade: Trade,
+    },
+    /// Whether the ledger checks out, and everything the engine couldn't
+    /// settle since it started, oldest first
+    Audit {
+        correlation_id: CorrelationId,
+        user_id: UserId,
+        ledger: Result<(), LedgerError>,
+        faults: Vec<SettlementFault>,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CurrencyBalance {
+    pub currency: Currency,
+    pub available: Balance,
+    /// Set aside for open orders and unfinished withdrawals
+    pub held: Balance,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct OpenOrder {
+    pub order_id: OrderId,
+    pub symbol: Symbol,
+    pub side: Side,
+    pub typ: OpenOrderType,
+    pub volume: Volume,
+    pub remaining_volume: Volume,
+    pub placed_at: SystemTime,
+    /// When the order last traded, or was placed if it hasn't
+    pub updated_at: SystemTime,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OpenOrderType {
+    Limit {
+        price: Price,
+    },
+    /// Goes into the book as a market order once the market trades through
+    /// `stop_price`
+    StopMarket {
+        stop_price: Price,
+    },
+    /// Goes into the book as a limit order at `limit_price` once the market
+    /// trades through `stop_price`
+    StopLimit {
+        stop_price: Price,
+        limit_price: Price,
+    },
+}
+
+/// A trade as the market sees it - who traded is left out
+#[derive(Clone, Copy, PartialEq, Eq, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Trade {
+    pub id: TradeId,
+    pub symbol: Symbol,
+    pub price: Price,
+    pub volume: Volume,
+    /// The side of the order that took liquidity
+    pub taker_side: Side,
+    /// When the engine settled it
+    pub at: SystemTime,
+}
+
+impl AccountOutcome {
+    pub fn correlation_id(&self) -> CorrelationId {
+        match *self {
+            AccountOutcome::Accepted { correlation_id, .. }
+            | AccountOutcome::Rejected { correlation_id, .. }
+            | AccountOutcome::Forwarded { correlation_id, .. }
+            | AccountOutcome::Fill { correlation_id, .. }
+            | AccountOutcome::Cancelled { correlation_id, .. }
+            | AccountOutcome::Triggered { correlation_id, .. }
+            | AccountOutcome::Balance { correlation_id, .. }
+            | AccountOutcome::Transfer { correlation_id, .. }
+            | AccountOutcome::Balances { correlation_id, .. }
+            | AccountOutcome::OpenOrders { correlation_id, .. }
+            | AccountOutcome::Trade { correlation_id, .. }
+            | AccountOutcome::Audit { correlation_id, .. } => correlation_id,
+        }
+    }
+
+    pub fn user_id(&self) -> UserId {
+        match *self {
+            AccountOutcome::Accepted { user_id, .. }
+            | AccountOutcome::Rejected { user_id, .. }
+            | AccountOutcome::Forwarded { user_id, .. }
+            | AccountOutcome::Fill { user_id, .. }
+            | AccountOutcome::Cancelled { user_id, .. }
+            | AccountOutcome::Triggered { user_id, .. }
+            | AccountOutcome::Balance { user_id, .. }
+            | AccountOutcome::Transfer { user_id, .. }
+            | AccountOutcome::Balances { user_id, .. }
+            | AccountOutcome::OpenOrders { user_id, .. }
+            | AccountOutcome::Trade { user_id, .. }
+            | AccountOutcome::Audit { user_id, .. } => user_id,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RejectReason {
+    InsufficientBalance,
+    /// No book is running for the order's symbol
+    UnknownMarket,
+    UnknownTransfer,
+    /// The transfer can't go from its current status to the one asked for
+    InvalidTransferUpdate,
+    /// What the order would hold is too much to account for
+    NotionalTooLarge,
+    /// The user has no live order with that id in the market
+    UnknownOrder,
+    /// Only limit orders rest on the book to be amended
+    NotAmendable,
+}
+
+// Nobody listening for outcomes isn't the account engine's problem
+fn report(tx_outcome: &Sender<AccountOutcome>, outcome: AccountOutcome) {
+    let _ = tx_outcome.send(outcome);
+}
+
+/// Run the account engine in front of one book per symbol, until shut down.
+/// The engine starts from `journal`, the ledger journal of a previous run
+/// (empty to start afresh), appends what it posts to `journal_out` after each
+/// event - before reporting the event's outcomes, so nothing reported is lost
+/// in a restart - and charges fees by `fee_schedules` (markets without one
+/// are free to trade). It stops if the journal can't be written.
+/// Every book reports on the same `rx_book_events` - order ids are unique
+/// across markets, so events need no symbol. Shutdown starts when every
+/// `AccountEvent` sender has gone: the engine drops `tx_orders` so the books
+/// can work through what they already have and exit, settles everything the
+/// books report until they all have, then returns (and so closes `tx_outcome`).
 pub fn run_account_event_loop(
+    journal: Vec<JournalEntry>,
+    mut journal_out: impl Write,
+    fee_schedules: HashMap<Symbol, FeeSchedule>,
     rx_acct_event: Receiver<AccountEvent>,
-    rx_matches: Receiver<Match>,
-    tx_order: Sender<Order>,
-    tx_outcome: Sender<String>,
+    rx_book_events: Receiver<BookEvent>,
+    tx_orders: HashMap<Symbol, Sender<Order>>,
+    tx_outcome: Sender<AccountOutcome>,
+) -> std::io::Result<()> {
+    let mut written = journal.len();
+    let mut accounts =
+        Accounts::resume(journal, SystemTime::now()).expect("journal doesn't replay");
+    accounts.fee_schedules = fee_schedules;
+    let mut rx_acct_event = rx_acct_event;
+    let mut tx_orders = Some(tx_orders);
+    // outcomes wait here until what they report has been written
+    let (tx_unwritten, rx_unwritten) = crossbeam_channel::unbounded();
+    loop {
+        // including the releases resuming posted
+        let journal = accounts.ledger.journal();
+        write_journal(&mut journal_out, &journal[written..])?;
+        written = journal.len();
+        for outcome in rx_unwritten.try_iter() {
+            report(&tx_outcome, outcome);
+        }
+        crossbeam_channel::select! {
+            recv(rx_acct_event) -> msg => match msg {
+                Ok(ev) => {
+                    let tx_orders = tx_orders.as_ref().expect("account events after shutdown");
+                    handle_account_event(ev, &mut accounts, tx_orders, SystemTime::now(), &tx_unwritten)
+                }
+                Err(_) => {
+                    rx_acct_event = crossbeam_channel::never();
+                    tx_orders = None;
+                }
+            },
+            recv(rx_book_events) -> msg => match msg {
+                Ok(ev) => handle_book_event(ev, &mut accounts, SystemTime::now(), &tx_unwritten),
+                // nothing more can happen to any order
+                Err(_) => return Ok(()),
+            },
+        }
+    }
+}
+
+fn handle_book_event(
+    ev: BookEvent,
+    accounts: &mut Accounts,
+    now: SystemTime,
+    tx_outcome: &Sender<AccountOutcome>,
 ) {
-    let mut accounts = Accounts::default();
-    crossbeam_channel::select! {
-        recv(rx_acct_event) -> msg => {
-            handle_account_event(msg.unwrap(), &mut accounts, &tx_order, &tx_outcome)
+    match ev {
+        BookEvent::Match(match_ev) => handle_matched_trade(match_ev, accounts, now, tx_outcome),
+        BookEvent::Cancelled {
+            order_id,
+            remaining_volume,
+            reason,
+        } => {
+            if let Some(live) = finish_order(accounts, order_id) {
+                let outcome = AccountOutcome::Cancelled {
+                    correlation_id: live.correlation_id,
+                    user_id: live.user_id,
+                    order_id,
+                    symbol: live.order.symbol,
+                    remaining_volume,
+                    reason,
+                };
+                report(tx_outcome, outcome);
+                let currency = live.order.spend_currency();
+                report_balance(
+                    accounts,
+                    live.correlation_id,
+                    live.user_id,
+                    currency,
+                    tx_outcome,
+                );
+            }
         }
-        recv(rx_matches) -> msg => {
-            handle_matched_trade(msg.unwrap(), &mut accounts)
+        BookEvent::Decremented { order_id, volume } => {
+            release_limit_hold(accounts, order_id, volume, now);
+            if let Some(live) = accounts.live_orders.get(&order_id) {
+                let currency = live.order.spend_currency();
+                report_balance(
+                    accounts,
+                    live.correlation_id,
+                    live.user_id,
+                    currency,
+                    tx_outcome,
+                );
+            }
+        }
+        BookEvent::StopTriggered {
+            order_id,
+            last_price,
+        } => {
+            if let Some(live) = accounts.live_orders.get_mut(&order_id) {
+                // from here on it's whatever it was released into the book as
+                if let Some(typ) = live.order.typ.triggered(live.held) {
+                    live.order.typ = typ;
+                }
+                let outcome = AccountOutcome::Triggered {
+                    correlation_id: live.correlation_id,
+                    user_id: live.user_id,
+                    order_id,
+                    symbol: live.order.symbol,
+                    last_price,
+                };
+                report(tx_outcome, outcome);
+            }
+        }
+        BookEvent::Amended {
+            order_id,
+            price,
+            volume,
+            ..
+        } => rest_at(accounts, order_id, price, volume, tx_outcome),
+        BookEvent::Repriced { order_id, price } => {
+            if let Some(live) = accounts.live_orders.get(&order_id) {
+                let remaining = live.remaining;
+                rest_at(accounts, order_id, price, remaining, tx_outcome);
+            }
+        }
+        BookEvent::CancelRejected { .. } | BookEvent::AmendRejected { .. } => {}
+    }
+}
+
+fn handle_matched_trade(
+    match_ev: Match,
+    accounts: &mut Accounts,
+    now: SystemTime,
+    tx_outcome: &Sender<AccountOutcome>,
+) {
+    let (maker_done, taker_done) = match match_ev.typ {
+        MatchType::MakerFilled => (true, false),
+        MatchType::TakerFilled => (false, true),
+        MatchType::BothFilled => (true, true),
+        MatchType::NeitherFilled => (false, false),
+    };
+    let (maker_id, taker_id) = (match_ev.maker_order_id, match_ev.taker_order_id);
+    for order_id in [maker_id, taker_id] {
+        if !accounts.live_orders.contains_key(&order_id) {
+            accounts
+                .faults
+                .push(SettlementFault::UnknownOrder { order_id });
+            return;
+        }
+    }
+    let Some(notional) = match_ev.price.notional(match_ev.volume) else {
+        let fault = SettlementFault::Overflow { order_id: taker_id };
+        accounts.faults.push(fault);
+        return;
+    };
+    let (maker, taker) = (
+        &accounts.live_orders[&maker_id],
+        &accounts.live_orders[&taker_id],
+    );
+    // each side pays the other out of what it has held
+    let maker_pays = maker.payment(taker.user_id, &match_ev, notional);
+    let taker_pays = taker.payment(maker.user_id, &match_ev, notional);
+    let trade = Trade {
+        id: accounts.last_trade_id + TradeId::new(1),
+        symbol: taker.order.symbol,
+        price: match_ev.price,
+        volume: match_ev.volume,
+        taker_side: taker.order.typ.side(),
+        at: now,
+    };
+    let entry = JournalEntry {
+        kind: EntryKind::Trade,
+        order_id: Some(taker_id),
+        client_order_id: None,
+        trade: Some(trade),
+        transfer: None,
+        legs: vec![maker_pays, taker_pays],
+    };
+    if !settle(accounts, taker_id, entry) {
+        return;
+    }
+    accounts.last_trade_id = trade.id;
+    for (order_id, paid) in [(maker_id, maker_pays), (taker_id, taker_pays)] {
+        let live = accounts.live_orders.get_mut(&order_id).unwrap();
+        live.held -= paid.amount;
+        live.traded(match_ev.volume, now);
+    }
+    // fees come out of what each side received
+    for (order_id, received, is_maker) in
+        [(maker_id, taker_pays, true), (taker_id, maker_pays, false)]
+    {
+        let fee = charge_fee(accounts, order_id, notional, received, is_maker, now);
+        let live = &accounts.live_orders[&order_id];
+        let outcome = AccountOutcome::Fill {
+            correlation_id: live.correlation_id,
+            user_id: live.user_id,
+            order_id,
+            symbol: live.order.symbol,
+            price: match_ev.price,
+            volume: match_ev.volume,
+            maker: is_maker,
+            filled: if is_maker { maker_done } else { taker_done },
+            fee,
+            fee_currency: received.currency,
+        };
+        report(tx_outcome, outcome);
+    }
+    let taker = &accounts.live_orders[&taker_id];
+    let outcome = AccountOutcome::Trade {
+        correlation_id: taker.correlation_id,
+        user_id: taker.user_id,
+        trade,
+    };
+    report(tx_outcome, outcome);
+    // both sides paid in one currency and received the other
+    let parties = [maker_id, taker_id].map(|order_id| {
+        let live = &accounts.live_orders[&order_id];
+        (live.correlation_id, live.user_id)
+    });
+    if maker_done {
+        finish_order(accounts, maker_id);
+    }
+    if taker_done {
+        finish_order(accounts, taker_id);
+    }
+    let Symbol { base, quote } = trade.symbol;
+    for (correlation_id, user_id) in parties {
+        for currency in [base, quote] {
+            report_balance(accounts, correlation_id, user_id, currency, tx_outcome);
         }
     }
 }
 
-fn handle_matched_trade(match_ev: Match, accounts: &mut Accounts) {
-    let Some((maker_user_id, maker_acct_order)) =
-        accounts.live_orders.get(&match_ev.maker_order_id)
-    else {
-        todo!()
+// Charge (or rebate) the fee for one side of a fill at the user's current
+// tier, then count the fill towards their trailing volume
+fn charge_fee(
+    accounts: &mut Accounts,
+    order_id: OrderId,
+    notional: Balance,
+    received: Leg,
+    is_maker: bool,
+    now: SystemTime,
+) -> i128 {
+    let live = &accounts.live_orders[&order_id];
+    let (user_id, symbol) = (live.user_id, live.order.symbol);
+    let traded = accounts
+        .accounts
+        .get_mut(&user_id)
+        .expect("live order without an account")
+        .traded
+        .entry(symbol)
+        .or_default();
+    let bps = accounts
+        .fee_schedules
+        .get(&symbol)
+        .map_or(0, |s| s.rate(traded.total(now), is_maker));
+    traded.record(now, notional);
+    let fee = fees::fee(received.amount, bps);
+    if fee == 0 {
+        return 0;
+    }
+    // rates are capped at everything received, so this fits
+    let amount = Balance::new(fee.unsigned_abs().try_into().unwrap_or(u64::MAX));
+    let (from, to) = if fee > 0 {
+        (LedgerAccount::Available(user_id), LedgerAccount::Fees)
+    } else {
+        (LedgerAccount::Fees, LedgerAccount::Available(user_id))
     };
-    let Some((taker_user_id, taker_acct_order)) =
-        accounts.live_orders.get(&match_ev.taker_order_id)
-    else {
-        todo!()
+    let entry = journal_entry(
+        EntryKind::Fee,
+        Some(order_id),
+        received.currency,
+        from,
+        to,
+        amount,
+    );
+    // nothing is charged if it can't be
+    if settle(accounts, order_id, entry) {
+        fee
+    } else {
+        0
+    }
+}
+
+// The book is done with the order - give back whatever is still held for it.
+// Orders can be finished more than once (e.g. by self-trade prevention and
+// then expiry), the first time wins.
+fn finish_order(accounts: &mut Accounts, order_id: OrderId) -> Option<LiveOrder> {
+    let live = accounts.live_orders.remove(&order_id)?;
+    release(accounts, &live, live.held);
+    accounts
+        .accounts
+        .get_mut(&live.user_id)
+        .expect("live order without an account")
+        .live_orders
+        .retain(|&id| id != order_id);
+    Some(live)
+}
+
+// Self-trade prevention took volume off a resting order, so it needs less held.
+// Market orders keep their hold until they finish.
+fn release_limit_hold(accounts: &mut Accounts, order_id: OrderId, volume: Volume, now: SystemTime) {
+    let Some(live) = accounts.live_orders.get_mut(&order_id) else {
+        return;
     };
-    let Some(maker_acct) = accounts.accounts.get_mut(&maker_user_id) else {
-        todo!()
+    live.traded(volume, now);
+    let amount = match live.order.typ {
+        AccountOrderType::LimitBuy { price, .. } => price.notional(volume),
+        AccountOrderType::LimitSell { .. } => Some(Balance::new(volume.inner())),
+        _ => return,
     };
-    let Some(taker_acct) = accounts.accounts.get_mut(&taker_user_id) else {
-        todo!()
+    let Some(amount) = amount else {
+        accounts.faults.push(SettlementFault::Overflow { order_id });
+        return;
     };
+    let entry = journal_entry(
+        EntryKind::Release,
+        Some(order_id),
+        live.order.spend_currency(),
+        LedgerAccount::Held(live.user_id),
+        LedgerAccount::Available(live.user_id),
+        amount,
+    );
+    if settle(accounts, order_id, entry) {
+        accounts.live_orders.get_mut(&order_id).unwrap().held -= amount;
+    }
+}
 
-    let maker_side = maker_acct_order.typ.side();
-    let taker_side = taker_acct_order.typ.side();
-    assert!(maker_side != taker_side);
-    match match_ev.typ {
-        MatchType::MakerFilled => {
-            todo!()
+// A resting order has moved to `price` with `remaining` open (any fills that
+// caused come after), so hold what it needs there - more for an amended buy
+// that now costs more, less for one that was repriced below its limit
+fn rest_at(
+    accounts: &mut Accounts,
+    order_id: OrderId,
+    price: Price,
+    remaining: Volume,
+    tx_outcome: &Sender<AccountOutcome>,
+) {
+    let Some(live) = accounts.live_orders.get_mut(&order_id) else {
+        return;
+    };
+    live.remaining = remaining;
+    let needed = match &mut live.order.typ {
+        AccountOrderType::LimitBuy { price: limit, .. } => {
+            *limit = price;
+            price.notional(remaining)
+        }
+        AccountOrderType::LimitSell { price: limit, .. } => {
+            *limit = price;
+            Some(Balance::new(remaining.inner()))
         }
-        MatchType::TakerFilled => todo!(),
-        MatchType::BothFilled => todo!(),
+        // only limit orders rest
+        _ => return,
+    };
+    let Some(needed) = needed else {
+        accounts.faults.push(SettlementFault::Overflow { order_id });
+        return;
+    };
+    let (user_id, held) = (live.user_id, live.held);
+    let currency = live.order.spend_currency();
+    let (kind, from, to, amount) = if needed < held {
+        let kind = EntryKind::Release;
+        (
+            kind,
+            LedgerAccount::Held(user_id),
+            LedgerAccount::Available(user_id),
+            held - needed,
+        )
+    } else {
+        let kind = EntryKind::Hold;
+        (
+            kind,
+            LedgerAccount::Available(user_id),
+            LedgerAccount::Held(user_id),
+            needed - held,
+        )
+    };
+    if amount == Balance::new(0) {
+        return;
+    }
+    let entry = journal_entry(kind, Some(order_id), currency, from, to, amount);
+    if settle(accounts, order_id, entry) {
+        let live = accounts.live_orders.get_mut(&order_id).unwrap();
+        live.held = needed;
+        let correlation_id = live.correlation_id;
+        report_balance(accounts, correlation_id, user_id, currency, tx_outcome);
     }
 }
 
+fn release(accounts: &mut Accounts, live: &LiveOrder, amount: Balance) {
+    if amount == Balance::new(0) {
+        return;
+    }
+    let entry = journal_entry(
+        EntryKind::Release,
+        Some(live.order.id),
+        live.order.spend_currency(),
+        LedgerAccount::Held(live.user_id),
+        LedgerAccount::Available(live.user_id),
+        amount,
+    );
+    settle(accounts, live.order.id, entry);
+}
+
+fn report_balance(
+    accounts: &Accounts,
+    correlation_id: CorrelationId,
+    user_id: UserId,
+    currency: Currency,
+    tx_outcome: &Sender<AccountOutcome>,
+) {
+    let ledger = &accounts.ledger;
+    // same split as `user_balances`: anything set aside counts as held
+    let balance = CurrencyBalance {
+        currency,
+        available: ledger.available(user_id, currency),
+        held: ledger.held(user_id, currency) + ledger.withdrawing(user_id, currency),
+    };
+    let outcome = AccountOutcome::Balance {
+        correlation_id,
+        user_id,
+        balance,
+    };
+    report(tx_outcome, outcome);
+}
+
+fn user_balances(accounts: &Accounts, user_id: UserId) -> Vec<CurrencyBalance> {
+    let mut balances: BTreeMap<Currency, CurrencyBalance> = BTreeMap::new();
+    for (account, currency, amount) in accounts.ledger.user_balances(user_id) {
+        let balance = balances.entry(currency).or_insert(CurrencyBalance {
+            currency,
+            available: Balance::new(0),
+            held: Balance::new(0),
+        });
+        match account {
+            LedgerAccount::Available(_) => balance.available += amount,
+            _ => balance.held += amount,
+        }
+    }
+    balances.into_values().collect()
+}
+
+fn new_transfer(
+    accounts: &Accounts,
+    user_id: UserId,
+    kind: TransferKind,
+    currency: Currency,
+    amount: Balance,
+) -> Transfer {
+    Transfer {
+        id: accounts.last_transfer_id + TransferId::new(1),
+        user_id,
+        kind,
+        currency,
+        amount,
+        status: TransferStatus::Requested,
+    }
+}
+
+// Journal a transfer as it now stands, along with whatever funds move with it,
+// so it survives a restart
+fn record_transfer(
+    accounts: &mut Accounts,
+    transfer: Transfer,
+    movement: Option<(EntryKind, LedgerAccount, LedgerAccount)>,
+) -> Result<(), LedgerError> {
+    let mut entry = match movement {
+        Some((kind, from, to)) => {
+            journal_entry(kind, None, transfer.currency, from, to, transfer.amount)
+        }
+        None => JournalEntry {
+            kind: EntryKind::Transfer,
+            order_id: None,
+            client_order_id: None,
+            trade: None,
+            transfer: None,
+            legs: Vec::new(),
+        },
+    };
+    entry.transfer = Some(transfer);
+    accounts.ledger.post(entry)?;
+    accounts.last_transfer_id = accounts.last_transfer_id.max(transfer.id);
+    accounts.transfers.insert(transfer.id, transfer);
+    Ok(())
+}
+
+// Money only moves once custody is done with a transfer: completed deposits
+// are credited, and withdrawals either leave or become available again
+fn update_transfer(
+    accounts: &mut Accounts,
+    transfer_id: TransferId,
+    status: TransferStatus,
+) -> Result<Transfer, RejectReason> {
+    let Some(&transfer) = accounts.transfers.get(&transfer_id) else {
+        return Err(RejectReason::UnknownTransfer);
+    };
+    if !transfer.status.can_become(status) {
+        return Err(RejectReason::InvalidTransferUpdate);
+    }
+    let transfer = Transfer { status, ..transfer };
+    let user_id = transfer.user_id;
+    let movement = match (transfer.kind, status) {
+        (TransferKind::Deposit, TransferStatus::Completed) => Some((
+            EntryKind::Deposit,
+            LedgerAccount::External,
+            LedgerAccount::Available(user_id),
+        )),
+        (TransferKind::Withdrawal, TransferStatus::Completed) => Some((
+            EntryKind::Withdraw,
+            LedgerAccount::Withdrawing(user_id),
+            LedgerAccount::External,
+        )),
+        (TransferKind::Withdrawal, TransferStatus::Rejected) => Some((
+            EntryKind::Release,
+            LedgerAccount::Withdrawing(user_id),
+            LedgerAccount::Available(user_id),
+        )),
+        _ => None,
+    };
+    accounts.accounts.entry(user_id).or_default();
+    record_transfer(accounts, transfer, movement).expect("withdrawal not covered by locked funds");
+    Ok(transfer)
+}
+
 fn handle_account_event(
     ev: AccountEvent,
     accounts: &mut Accounts,
-    tx_order: &Sender<Order>,
-    tx_outcome: &Sender<String>,
+    tx_orders: &HashMap<Symbol, Sender<Order>>,
+    now: SystemTime,
+    tx_outcome: &Sender<AccountOutcome>,
 ) {
+    let (correlation_id, user_id) = (ev.correlation_id, ev.user_id);
+    let reject = |reason| {
+        let outcome = AccountOutcome::Rejected {
+            correlation_id,
+            user_id,
+            reason,
+        };
+        report(tx_outcome, outcome);
+    };
     match ev.event {
         AccountEventType::Deposit { currency, balance } => {
-            let entry = accounts.accounts.entry(ev.user_id).or_default();
-            let bal = entry.balances.entry(currency).or_default();
-            *bal += balance;
+            let transfer =
+                new_transfer(accounts, user_id, TransferKind::Deposit, currency, balance);
+            record_transfer(accounts, transfer, None).expect("no funds move yet");
+            let outcome = AccountOutcome::Transfer {
+                correlation_id,
+                user_id,
+                transfer,
+            };
+            report(tx_outcome, outcome);
         }
         AccountEventType::Withdraw { currency, balance } => {
-            let Some(acct) = accounts.accounts.get_mut(&ev.user_id) else {
-                tx_outcome.send("insufficient balance".into()).unwrap();
+            // only what isn't held for live orders can be withdrawn
+            let kind = TransferKind::Withdrawal;
+            let transfer = new_transfer(accounts, user_id, kind, currency, balance);
+            let lock = (
+                EntryKind::Hold,
+                LedgerAccount::Available(user_id),
+                LedgerAccount::Withdrawing(user_id),
+            );
+            if record_transfer(accounts, transfer, Some(lock)).is_err() {
+                reject(RejectReason::InsufficientBalance);
                 return;
+            }
+            let outcome = AccountOutcome::Transfer {
+                correlation_id,
+                user_id,
+                transfer,
             };
-            let Some(bal) = acct.balances.get_mut(&currency) else {
-                tx_outcome.send("insufficient balance".into()).unwrap();
-                return;
+            report(tx_outcome, outcome);
+            report_balance(accounts, correlation_id, user_id, currency, tx_outcome);
+        }
+        AccountEventType::UpdateTransfer {
+            transfer_id,
+            status,
+        } => match update_transfer(accounts, transfer_id, status) {
+            Ok(transfer) => {
+                let outcome = AccountOutcome::Transfer {
+                    correlation_id,
+                    user_id: transfer.user_id,
+                    transfer,
+                };
+                report(tx_outcome, outcome);
+                if matches!(
+                    transfer.status,
+                    TransferStatus::Completed | TransferStatus::Rejected
+                ) {
+                    let (user_id, currency) = (transfer.user_id, transfer.currency);
+                    report_balance(accounts, correlation_id, user_id, currency, tx_outcome);
+                }
+            }
+            Err(reason) => reject(reason),
+        },
+        AccountEventType::GetBalances => {
+            let outcome = AccountOutcome::Balances {
+                correlation_id,
+                user_id,
+                balances: user_balances(accounts, user_id),
             };
-            if *bal < balance {
-                tx_outcome.send("insufficient balance".into()).unwrap();
+            report(tx_outcome, outcome);
+        }
+        AccountEventType::GetOpenOrders => {
+            let orders = accounts
+                .accounts
+                .get(&user_id)
+                .into_iter()
+                .flat_map(|acct| &acct.live_orders)
+                .filter_map(|order_id| accounts.live_orders[order_id].open_order())
+                .collect();
+            let outcome = AccountOutcome::OpenOrders {
+                correlation_id,
+                user_id,
+                orders,
+            };
+            report(tx_outcome, outcome);
+        }
+        AccountEventType::Audit => {
+            let outcome = AccountOutcome::Audit {
+                correlation_id,
+                user_id,
+                ledger: accounts.ledger.check(),
+                faults: accounts.faults.clone(),
+            };
+            report(tx_outcome, outcome);
+        }
+        AccountEventType::CancelOrder { symbol, order_id } => {
+            let owned = accounts
+                .live_orders
+                .get(&order_id)
+                .is_some_and(|live| live.user_id == user_id && live.order.symbol == symbol);
+            if !owned {
+                reject(RejectReason::UnknownOrder);
                 return;
             }
-            *bal += balance;
-            tx_outcome.send("balance withdrawn".into()).unwrap();
+            // the book reports the cancel, which releases the hold
+            let order = Order {
+                id: order_id,
+                owner: Some(user_id),
+                stp: None,
+                typ: order_book::OrderType::Cancel { order_id },
+            };
+            let outcome = AccountOutcome::Forwarded {
+                correlation_id,
+                user_id,
+                order_id,
+            };
+            report(tx_outcome, outcome);
+            tx_orders[&symbol]
+                .send(order)
+                .expect("order book has shut down");
         }
-        AccountEventType::PlaceOrder(acct_order) => match acct_order.typ {
-            AccountOrderType::MarketBuy { base_qty } => {
-                let Some(acct) = accounts.accounts.get_mut(&ev.user_id) else {
-                    tx_outcome.send("insufficient balance".into()).unwrap();
+        AccountEventType::AmendOrder {
+            symbol,
+            order_id,
+            new_price,
+            new_volume,
+        } => {
+            let Some(live) = accounts
+                .live_orders
+                .get_mut(&order_id)
+                .filter(|live| live.user_id == user_id && live.order.symbol == symbol)
+            else {
+                reject(RejectReason::UnknownOrder);
+                return;
+            };
+            let needed = match live.order.typ {
+                AccountOrderType::LimitBuy { .. } => new_price.notional(new_volume),
+                AccountOrderType::LimitSell { .. } => Some(Balance::new(new_volume.inner())),
+                _ => {
+                    reject(RejectReason::NotAmendable);
                     return;
-                };
-                let Some(quote_bal) = acct.balances.get_mut(&acct_order.symbol.quote) else {
-                    tx_outcome.send("insufficient balance".into()).unwrap();
+                }
+            };
+            let Some(needed) = needed else {
+                reject(RejectReason::NotionalTooLarge);
+                return;
+            };
+            let stp = live.stp;
+            // Hold any more the amended order needs before the book gets to
+            // it, so it can't be spent meanwhile. `rest_at` settles up once
+            // the book says where the order ended up.
+            if needed > live.held {
+                let currency = live.order.spend_currency();
+                let hold = journal_entry(
+                    EntryKind::Hold,
+                    Some(order_id),
+                    currency,
+                    LedgerAccount::Available(user_id),
+                    LedgerAccount::Held(user_id),
+                    needed - live.held,
+                );
+                if accounts.ledger.post(hold).is_err() {
+                    reject(RejectReason::InsufficientBalance);
                     return;
-                };
-                todo!()
+                }
+                live.held = needed;
+                report_balance(accounts, correlation_id, user_id, currency, tx_outcome);
             }
-            AccountOrderType::MarketSell { base_qty } => {
-                let Some(acct) = accounts.accounts.get_mut(&ev.user_id) else {
-                    tx_outcome.send("insufficient balance".into()).unwrap();
-                    return;
-                };
-                let Some(base_bal) = acct.balances.get_mut(&acct_order.symbol.base) else {
-                    tx_outcome.send("insufficient balance".into()).unwrap();
-                    return;
+            let order = Order {
+                id: order_id,
+                owner: Some(user_id),
+                stp,
+                typ: order_book::OrderType::Amend {
+                    order_id,
+                    new_price,
+                    new_volume,
+                },
+            };
+            let outcome = AccountOutcome::Forwarded {
+                correlation_id,
+                user_id,
+                order_id,
+            };
+            report(tx_outcome, outcome);
+            tx_orders[&symbol]
+                .send(order)
+                .expect("order book has shut down");
+        }
+        AccountEventType::PlaceOrder(new_order) => {
+            let client_order = new_order.client_order_id.map(|id| (user_id, id));
+            if let Some(&order_id) = client_order.and_then(|key| accounts.client_orders.get(&key)) {
+                // a retry - it went through the first time
+                let outcome = AccountOutcome::Accepted {
+                    correlation_id,
+                    user_id,
+                    order_id,
                 };
-                // this is the easiest order type - just check we have enough of
-                // the thing we want to sell
-                if base_bal.inner() < base_qty.into() {
-                    tx_outcome.send("insufficient balance".into()).unwrap();
-                    return;
+                report(tx_outcome, outcome);
+                return;
+            }
+            let Some(tx_order) = tx_orders.get(&new_order.symbol) else {
+                reject(RejectReason::UnknownMarket);
+                return;
+            };
+            let Some(acct) = accounts.accounts.get_mut(&ev.user_id) else {
+                reject(RejectReason::InsufficientBalance);
+                return;
+            };
+            let acct_order = AccountOrder {
+                id: accounts.order_ids.peek(),
+                symbol: new_order.symbol,
+                typ: new_order.typ,
+            };
+            let currency = acct_order.spend_currency();
+            let available = accounts.ledger.available(ev.user_id, currency);
+            // Hold everything the order could possibly spend. Market orders
+            // sized in what they get back can't know that up front, so they
+            // hold up to the caller's estimate and let the book work out how
+            // far it goes.
+            let (held, typ) = match acct_order.typ {
+                AccountOrderType::MarketBuy {
+                    base_qty,
+                    max_quote,
+                } => {
+                    let held = std::cmp::min(available, max_quote);
+                    let typ = order_book::OrderType::MarketBuy {
+                        target_base_qty: base_qty,
+                        available_quote_balance: held,
+                    };
+                    (Some(held), typ)
                 }
-                *base_bal -= Balance::from(base_qty.inner());
-                acct.live_orders.push(acct_order.id);
-                let order = Order {
-                    id: acct_order.id,
-                    typ: order_book::OrderType::MarketSell { base_qty },
-                };
-                accounts
-                    .live_orders
-                    .insert(acct_order.id, (ev.user_id, acct_order));
-                tx_order.send(order);
+                AccountOrderType::MarketSell { base_qty } => (
+                    Some(Balance::new(base_qty.inner())),
+                    order_book::OrderType::MarketSell { base_qty },
+                ),
+                AccountOrderType::MarketBuyQ { quote_qty } => (
+                    Some(quote_qty),
+                    order_book::OrderType::MarketBuyQ {
+                        target_quote_balance: quote_qty,
+                    },
+                ),
+                AccountOrderType::MarketSellQ {
+                    quote_qty,
+                    max_base,
+                } => {
+                    let held = std::cmp::min(available, Balance::new(max_base.inner()));
+                    let typ = order_book::OrderType::MarketSellQ {
+                        target_quote_balance: quote_qty,
+                        available_base_qty: Volume::new(held.inner()),
+                    };
+                    (Some(held), typ)
+                }
+                AccountOrderType::LimitBuy {
+                    volume,
+                    price,
+                    time_in_force,
+                    post_only,
+                    display_volume,
+                } => (
+                    price.notional(volume),
+                    order_book::OrderType::LimitBuy {
+                        price,
+                        volume,
+                        time_in_force,
+                        post_only,
+                        display_volume,
+                    },
+                ),
+                AccountOrderType::LimitSell {
+                    volume,
+                    price,
+                    time_in_force,
+                    post_only,
+                    display_volume,
+                } => (
+                    Some(Balance::new(volume.inner())),
+                    order_book::OrderType::LimitSell {
+                        price,
+                        volume,
+                        time_in_force,
+                        post_only,
+                        display_volume,
+                    },
+                ),
+                AccountOrderType::StopMarket {
+                    side,
+                    stop_price,
+                    volume,
+                } => {
+                    let held = match side {
+                        Side::Buy => stop_price.notional(volume),
+                        Side::Sell => Some(Balance::new(volume.inner())),
+                    };
+                    let typ = order_book::OrderType::StopMarket {
+                        side,
+                        stop_price,
+                        volume,
+                        available_quote_balance: held.unwrap_or_default(),
+                    };
+                    (held, typ)
+                }
+                AccountOrderType::StopLimit {
+                    side,
+                    stop_price,
+                    limit_price,
+                    volume,
+                } => {
+                    let held = match side {
+                        Side::Buy => limit_price.notional(volume),
+                        Side::Sell => Some(Balance::new(volume.inner())),
+                    };
+                    let typ = order_book::OrderType::StopLimit {
+                        side,
+                        stop_price,
+                        limit_price,
+                        volume,
+                    };
+                    (held, typ)
+                }
+            };
+            let Some(held) = held else {
+                reject(RejectReason::NotionalTooLarge);
+                return;
+            };
+            let mut hold = journal_entry(
+                EntryKind::Hold,
+                Some(acct_order.id),
+                currency,
+                LedgerAccount::Available(ev.user_id),
+                LedgerAccount::Held(ev.user_id),
+                held,
+            );
+            hold.client_order_id = new_order.client_order_id;
+            if held == Balance::new(0) || accounts.ledger.post(hold).is_err() {
+                reject(RejectReason::InsufficientBalance);
+                return;
             }
-            AccountOrderType::LimitBuy { volume, price } => todo!(),
-            AccountOrderType::LimitSell { volume, price } => todo!(),
-            AccountOrderType::MarketBuyQ { quote_qty } => {}
-            AccountOrderType::MarketSellQ { quote_qty } => todo!(),
-        },
+            accounts.order_ids.issue();
+            if let Some(key) = client_order {
+                accounts.client_orders.insert(key, acct_order.id);
+            }
+            acct.live_orders.push(acct_order.id);
+            let order = Order {
+                id: acct_order.id,
+                owner: Some(ev.user_id),
+                stp: new_order.stp,
+                typ,
+            };
+            let order_id = acct_order.id;
+            accounts.live_orders.insert(
+                order_id,
+                LiveOrder {
+                    correlation_id,
+                    user_id,
+                    stp: new_order.stp,
+                    held,
+                    remaining: acct_order.typ.base_volume().unwrap_or_default(),
+                    placed_at: now,
+                    updated_at: now,
+                    order: acct_order,
+                },
+            );
+            let outcome = AccountOutcome::Accepted {
+                correlation_id,
+                user_id,
+                order_id,
+            };
+            report(tx_outcome, outcome);
+            report_balance(accounts, correlation_id, user_id, currency, tx_outcome);
+            tx_order.send(order).expect("order book has shut down");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GBP: Currency = Currency("GBP");
+    const USD: Currency = Currency("USD");
+
+    fn gbp_usd() -> Symbol {
+        Symbol {
+            base: GBP,
+            quote: USD,
+        }
+    }
+
+    // a single GBP/USD book
+    fn books() -> (HashMap<Symbol, Sender<Order>>, Receiver<Order>) {
+        let (tx_order, rx_order) = crossbeam_channel::unbounded();
+        (HashMap::from([(gbp_usd(), tx_order)]), rx_order)
+    }
+
+    fn deposit(user_id: u64, currency: Currency, balance: u64) -> AccountEvent {
+        AccountEvent {
+            correlation_id: CorrelationId::new(0),
+            user_id: UserId::new(user_id),
+            event: AccountEventType::Deposit {
+                currency,
+                balance: Balance::new(balance),
+            },
+        }
+    }
+
+    // custody finishing a transfer
+    fn complete(transfer_id: u64) -> AccountEvent {
+        update(transfer_id, TransferStatus::Completed)
+    }
+
+    fn update(transfer_id: u64, status: TransferStatus) -> AccountEvent {
+        AccountEvent {
+            correlation_id: CorrelationId::new(0),
+            user_id: UserId::new(0),
+            event: AccountEventType::UpdateTransfer {
+                transfer_id: TransferId::new(transfer_id),
+                status,
+            },
+        }
+    }
+
+    // Orders are given ids in the order they're accepted, so tests correlate
+    // by the id they expect the order to get
+    fn place(user_id: u64, correlation_id: u64, typ: AccountOrderType) -> AccountEvent {
+        AccountEvent {
+            correlation_id: CorrelationId::new(correlation_id),
+            user_id: UserId::new(user_id),
+            event: AccountEventType::PlaceOrder(NewOrder {
+                client_order_id: None,
+                symbol: gbp_usd(),
+                stp: None,
+                typ,
+            }),
+        }
+    }
+
+    fn accepted(correlation_id: u64, user_id: u64, order_id: u64) -> AccountOutcome {
+        AccountOutcome::Accepted {
+            correlation_id: CorrelationId::new(correlation_id),
+            user_id: UserId::new(user_id),
+            order_id: OrderId::new(order_id),
+        }
+    }
+
+    fn rejected(id: u64, user_id: u64, reason: RejectReason) -> AccountOutcome {
+        AccountOutcome::Rejected {
+            correlation_id: CorrelationId::new(id),
+            user_id: UserId::new(user_id),
+            reason,
+        }
+    }
+
+    fn balance(accounts: &Accounts, user_id: u64, currency: Currency) -> Balance {
+        accounts.ledger.available(UserId::new(user_id), currency)
+    }
+
+    fn held(accounts: &Accounts, user_id: u64, currency: Currency) -> Balance {
+        accounts.ledger.held(UserId::new(user_id), currency)
+    }
+
+    #[test]
+    fn test_settlement() {
+        let (tx_orders, rx_order) = books();
+        let (tx_outcome, rx_outcome) = crossbeam_channel::unbounded();
+        let mut accounts = Accounts::default();
+        for ev in [
+            deposit(1, GBP, 100),
+            complete(1),
+            deposit(2, USD, 10_000),
+            complete(2),
+            place(
+                1,
+                1,
+                AccountOrderType::MarketSell {
+                    base_qty: Volume::new(30),
+                },
+            ),
+            place(
+                2,
+                2,
+                AccountOrderType::MarketBuyQ {
+                    quote_qty: Balance::new(1000),
+                },
+            ),
+        ] {
+            handle_account_event(
+                ev,
+                &mut accounts,
+                &tx_orders,
+                SystemTime::UNIX_EPOCH,
+                &tx_outcome,
+            );
+        }
+        assert_eq!(rx_order.try_iter().count(), 2);
+        assert_eq!(balance(&accounts, 1, GBP), Balance::new(70));
+        assert_eq!(balance(&accounts, 2, USD), Balance::new(9000));
+
+        let fill = |volume, typ| {
+            BookEvent::Match(Match::new(
+                OrderId::new(1),
+                OrderId::new(2),
+                Price::new(40),
+                Volume::new(volume),
+                typ,
+            ))
+        };
+        handle_book_event(
+            fill(5, MatchType::NeitherFilled),
+            &mut accounts,
+            SystemTime::UNIX_EPOCH,
+            &tx_outcome,
+        );
+        assert_eq!(balance(&accounts, 1, USD), Balance::new(200));
+        assert_eq!(balance(&accounts, 2, GBP), Balance::new(5));
+
+        // the buyer is done having spent 800 - the rest of their 1000 comes back
+        handle_book_event(
+            fill(15, MatchType::TakerFilled),
+            &mut accounts,
+            SystemTime::UNIX_EPOCH,
+            &tx_outcome,
+        );
+        assert_eq!(balance(&accounts, 1, USD), Balance::new(800));
+        assert_eq!(balance(&accounts, 2, GBP), Balance::new(20));
+        assert_eq!(balance(&accounts, 2, USD), Balance::new(9200));
+        assert!(!accounts.live_orders.contains_key(&OrderId::new(2)));
+        assert!(accounts.accounts[&UserId::new(2)].live_orders.is_empty());
+
+        // the seller still has 10 held
+        assert_eq!(balance(&accounts, 1, GBP), Balance::new(70));
+        assert_eq!(
+            accounts.live_orders[&OrderId::new(1)].held,
+            Balance::new(10)
+        );
+        assert_eq!(accounts.ledger.check(), Ok(()));
+
+        // both fills made the public record, numbered in order, and the
+        // numbering carries on after a restart
+        let trades: Vec<_> = rx_outcome
+            .try_iter()
+            .filter_map(|o| match o {
+                AccountOutcome::Trade { trade, .. } => Some((trade.id, trade.volume)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            trades,
+            &[
+                (TradeId::new(1), Volume::new(5)),
+                (TradeId::new(2), Volume::new(15))
+            ]
+        );
+
+        // fills that can't be settled are left for operators rather than
+        // settled in part - here one for the finished buy, and one the
+        // seller hasn't enough held for
+        let audit = AccountEvent {
+            correlation_id: CorrelationId::new(0),
+            user_id: UserId::new(0),
+            event: AccountEventType::Audit,
+        };
+        let buy = AccountOrderType::MarketBuyQ {
+            quote_qty: Balance::new(1000),
+        };
+        handle_account_event(
+            place(2, 3, buy),
+            &mut accounts,
+            &tx_orders,
+            SystemTime::UNIX_EPOCH,
+            &tx_outcome,
+        );
+        for taker_id in [2, 3] {
+            let fill = Match::new(
+                OrderId::new(1),
+                OrderId::new(taker_id),
+                Price::new(40),
+                Volume::new(20),
+                MatchType::MakerFilled,
+            );
+            handle_book_event(
+                BookEvent::Match(fill),
+                &mut accounts,
+                SystemTime::UNIX_EPOCH,
+                &tx_outcome,
+            );
+        }
+        assert_eq!(balance(&accounts, 1, GBP), Balance::new(70));
+        assert_eq!(balance(&accounts, 2, GBP), Balance::new(20));
+        handle_account_event(
+            audit,
+            &mut accounts,
+            &tx_orders,
+            SystemTime::UNIX_EPOCH,
+            &tx_outcome,
+        );
+        let held = LedgerError::InsufficientFunds {
+            account: LedgerAccount::Held(UserId::new(1)),
+            currency: GBP,
+        };
+        assert_eq!(
+            rx_outcome.try_iter().last(),
+            Some(AccountOutcome::Audit {
+                correlation_id: CorrelationId::new(0),
+                user_id: UserId::new(0),
+                ledger: Ok(()),
+                faults: vec![
+                    SettlementFault::UnknownOrder {
+                        order_id: OrderId::new(2)
+                    },
+                    SettlementFault::Ledger {
+                        order_id: OrderId::new(3),
+                        error: held
+                    },
+                ],
+            })
+        );
+
+        let journal = accounts.ledger.journal();
+        let trade = journal.iter().rev().find_map(|e| e.trade).unwrap();
+        assert_eq!(trade.taker_side, Side::Buy);
+        assert_eq!(trade.price, Price::new(40));
+        let accounts =
+            Accounts::resume(accounts.ledger.journal().to_vec(), SystemTime::UNIX_EPOCH).unwrap();
+        assert_eq!(accounts.last_trade_id, TradeId::new(2));
+    }
+
+    #[test]
+    fn test_limit_order_holds() {
+        let (tx_orders, rx_order) = books();
+        let (tx_outcome, rx_outcome) = crossbeam_channel::unbounded();
+        let mut accounts = Accounts::default();
+        let limit_buy = |id, price, volume, time_in_force| {
+            place(
+                1,
+                id,
+                AccountOrderType::LimitBuy {
+                    volume: Volume::new(volume),
+                    price: Price::new(price),
+                    time_in_force,
+                    post_only: None,
+                    display_volume: None,
+                },
+            )
+        };
+        for ev in [
+            deposit(1, USD, 1000),
+            complete(1),
+            limit_buy(1, 10, 60, TimeInForce::GoodTillCancel),
+            // only 400 left to commit
+            limit_buy(2, 10, 50, TimeInForce::GoodTillCancel),
+            // the rejected order didn't use up an id
+            limit_buy(3, 20, 20, TimeInForce::ImmediateOrCancel),
+            deposit(2, GBP, 50),
+            complete(2),
+            place(
+                2,
+                4,
+                AccountOrderType::LimitSell {
+                    volume: Volume::new(5),
+                    price: Price::new(20),
+                    time_in_force: TimeInForce::GoodTillCancel,
+                    post_only: None,
+                    display_volume: None,
+                },
+            ),
+        ] {
+            handle_account_event(
+                ev,
+                &mut accounts,
+                &tx_orders,
+                SystemTime::UNIX_EPOCH,
+                &tx_outcome,
+            );
+        }
+        assert_eq!(rx_order.try_iter().count(), 3);
+        let outcomes: Vec<_> = rx_outcome.try_iter().collect();
+        assert!(outcomes.contains(&rejected(2, 1, RejectReason::InsufficientBalance)));
+        assert!(outcomes.contains(&accepted(3, 1, 2)));
+        assert!(outcomes.contains(&accepted(4, 2, 3)));
+        assert_eq!(balance(&accounts, 1, USD), Balance::new(0));
+        assert_eq!(held(&accounts, 1, USD), Balance::new(1000));
+
+        // funds held by live orders can't be withdrawn
+        let withdraw = AccountEvent {
+            correlation_id: CorrelationId::new(99),
+            user_id: UserId::new(1),
+            event: AccountEventType::Withdraw {
+                currency: USD,
+                balance: Balance::new(1),
+            },
+        };
+        handle_account_event(
+            withdraw,
+            &mut accounts,
+            &tx_orders,
+            SystemTime::UNIX_EPOCH,
+            &tx_outcome,
+        );
+        assert_eq!(
+            rx_outcome.try_recv(),
+            Ok(rejected(99, 1, RejectReason::InsufficientBalance))
+        );
+
+        // the IOC order fills 5 then expires, the cancel releases the rest
+        handle_book_event(
+            BookEvent::Match(Match::new(
+                OrderId::new(3),
+                OrderId::new(2),
+                Price::new(20),
+                Volume::new(5),
+                MatchType::MakerFilled,
+            )),
+            &mut accounts,
+            SystemTime::UNIX_EPOCH,
+            &tx_outcome,
+        );
+        assert_eq!(held(&accounts, 1, USD), Balance::new(900));
+        assert_eq!(held(&accounts, 2, GBP), Balance::new(0));
+        assert_eq!(balance(&accounts, 2, USD), Balance::new(100));
+        for order_id in [2, 1] {
+            handle_book_event(
+                BookEvent::Cancelled {
+                    order_id: OrderId::new(order_id),
+                    remaining_volume: Volume::new(15),
+                    reason: order_book::CancelReason::Expired,
+                },
+                &mut accounts,
+                SystemTime::UNIX_EPOCH,
+                &tx_outcome,
+            );
+        }
+        let cancelled = rx_outcome
+            .try_iter()
+            .filter(|o| matches!(o, AccountOutcome::Cancelled { .. }))
+            .count();
+        assert_eq!(cancelled, 2);
+        assert_eq!(balance(&accounts, 1, USD), Balance::new(900));
+        assert_eq!(held(&accounts, 1, USD), Balance::new(0));
+        assert_eq!(balance(&accounts, 1, GBP), Balance::new(5));
+        assert!(accounts.live_orders.is_empty());
+        assert_eq!(accounts.ledger.check(), Ok(()));
+    }
+
+    #[test]
+    fn test_moved_order_holds() {
+        let (tx_orders, _rx_order) = books();
+        let (tx_outcome, rx_outcome) = crossbeam_channel::unbounded();
+        let mut accounts = Accounts::default();
+        let limit_buy = |id, price, volume| {
+            place(
+                1,
+                id,
+                AccountOrderType::LimitBuy {
+                    volume: Volume::new(volume),
+                    price: Price::new(price),
+                    time_in_force: TimeInForce::GoodTillCancel,
+                    post_only: Some(PostOnly::Reprice),
+                    display_volume: None,
+                },
+            )
+        };
+        for ev in [
+            deposit(1, USD, 1000),
+            complete(1),
+            limit_buy(1, 10, 60),
+            // can't be accounted for, let alone paid for
+            limit_buy(2, u64::MAX / 2, 3),
+        ] {
+            handle_account_event(
+                ev,
+                &mut accounts,
+                &tx_orders,
+                SystemTime::UNIX_EPOCH,
+                &tx_outcome,
+            );
+        }
+        let outcomes: Vec<_> = rx_outcome.try_iter().collect();
+        assert!(outcomes.contains(&rejected(2, 1, RejectReason::NotionalTooLarge)));
+        assert_eq!(held(&accounts, 1, USD), Balance::new(600));
+
+        let order_id = OrderId::new(1);
+        let amended = |price, volume| BookEvent::Amended {
+            order_id,
+            price: Price::new(price),
+            volume: Volume::new(volume),
+            previous_volume: Volume::new(60),
+            kept_priority: false,
+        };
+        let price = Price::new(8);
+        // repriced below its limit, it needs less held; amended to cost more,
+        // it needs more - unless there's not enough to go round
+        for (ev, held_after) in [
+            (BookEvent::Repriced { order_id, price }, 480),
+            (amended(12, 50), 600),
+            (amended(30, 50), 600),
+        ] {
+            handle_book_event(ev, &mut accounts, SystemTime::UNIX_EPOCH, &tx_outcome);
+            assert_eq!(held(&accounts, 1, USD), Balance::new(held_after));
+        }
+        assert_eq!(balance(&accounts, 1, USD), Balance::new(400));
+        let live = &accounts.live_orders[&order_id];
+        assert_eq!(
+            (live.held, live.remaining),
+            (Balance::new(600), Volume::new(50))
+        );
+        assert_eq!(
+            accounts.faults,
+            [SettlementFault::Ledger {
+                order_id,
+                error: LedgerError::InsufficientFunds {
+                    account: LedgerAccount::Available(UserId::new(1)),
+                    currency: USD
+                }
+            }]
+        );
+        assert_eq!(accounts.ledger.check(), Ok(()));
+    }
+
+    #[test]
+    fn test_fees() {
+        let (tx_orders, _rx_order) = books();
+        let (tx_outcome, rx_outcome) = crossbeam_channel::unbounded();
+        let mut accounts = Accounts::default();
+        let tier = |min_volume, maker_bps, taker_bps| fees::FeeTier {
+            min_volume: Balance::new(min_volume),
+            maker_bps,
+            taker_bps,
+        };
+        let schedule = FeeSchedule::new(vec![tier(0, -10, 20), tier(50_000, 0, 10)]);
+        accounts.fee_schedules.insert(gbp_usd(), schedule.unwrap());
+        for ev in [
+            deposit(1, GBP, 2000),
+            complete(1),
+            deposit(2, USD, 100_000),
+            complete(2),
+        ] {
+            handle_account_event(
+                ev,
+                &mut accounts,
+                &tx_orders,
+                SystemTime::UNIX_EPOCH,
+                &tx_outcome,
+            );
+        }
+        for (sell_id, buy_id) in [(1, 2), (3, 4)] {
+            let sell = AccountOrderType::LimitSell {
+                volume: Volume::new(1000),
+                price: Price::new(50),
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: None,
+                display_volume: None,
+            };
+            let buy = AccountOrderType::MarketBuyQ {
+                quote_qty: Balance::new(50_000),
+            };
+            handle_account_event(
+                place(1, sell_id, sell),
+                &mut accounts,
+                &tx_orders,
+                SystemTime::UNIX_EPOCH,
+                &tx_outcome,
+            );
+            handle_account_event(
+                place(2, buy_id, buy),
+                &mut accounts,
+                &tx_orders,
+                SystemTime::UNIX_EPOCH,
+                &tx_outcome,
+            );
+            let fill = Match::new(
+                OrderId::new(sell_id),
+                OrderId::new(buy_id),
+                Price::new(50),
+                Volume::new(1000),
+                MatchType::BothFilled,
+            );
+            handle_book_event(
+                BookEvent::Match(fill),
+                &mut accounts,
+                SystemTime::UNIX_EPOCH,
+                &tx_outcome,
+            );
+        }
+        let fills: Vec<_> = rx_outcome
+            .try_iter()
+            .filter_map(|outcome| match outcome {
+                AccountOutcome::Fill {
+                    order_id,
+                    fee,
+                    fee_currency,
+                    ..
+                } => Some((order_id.inner(), fee, fee_currency)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            fills,
+            &[
+                (1, -50, USD),
+                (2, 2, GBP),
+                // both now trade enough for the next tier
+                (3, 0, USD),
+                (4, 1, GBP),
+            ]
+        );
+        assert_eq!(balance(&accounts, 1, USD), Balance::new(100_050));
+        assert_eq!(balance(&accounts, 2, GBP), Balance::new(1997));
+        assert_eq!(accounts.ledger.balance(LedgerAccount::Fees, USD), -50);
+        assert_eq!(accounts.ledger.balance(LedgerAccount::Fees, GBP), 3);
+        assert_eq!(accounts.ledger.check(), Ok(()));
+
+        // a restart remembers what each side traded, for as long as it counts
+        let traded = |journal: &[JournalEntry], user_id, now| {
+            let mut accounts = Accounts::resume(journal.to_vec(), now).unwrap();
+            let acct = accounts.accounts.get_mut(&UserId::new(user_id)).unwrap();
+            acct.traded.get_mut(&gbp_usd()).unwrap().total(now)
+        };
+        let journal = accounts.ledger.journal();
+        let start = SystemTime::UNIX_EPOCH;
+        let month = std::time::Duration::from_secs(30 * 24 * 60 * 60);
+        assert_eq!(traded(journal, 1, start), Balance::new(100_000));
+        assert_eq!(traded(journal, 2, start), Balance::new(100_000));
+        assert_eq!(traded(journal, 1, start + month), Balance::new(0));
+    }
+
+    #[test]
+    fn test_transfers() {
+        let (tx_orders, _rx_order) = books();
+        let (tx_outcome, rx_outcome) = crossbeam_channel::unbounded();
+        let mut accounts = Accounts::default();
+        let withdraw = |balance| AccountEvent {
+            correlation_id: CorrelationId::new(0),
+            user_id: UserId::new(1),
+            event: AccountEventType::Withdraw {
+                currency: GBP,
+                balance: Balance::new(balance),
+            },
+        };
+        let mut handle = |ev| {
+            handle_account_event(
+                ev,
+                &mut accounts,
+                &tx_orders,
+                SystemTime::UNIX_EPOCH,
+                &tx_outcome,
+            );
+            let verdict = match rx_outcome.try_recv().unwrap() {
+                AccountOutcome::Transfer { transfer, .. } => Ok(transfer.status),
+                AccountOutcome::Rejected { reason, .. } => Err(reason),
+                outcome => panic!("unexpected outcome {outcome:?}"),
+            };
+            // anything after the verdict is where the balance ended up
+            for outcome in rx_outcome.try_iter() {
+                assert!(matches!(outcome, AccountOutcome::Balance { .. }));
+            }
+            verdict
+        };
+        use TransferStatus::*;
+        assert_eq!(handle(deposit(1, GBP, 100)), Ok(Requested));
+        // nothing to withdraw until custody has the deposit
+        assert_eq!(handle(withdraw(10)), Err(RejectReason::InsufficientBalance));
+        assert_eq!(handle(update(1, Pending)), Ok(Pending));
+        assert_eq!(handle(complete(1)), Ok(Completed));
+        assert_eq!(
+            handle(complete(1)),
+            Err(RejectReason::InvalidTransferUpdate)
+        );
+        assert_eq!(handle(complete(9)), Err(RejectReason::UnknownTransfer));
+
+        // withdrawals lock their funds while custody works on them
+        assert_eq!(handle(withdraw(60)), Ok(Requested));
+        assert_eq!(handle(withdraw(60)), Err(RejectReason::InsufficientBalance));
+        assert_eq!(handle(withdraw(30)), Ok(Requested));
+        assert_eq!(handle(update(2, Rejected)), Ok(Rejected));
+        assert_eq!(handle(complete(3)), Ok(Completed));
+        assert_eq!(balance(&accounts, 1, GBP), Balance::new(70));
+        assert_eq!(accounts.ledger.balance(LedgerAccount::External, GBP), -70);
+        assert_eq!(accounts.ledger.check(), Ok(()));
+
+        // withdrawing counts as held until custody sends it
+        handle_account_event(
+            withdraw(50),
+            &mut accounts,
+            &tx_orders,
+            SystemTime::UNIX_EPOCH,
+            &tx_outcome,
+        );
+        assert!(matches!(
+            rx_outcome.try_recv(),
+            Ok(AccountOutcome::Transfer { .. })
+        ));
+        assert_eq!(
+            rx_outcome.try_recv(),
+            Ok(AccountOutcome::Balance {
+                correlation_id: CorrelationId::new(0),
+                user_id: UserId::new(1),
+                balance: CurrencyBalance {
+                    currency: GBP,
+                    available: Balance::new(20),
+                    held: Balance::new(50),
+                },
+            })
+        );
+
+        // transfers survive a restart, and so does what's locked for them
+        let mut accounts =
+            Accounts::resume(accounts.ledger.journal().to_vec(), SystemTime::UNIX_EPOCH).unwrap();
+        assert_eq!(
+            accounts.ledger.withdrawing(UserId::new(1), GBP),
+            Balance::new(50)
+        );
+        assert_eq!(accounts.transfers[&TransferId::new(4)].status, Requested);
+        assert_eq!(
+            update_transfer(&mut accounts, TransferId::new(4), Completed).map(|t| t.status),
+            Ok(Completed)
+        );
+        let transfer = new_transfer(
+            &accounts,
+            UserId::new(1),
+            TransferKind::Deposit,
+            GBP,
+            1.into(),
+        );
+        assert_eq!(transfer.id, TransferId::new(5));
+        assert_eq!(accounts.ledger.balance(LedgerAccount::External, GBP), -20);
+        assert_eq!(accounts.ledger.check(), Ok(()));
+    }
+
+    #[test]
+    fn test_account_queries() {
+        let (tx_orders, _rx_order) = books();
+        let (tx_outcome, rx_outcome) = crossbeam_channel::unbounded();
+        let mut accounts = Accounts::default();
+        let start = SystemTime::UNIX_EPOCH;
+        let later = start + std::time::Duration::from_secs(1);
+        let limit_sell = |price| AccountOrderType::LimitSell {
+            volume: Volume::new(10),
+            price: Price::new(price),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            display_volume: None,
+        };
+        for ev in [
+            deposit(1, GBP, 100),
+            complete(1),
+            place(1, 1, limit_sell(5)),
+            place(1, 2, limit_sell(6)),
+            deposit(2, USD, 100),
+            complete(2),
+            place(
+                2,
+                3,
+                AccountOrderType::MarketBuy {
+                    base_qty: Volume::new(4),
+                    max_quote: Balance::new(24),
+                },
+            ),
+        ] {
+            handle_account_event(ev, &mut accounts, &tx_orders, start, &tx_outcome);
+        }
+        let fill = Match::new(
+            OrderId::new(1),
+            OrderId::new(3),
+            Price::new(5),
+            Volume::new(4),
+            MatchType::TakerFilled,
+        );
+        handle_book_event(BookEvent::Match(fill), &mut accounts, later, &tx_outcome);
+        let _ = rx_outcome.try_iter().count();
+
+        let query = |event| AccountEvent {
+            correlation_id: CorrelationId::new(0),
+            user_id: UserId::new(1),
+            event,
+        };
+        for ev in [
+            query(AccountEventType::GetBalances),
+            query(AccountEventType::GetOpenOrders),
+        ] {
+            handle_account_event(ev, &mut accounts, &tx_orders, later, &tx_outcome);
+        }
+        let Ok(AccountOutcome::Balances { balances, .. }) = rx_outcome.try_recv() else {
+            panic!("no balances");
+        };
+        let balance = |currency, available, held| CurrencyBalance {
+            currency,
+            available: Balance::new(available),
+            held: Balance::new(held),
+        };
+        assert_eq!(balances, &[balance(GBP, 80, 16), balance(USD, 20, 0)]);
+        let Ok(AccountOutcome::OpenOrders { orders, .. }) = rx_outcome.try_recv() else {
+            panic!("no open orders");
+        };
+        let open = |id, price, remaining_volume, updated_at| OpenOrder {
+            order_id: OrderId::new(id),
+            symbol: gbp_usd(),
+            side: Side::Sell,
+            typ: OpenOrderType::Limit {
+                price: Price::new(price),
+            },
+            volume: Volume::new(10),
+            remaining_volume: Volume::new(remaining_volume),
+            placed_at: start,
+            updated_at,
+        };
+        assert_eq!(orders, &[open(1, 5, 6, later), open(2, 6, 10, start)]);
+    }
+
+    #[test]
+    fn test_order_ids() {
+        let (tx_orders, rx_order) = books();
+        let (tx_outcome, rx_outcome) = crossbeam_channel::unbounded();
+        let mut accounts = Accounts::default();
+        let sell = |correlation_id, client_order_id| AccountEvent {
+            correlation_id: CorrelationId::new(correlation_id),
+            user_id: UserId::new(1),
+            event: AccountEventType::PlaceOrder(NewOrder {
+                client_order_id: Some(ClientOrderId::new(client_order_id)),
+                symbol: gbp_usd(),
+                stp: None,
+                typ: AccountOrderType::LimitSell {
+                    volume: Volume::new(10),
+                    price: Price::new(5),
+                    time_in_force: TimeInForce::GoodTillCancel,
+                    post_only: None,
+                    display_volume: None,
+                },
+            }),
+        };
+        // the second is a retry of the first
+        for ev in [
+            deposit(1, GBP, 100),
+            complete(1),
+            sell(1, 7),
+            sell(2, 7),
+            sell(3, 8),
+        ] {
+            handle_account_event(
+                ev,
+                &mut accounts,
+                &tx_orders,
+                SystemTime::UNIX_EPOCH,
+                &tx_outcome,
+            );
+        }
+        assert_eq!(rx_order.try_iter().count(), 2);
+        let accepted_orders: Vec<_> = rx_outcome
+            .try_iter()
+            .filter(|o| matches!(o, AccountOutcome::Accepted { .. }))
+            .collect();
+        assert_eq!(
+            accepted_orders,
+            &[accepted(1, 1, 1), accepted(2, 1, 1), accepted(3, 1, 2)]
+        );
+        assert_eq!(held(&accounts, 1, GBP), Balance::new(20));
+
+        // after a restart from what was written out the books are empty, so
+        // nothing is held any more, and ids carry on from the journal
+        let mut written = Vec::new();
+        ledger::write_journal(&mut written, accounts.ledger.journal()).unwrap();
+        let journal = ledger::read_journal(written.as_slice()).unwrap();
+        assert_eq!(journal, accounts.ledger.journal());
+        let mut accounts = Accounts::resume(journal, SystemTime::UNIX_EPOCH).unwrap();
+        assert_eq!(held(&accounts, 1, GBP), Balance::new(0));
+        assert_eq!(balance(&accounts, 1, GBP), Balance::new(100));
+        for ev in [sell(4, 9), sell(5, 7)] {
+            handle_account_event(
+                ev,
+                &mut accounts,
+                &tx_orders,
+                SystemTime::UNIX_EPOCH,
+                &tx_outcome,
+            );
+        }
+        // retries are still spotted
+        assert_eq!(rx_order.try_iter().count(), 1);
+        let accepted_orders: Vec<_> = rx_outcome
+            .try_iter()
+            .filter(|o| matches!(o, AccountOutcome::Accepted { .. }))
+            .collect();
+        assert_eq!(accepted_orders, &[accepted(4, 1, 3), accepted(5, 1, 1)]);
+        assert_eq!(accounts.ledger.check(), Ok(()));
+    }
+
+    // Run the engine in front of a real GBP/USD book until it has dealt with
+    // `events`, and collect everything it reported
+    fn run_engine(events: impl IntoIterator<Item = AccountEvent>) -> Vec<AccountOutcome> {
+        let (tx_acct, rx_acct) = crossbeam_channel::unbounded();
+        let (tx_order, rx_order) = crossbeam_channel::unbounded();
+        let (tx_book_event, rx_book_event) = crossbeam_channel::unbounded();
+        let (tx_snapshot, _rx_snapshot) = crossbeam_channel::unbounded();
+        let (tx_depth, _rx_depth) = crossbeam_channel::unbounded();
+        let (tx_outcome, rx_outcome) = crossbeam_channel::unbounded();
+        let book = std::thread::spawn(move || {
+            let book = OrderBook::new();
+            run_orderbook_event_loop(book, rx_order, tx_book_event, tx_snapshot, tx_depth)
+        });
+        let engine = std::thread::spawn(move || {
+            let tx_orders = HashMap::from([(gbp_usd(), tx_order)]);
+            let fee_schedules = HashMap::new();
+            run_account_event_loop(
+                Vec::new(),
+                std::io::sink(),
+                fee_schedules,
+                rx_acct,
+                rx_book_event,
+                tx_orders,
+                tx_outcome,
+            )
+            .unwrap()
+        });
+        for ev in events {
+            tx_acct.send(ev).unwrap();
+        }
+        // shut down, the engine finishes everything in flight first
+        drop(tx_acct);
+        engine.join().unwrap();
+        book.join().unwrap();
+        rx_outcome.iter().collect()
+    }
+
+    #[test]
+    fn test_account_engine_end_to_end() {
+        let limit = |volume, time_in_force| AccountOrderType::LimitBuy {
+            volume: Volume::new(volume),
+            price: Price::new(100),
+            time_in_force,
+            post_only: None,
+            display_volume: None,
+        };
+        let outcomes = run_engine([
+            deposit(1, GBP, 50),
+            deposit(2, USD, 5000),
+            complete(1),
+            complete(2),
+            place(
+                1,
+                1,
+                AccountOrderType::LimitSell {
+                    volume: Volume::new(50),
+                    price: Price::new(100),
+                    time_in_force: TimeInForce::GoodTillCancel,
+                    post_only: None,
+                    display_volume: None,
+                },
+            ),
+            place(2, 2, limit(30, TimeInForce::ImmediateOrCancel)),
+            // only 2000 left, so this can't go through
+            place(2, 3, limit(30, TimeInForce::GoodTillCancel)),
+            place(
+                2,
+                4,
+                AccountOrderType::MarketBuy {
+                    base_qty: Volume::new(30),
+                    max_quote: Balance::new(3000),
+                },
+            ),
+        ]);
+        assert!(outcomes.contains(&rejected(3, 2, RejectReason::InsufficientBalance)));
+        let fills: Vec<_> = outcomes
+            .iter()
+            .filter_map(|outcome| match *outcome {
+                AccountOutcome::Fill {
+                    order_id,
+                    volume,
+                    filled,
+                    ..
+                } => Some((order_id.inner(), volume.inner(), filled)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            fills,
+            &[(1, 30, false), (2, 30, true), (1, 20, true), (3, 20, false)]
+        );
+        // the market buy ran out of book, so gets its remaining 10 expired,
+        // and the buyer hears where that leaves their dollars
+        let [cancelled, balance] = &outcomes[outcomes.len() - 2..] else {
+            unreachable!()
+        };
+        assert_eq!(
+            cancelled,
+            &AccountOutcome::Cancelled {
+                correlation_id: CorrelationId::new(4),
+                user_id: UserId::new(2),
+                order_id: OrderId::new(3),
+                symbol: gbp_usd(),
+                remaining_volume: Volume::new(10),
+                reason: CancelReason::Expired,
+            }
+        );
+        assert!(matches!(
+            balance,
+            AccountOutcome::Balance {
+                user_id,
+                balance: CurrencyBalance { currency: USD, held, .. },
+                ..
+            } if *user_id == UserId::new(2) && *held == Balance::new(0)
+        ));
+    }
+
+    #[test]
+    fn test_stop_orders() {
+        let limit_sell = |volume, price| AccountOrderType::LimitSell {
+            volume: Volume::new(volume),
+            price: Price::new(price),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            display_volume: None,
+        };
+        let take = |volume, price| AccountOrderType::LimitBuy {
+            volume: Volume::new(volume),
+            price: Price::new(price),
+            time_in_force: TimeInForce::ImmediateOrCancel,
+            post_only: None,
+            display_volume: None,
+        };
+        let outcomes = run_engine([
+            deposit(1, GBP, 50),
+            deposit(2, USD, 5000),
+            complete(1),
+            complete(2),
+            place(1, 1, limit_sell(10, 100)),
+            place(1, 2, limit_sell(10, 110)),
+            // holds 10 at 105
+            place(
+                2,
+                3,
+                AccountOrderType::StopMarket {
+                    side: Side::Buy,
+                    stop_price: Price::new(105),
+                    volume: Volume::new(10),
+                },
+            ),
+            // no trade yet, so any stop goes
+            place(
+                2,
+                4,
+                AccountOrderType::StopMarket {
+                    side: Side::Buy,
+                    stop_price: Price::new(90),
+                    volume: Volume::new(5),
+                },
+            ),
+            // trades at 100, setting off the stop at 90 - whose 450 buys 4
+            place(2, 5, take(5, 100)),
+            // already through, so cancelled rather than triggered
+            place(
+                2,
+                6,
+                AccountOrderType::StopMarket {
+                    side: Side::Buy,
+                    stop_price: Price::new(100),
+                    volume: Volume::new(5),
+                },
+            ),
+            // trades at 100 and 110, setting off the stop at 105 - which can
+            // only get the 5 left
+            place(2, 7, take(6, 110)),
+        ]);
+        let stops: Vec<_> = outcomes
+            .iter()
+            .filter_map(|outcome| match *outcome {
+                AccountOutcome::Triggered {
+                    order_id,
+                    last_price,
+                    ..
+                } => Some((order_id.inner(), "triggered", last_price.inner())),
+                AccountOutcome::Cancelled {
+                    order_id,
+                    reason: CancelReason::StopReached,
+                    remaining_volume,
+                    ..
+                } => Some((order_id.inner(), "reached", remaining_volume.inner())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            stops,
+            &[
+                (4, "triggered", 100),
+                (6, "reached", 5),
+                (3, "triggered", 110)
+            ]
+        );
+        let fills: Vec<_> = outcomes
+            .iter()
+            .filter_map(|outcome| match *outcome {
+                AccountOutcome::Fill {
+                    order_id,
+                    price,
+                    volume,
+                    maker: false,
+                    ..
+                } => Some((order_id.inner(), price.inner(), volume.inner())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            fills,
+            &[
+                (5, 100, 5),
+                (4, 100, 4),
+                (7, 100, 1),
+                (7, 110, 5),
+                (3, 110, 5)
+            ]
+        );
     }
 }
@@ -0,0 +1,209 @@
+use serde::{Deserialize, Serialize};
+
+use rust_decimal::Decimal;
+
+use crate::{fees::FeeSchedule, Balance, DecimalError, Price, RejectReason, Volume};
+
+/// Trading rules for a single market, checked before an order is sent to
+/// the book. All quantities are in the book's integer units; `min_notional`
+/// is in price * volume units, as with balances.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct MarketSpec {
+    /// Decimal places in a price, e.g. 0 for most JPY quotes
+    pub price_decimals: u32,
+    /// Decimal places in a volume of the base currency
+    pub volume_decimals: u32,
+    pub tick_size: Price,
+    pub lot_size: Volume,
+    pub min_volume: Volume,
+    pub max_volume: Volume,
+    pub min_notional: Balance,
+    pub fees: FeeSchedule,
+}
+
+impl Default for MarketSpec {
+    // anything goes, so long as it can be accounted for
+    fn default() -> Self {
+        Self {
+            price_decimals: 3,
+            volume_decimals: 3,
+            tick_size: Price::new(1),
+            lot_size: Volume::new(1),
+            min_volume: Volume::new(1),
+            // a billion at three decimal places
+            max_volume: Volume::new(1_000_000_000_000),
+            min_notional: Balance::new(0),
+            fees: FeeSchedule::default(),
+        }
+    }
+}
+
+/// Why an order was refused before reaching the book, by the market's rules
+/// or by the account engine
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(tag = "reason")]
+pub enum OrderRejection {
+    InvalidPrice {
+        error: DecimalError,
+    },
+    InvalidVolume {
+        error: DecimalError,
+    },
+    /// A quote currency amount, for orders sized in the quote currency
+    InvalidAmount {
+        error: DecimalError,
+    },
+    PriceNotOnTick,
+    VolumeNotOnLot,
+    VolumeTooSmall,
+    VolumeTooLarge,
+    NotionalTooSmall,
+    /// Price * volume is too big to account for
+    NotionalTooLarge,
+    /// Icebergs have to display something, and no more than their volume
+    InvalidDisplayVolume,
+    /// Post-only orders have to be good-till-cancel
+    InvalidTimeInForce,
+    /// A market order sized in what it gets back found nothing to trade with
+    NoLiquidity,
+    InsufficientBalance,
+    UnknownMarket,
+    /// Cancels and amendments have to be for a live order of the user's
+    UnknownOrder,
+    /// Only limit orders resting on the book can be amended
+    NotAmendable,
+}
+
+impl From<RejectReason> for OrderRejection {
+    fn from(reason: RejectReason) -> Self {
+        match reason {
+            RejectReason::InsufficientBalance => Self::InsufficientBalance,
+            RejectReason::UnknownMarket => Self::UnknownMarket,
+            RejectReason::NotionalTooLarge => Self::NotionalTooLarge,
+            RejectReason::UnknownOrder => Self::UnknownOrder,
+            RejectReason::NotAmendable => Self::NotAmendable,
+            RejectReason::UnknownTransfer | RejectReason::InvalidTransferUpdate => {
+                unreachable!("orders aren't transfers")
+            }
+        }
+    }
+}
+
+impl MarketSpec {
+    pub fn price(&self, price: Decimal) -> Result<Price, OrderRejection> {
+        Price::from_decimal(price, self.price_decimals)
+            .map_err(|error| OrderRejection::InvalidPrice { error })
+    }
+
+    pub fn volume(&self, volume: Decimal) -> Result<Volume, OrderRejection> {
+        Volume::from_decimal(volume, self.volume_decimals)
+            .map_err(|error| OrderRejection::InvalidVolume { error })
+    }
+
+    /// Quote currency amounts are price * volume, so carry both scalings
+    pub fn quote_decimals(&self) -> u32 {
+        self.price_decimals + self.volume_decimals
+    }
+
+    pub fn amount(&self, amount: Decimal) -> Result<Balance, OrderRejection> {
+        Balance::from_decimal(amount, self.quote_decimals())
+            .map_err(|error| OrderRejection::InvalidAmount { error })
+    }
+
+    pub fn validate_limit(&self, price: Price, volume: Volume) -> Result<(), OrderRejection> {
+        if price == Price::new(0) || !price.inner().is_multiple_of(self.tick_size.inner()) {
+            return Err(OrderRejection::PriceNotOnTick);
+        }
+        self.validate_volume(volume)?;
+        // the book and the account engine work in price * volume, which has
+        // to fit
+        let Some(notional) = price.inner().checked_mul(volume.inner()) else {
+            return Err(OrderRejection::NotionalTooLarge);
+        };
+        if notional < self.min_notional.inner() {
+            return Err(OrderRejection::NotionalTooSmall);
+        }
+        Ok(())
+    }
+
+    /// Orders sized in the quote currency have to spend at least min notional
+    pub fn validate_amount(&self, amount: Balance) -> Result<(), OrderRejection> {
+        if amount == Balance::new(0) || amount < self.min_notional {
+            return Err(OrderRejection::NotionalTooSmall);
+        }
+        Ok(())
+    }
+
+    /// Market orders have no price, so min notional can't be checked here
+    pub fn validate_volume(&self, volume: Volume) -> Result<(), OrderRejection> {
+        if !volume.inner().is_multiple_of(self.lot_size.inner()) {
+            return Err(OrderRejection::VolumeNotOnLot);
+        }
+        if volume < self.min_volume {
+            return Err(OrderRejection::VolumeTooSmall);
+        }
+        if volume > self.max_volume {
+            return Err(OrderRejection::VolumeTooLarge);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec() -> MarketSpec {
+        MarketSpec {
+            price_decimals: 2,
+            volume_decimals: 0,
+            tick_size: Price::new(5),
+            lot_size: Volume::new(10),
+            min_volume: Volume::new(20),
+            max_volume: Volume::new(1000),
+            min_notional: Balance::new(2000),
+            fees: FeeSchedule::default(),
+        }
+    }
+
+    #[test]
+    fn test_validate_limit() {
+        let spec = spec();
+        let check = |price, volume| spec.validate_limit(Price::new(price), Volume::new(volume));
+        assert_eq!(check(100, 20), Ok(()));
+        assert_eq!(check(0, 20), Err(OrderRejection::PriceNotOnTick));
+        assert_eq!(check(101, 20), Err(OrderRejection::PriceNotOnTick));
+        assert_eq!(check(100, 25), Err(OrderRejection::VolumeNotOnLot));
+        assert_eq!(check(100, 10), Err(OrderRejection::VolumeTooSmall));
+        assert_eq!(check(100, 1010), Err(OrderRejection::VolumeTooLarge));
+        assert_eq!(check(95, 20), Err(OrderRejection::NotionalTooSmall));
+        let spec = MarketSpec {
+            max_volume: Volume::new(u64::MAX),
+            ..spec
+        };
+        assert_eq!(
+            spec.validate_limit(Price::new(u64::MAX / 50 * 5), Volume::new(20)),
+            Err(OrderRejection::NotionalTooLarge)
+        );
+        assert_eq!(
+            MarketSpec::default().validate_limit(Price::new(1), Volume::new(1)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_validate_amount() {
+        let spec = spec();
+        let amount: Decimal = "20.05".parse().unwrap();
+        assert_eq!(spec.amount(amount), Ok(Balance::new(2005)));
+        assert_eq!(spec.validate_amount(Balance::new(2005)), Ok(()));
+        assert_eq!(
+            spec.validate_amount(Balance::new(1995)),
+            Err(OrderRejection::NotionalTooSmall)
+        );
+        assert_eq!(
+            MarketSpec::default().validate_amount(Balance::new(0)),
+            Err(OrderRejection::NotionalTooSmall)
+        );
+    }
+}
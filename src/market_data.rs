@@ -0,0 +1,515 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::{Duration, SystemTime},
+};
+
+use crate::{order_book::LevelChange, Balance, Price, Side, Trade, TradeId, Volume};
+
+/// How many trades each market remembers
+const RECENT_TRADES: usize = 1000;
+/// How many candles each market remembers, for each interval
+const CANDLES: usize = 1000;
+/// How far back the ticker looks
+const TICKER_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Everything public about a market's trading, built up one trade at a time.
+/// It only goes by the times the engine gave the trades, so the same trades
+/// always build the same data.
+pub struct MarketData {
+    pub depth: Depth,
+    /// How many trades have been recorded, so feeds can number them
+    pub trade_seq: u64,
+    pub recent_trades: RecentTrades,
+    pub candles: BTreeMap<CandleInterval, Candles>,
+    pub last_24h: RollingWindow,
+}
+
+impl Default for MarketData {
+    fn default() -> Self {
+        Self {
+            depth: Depth::default(),
+            trade_seq: 0,
+            recent_trades: RecentTrades::default(),
+            candles: CandleInterval::ALL
+                .into_iter()
+                .map(|interval| (interval, Candles::new(interval, CANDLES)))
+                .collect(),
+            last_24h: RollingWindow::new(TICKER_WINDOW),
+        }
+    }
+}
+
+impl MarketData {
+    pub fn record(&mut self, trade: Trade) {
+        self.trade_seq += 1;
+        self.recent_trades.record(trade);
+        for candles in self.candles.values_mut() {
+            candles.record(&trade);
+        }
+        self.last_24h.record(&trade);
+    }
+}
+
+/// The displayed volume at each price, as the book reports it changing.
+/// `seq` counts the batches of changes so far.
+#[derive(Default)]
+pub struct Depth {
+    pub bids: BTreeMap<Price, Volume>,
+    pub asks: BTreeMap<Price, Volume>,
+    pub seq: u64,
+}
+
+impl Depth {
+    pub fn apply(&mut self, changes: &[LevelChange]) {
+        self.seq += 1;
+        for change in changes {
+            let levels = match change.side {
+                Side::Buy => &mut self.bids,
+                Side::Sell => &mut self.asks,
+            };
+            if change.volume == Volume::new(0) {
+                levels.remove(&change.price);
+            } else {
+                levels.insert(change.price, change.volume);
+            }
+        }
+    }
+}
+
+/// The latest trades on a market, oldest first. Once full, the oldest trade
+/// drops off for each new one.
+pub struct RecentTrades {
+    trades: VecDeque<Trade>,
+    capacity: usize,
+}
+
+impl Default for RecentTrades {
+    fn default() -> Self {
+        Self::new(RECENT_TRADES)
+    }
+}
+
+impl RecentTrades {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            trades: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn record(&mut self, trade: Trade) {
+        if self.trades.len() == self.capacity {
+            self.trades.pop_front();
+        }
+        self.trades.push_back(trade);
+    }
+
+    /// Up to `limit` of the trades between `after` and `before` (both
+    /// exclusive), oldest first. Paging forward from `after` gives the
+    /// earliest of them, otherwise it's the latest.
+    pub fn page(
+        &self,
+        after: Option<TradeId>,
+        before: Option<TradeId>,
+        limit: usize,
+    ) -> Vec<Trade> {
+        // trade ids only go up, so the ring is sorted by them
+        let start = after.map_or(0, |after| self.trades.partition_point(|t| t.id <= after));
+        let end = before.map_or(self.trades.len(), |before| {
+            self.trades.partition_point(|t| t.id < before)
+        });
+        let end = end.max(start);
+        let (start, end) = match after {
+            Some(_) => (start, end.min(start + limit)),
+            None => (start.max(end.saturating_sub(limit)), end),
+        };
+        self.trades.range(start..end).copied().collect()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum CandleInterval {
+    Minute,
+    FiveMinutes,
+    Hour,
+    Day,
+}
+
+impl CandleInterval {
+    pub const ALL: [CandleInterval; 4] = [
+        CandleInterval::Minute,
+        CandleInterval::FiveMinutes,
+        CandleInterval::Hour,
+        CandleInterval::Day,
+    ];
+
+    pub fn duration(self) -> Duration {
+        let secs = match self {
+            CandleInterval::Minute => 60,
+            CandleInterval::FiveMinutes => 5 * 60,
+            CandleInterval::Hour => 60 * 60,
+            CandleInterval::Day => 24 * 60 * 60,
+        };
+        Duration::from_secs(secs)
+    }
+
+    // Candles line up with the Unix epoch, so days run midnight to midnight UTC
+    fn start(self, at: SystemTime) -> SystemTime {
+        let since_epoch = at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let secs = since_epoch - since_epoch % self.duration().as_secs();
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+}
+
+/// Open, high, low and close prices and the volume traded over one interval
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Candle {
+    pub start: SystemTime,
+    pub open: Price,
+    pub high: Price,
+    pub low: Price,
+    pub close: Price,
+    pub volume: Volume,
+}
+
+impl Candle {
+    fn open(start: SystemTime, trade: &Trade) -> Self {
+        Self {
+            start,
+            open: trade.price,
+            high: trade.price,
+            low: trade.price,
+            close: trade.price,
+            volume: trade.volume,
+        }
+    }
+
+    fn add(&mut self, trade: &Trade) {
+        self.high = self.high.max(trade.price);
+        self.low = self.low.min(trade.price);
+        self.close = trade.price;
+        self.volume += trade.volume;
+    }
+}
+
+/// A market's latest candles for one interval, oldest first. Intervals
+/// without any trades have no candle.
+pub struct Candles {
+    interval: CandleInterval,
+    candles: VecDeque<Candle>,
+    capacity: usize,
+}
+
+impl Candles {
+    pub fn new(interval: CandleInterval, capacity: usize) -> Self {
+        Self {
+            interval,
+            candles: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn record(&mut self, trade: &Trade) {
+        let start = self.interval.start(trade.at);
+        match self.candles.back_mut() {
+            // the clock can step back - trades it sends into an earlier
+            // interval count towards the latest candle
+            Some(candle) if candle.start >= start => candle.add(trade),
+            _ => {
+                if self.candles.len() == self.capacity {
+                    self.candles.pop_front();
+                }
+                self.candles.push_back(Candle::open(start, trade));
+            }
+        }
+    }
+
+    /// The candles starting from `from` up to (but not including) `to`
+    pub fn range(&self, from: Option<SystemTime>, to: Option<SystemTime>) -> Vec<Candle> {
+        self.candles
+            .iter()
+            .filter(|c| from.is_none_or(|from| c.start >= from))
+            .filter(|c| to.is_none_or(|to| c.start < to))
+            .copied()
+            .collect()
+    }
+}
+
+/// Trading over a rolling window, as of some time
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Ticker {
+    pub open: Price,
+    pub high: Price,
+    pub low: Price,
+    pub last: Price,
+    pub volume: Volume,
+    /// In the book's quote units - price times volume
+    pub quote_volume: Balance,
+    pub trade_count: usize,
+}
+
+/// The trades over the last `window`, with running totals so nothing has to
+/// be added up again. The highs and lows are kept in monotonic queues: each
+/// holds the trades that could still become the high (or low) once older
+/// trades leave the window.
+pub struct RollingWindow {
+    window: Duration,
+    trades: VecDeque<Trade>,
+    volume: Volume,
+    quote_volume: Balance,
+    // falling prices, front is the high
+    highs: VecDeque<Trade>,
+    // rising prices, front is the low
+    lows: VecDeque<Trade>,
+}
+
+impl RollingWindow {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            trades: VecDeque::new(),
+            volume: Volume::default(),
+            quote_volume: Balance::default(),
+            highs: VecDeque::new(),
+            lows: VecDeque::new(),
+        }
+    }
+
+    pub fn record(&mut self, trade: &Trade) {
+        self.expire(trade.at);
+        self.trades.push_back(*trade);
+        self.volume += trade.volume;
+        self.quote_volume += quote_amount(trade);
+        while self.highs.back().is_some_and(|t| t.price <= trade.price) {
+            self.highs.pop_back();
+        }
+        self.highs.push_back(*trade);
+        while self.lows.back().is_some_and(|t| t.price >= trade.price) {
+            self.lows.pop_back();
+        }
+        self.lows.push_back(*trade);
+    }
+
+    /// The window ending at `now`, or nothing if there were no trades in it
+    pub fn ticker(&mut self, now: SystemTime) -> Option<Ticker> {
+        self.expire(now);
+        let (open, last) = (self.trades.front()?, self.trades.back()?);
+        Some(Ticker {
+            open: open.price,
+            high: self.highs.front()?.price,
+            low: self.lows.front()?.price,
+            last: last.price,
+            volume: self.volume,
+            quote_volume: self.quote_volume,
+            trade_count: self.trades.len(),
+        })
+    }
+
+    fn expire(&mut self, now: SystemTime) {
+        while let Some(&trade) = self.trades.front() {
+            if trade.at + self.window > now {
+                break;
+            }
+            self.trades.pop_front();
+            self.volume -= trade.volume;
+            self.quote_volume -= quote_amount(&trade);
+            for extremes in [&mut self.highs, &mut self.lows] {
+                if extremes.front().is_some_and(|t| t.id == trade.id) {
+                    extremes.pop_front();
+                }
+            }
+        }
+    }
+}
+
+// settled trades were accounted for, so this fits
+fn quote_amount(trade: &Trade) -> Balance {
+    trade.price.notional(trade.volume).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::{Currency, Symbol};
+
+    fn trade(id: u64) -> Trade {
+        Trade {
+            id: TradeId::new(id),
+            symbol: Symbol {
+                base: Currency::new("GBP"),
+                quote: Currency::new("USD"),
+            },
+            price: Price::new(100),
+            volume: Volume::new(1),
+            taker_side: Side::Buy,
+            at: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    fn trade_at(secs: u64, price: u64, volume: u64) -> Trade {
+        Trade {
+            price: Price::new(price),
+            volume: Volume::new(volume),
+            at: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+            ..trade(1)
+        }
+    }
+
+    fn ids(trades: Vec<Trade>) -> Vec<u64> {
+        trades.into_iter().map(|t| t.id.inner()).collect()
+    }
+
+    #[test]
+    fn test_recent_trades() {
+        let mut recent = RecentTrades::new(4);
+        // other markets use up ids too, so they aren't contiguous
+        for id in [1, 2, 4, 7, 8, 9] {
+            recent.record(trade(id));
+        }
+        assert_eq!(ids(recent.page(None, None, 10)), [4, 7, 8, 9]);
+        assert_eq!(ids(recent.page(None, None, 2)), [8, 9]);
+        assert_eq!(ids(recent.page(None, Some(TradeId::new(8)), 10)), [4, 7]);
+        assert_eq!(ids(recent.page(None, Some(TradeId::new(8)), 1)), [7]);
+        assert_eq!(ids(recent.page(Some(TradeId::new(5)), None, 2)), [7, 8]);
+        assert_eq!(
+            ids(recent.page(Some(TradeId::new(1)), None, 10)),
+            [4, 7, 8, 9]
+        );
+        assert_eq!(
+            ids(recent.page(Some(TradeId::new(4)), Some(TradeId::new(9)), 10)),
+            [7, 8]
+        );
+        assert!(recent.page(Some(TradeId::new(9)), None, 10).is_empty());
+        assert!(recent
+            .page(Some(TradeId::new(8)), Some(TradeId::new(4)), 10)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_candles() {
+        let mut data = MarketData::default();
+        for trade in [
+            trade_at(0, 100, 1),
+            trade_at(30, 120, 2),
+            trade_at(59, 90, 3),
+            trade_at(60, 95, 4),
+            // the clock stepped back
+            trade_at(50, 97, 5),
+            trade_at(301, 110, 6),
+        ] {
+            data.record(trade);
+        }
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let candle = |start, [open, high, low, close]: [u64; 4], volume| Candle {
+            start: at(start),
+            open: Price::new(open),
+            high: Price::new(high),
+            low: Price::new(low),
+            close: Price::new(close),
+            volume: Volume::new(volume),
+        };
+        let minutes = &data.candles[&CandleInterval::Minute];
+        assert_eq!(
+            minutes.range(None, None),
+            [
+                candle(0, [100, 120, 90, 90], 6),
+                candle(60, [95, 97, 95, 97], 9),
+                candle(300, [110, 110, 110, 110], 6),
+            ]
+        );
+        assert_eq!(
+            minutes.range(Some(at(60)), Some(at(300))),
+            [candle(60, [95, 97, 95, 97], 9)]
+        );
+        assert_eq!(
+            data.candles[&CandleInterval::FiveMinutes].range(None, None),
+            [
+                candle(0, [100, 120, 90, 97], 15),
+                candle(300, [110, 110, 110, 110], 6),
+            ]
+        );
+        assert_eq!(
+            data.candles[&CandleInterval::Day].range(None, None),
+            [candle(0, [100, 120, 90, 110], 21)]
+        );
+
+        let mut few = Candles::new(CandleInterval::Minute, 2);
+        for secs in [0, 60, 120] {
+            few.record(&trade_at(secs, 100, 1));
+        }
+        assert_eq!(few.range(None, None)[0].start, at(60));
+    }
+
+    #[test]
+    fn test_ticker() {
+        let hour = 60 * 60;
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let mut window = RollingWindow::new(Duration::from_secs(24 * hour));
+        assert_eq!(window.ticker(at(0)), None);
+        for (id, (secs, price, volume)) in [
+            (0, 100, 1),
+            (hour, 150, 2),
+            (2 * hour, 80, 3),
+            (3 * hour, 120, 4),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            window.record(&Trade {
+                id: TradeId::new(id as u64 + 1),
+                ..trade_at(secs, price, volume)
+            });
+        }
+        let ticker = |[open, high, low, last]: [u64; 4], volume, quote_volume, trade_count| {
+            Some(Ticker {
+                open: Price::new(open),
+                high: Price::new(high),
+                low: Price::new(low),
+                last: Price::new(last),
+                volume: Volume::new(volume),
+                quote_volume: Balance::new(quote_volume),
+                trade_count,
+            })
+        };
+        assert_eq!(
+            window.ticker(at(3 * hour)),
+            ticker([100, 150, 80, 120], 10, 1120, 4)
+        );
+        // the first trade has dropped out, then the high
+        assert_eq!(
+            window.ticker(at(24 * hour)),
+            ticker([150, 150, 80, 120], 9, 1020, 3)
+        );
+        assert_eq!(
+            window.ticker(at(25 * hour)),
+            ticker([80, 120, 80, 120], 7, 720, 2)
+        );
+        assert_eq!(window.ticker(at(27 * hour)), None);
+    }
+
+    #[test]
+    fn test_depth() {
+        let change = |side, price, volume| LevelChange {
+            side,
+            price: Price::new(price),
+            volume: Volume::new(volume),
+        };
+        let mut depth = Depth::default();
+        depth.apply(&[change(Side::Buy, 10, 5), change(Side::Sell, 12, 3)]);
+        depth.apply(&[change(Side::Sell, 12, 0), change(Side::Buy, 12, 2)]);
+        assert_eq!(depth.seq, 2);
+        let levels = |levels: &BTreeMap<Price, Volume>| {
+            levels
+                .iter()
+                .map(|(p, v)| (p.inner(), v.inner()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(levels(&depth.bids), [(10, 5), (12, 2)]);
+        assert!(depth.asks.is_empty());
+    }
+}
@@ -1,15 +1,22 @@
-use std::collections::{btree_map::Entry, BTreeMap};
+use std::collections::{btree_map::Entry, BTreeMap, HashMap, VecDeque};
 
 use crossbeam_channel::{Receiver, Sender};
 
-use crate::{Balance, OrderId, Price, Volume};
+use crate::{Balance, OrderId, Price, Side, UserId, Volume};
 
 const LEVEL_QUOTE_INIT_CAPACITY: usize = 128;
 const TOMBSTONE_GC_LIMIT: u32 = 1000;
 
+// Where each resting order lives - its price level and its slot in `Level::quotes`.
+// Slots only move when a level is compacted, at which point they are re-indexed.
+type OrderIndex = HashMap<OrderId, (Price, usize)>;
+
 #[derive(Clone, Debug)]
 pub(crate) struct Level {
+    // displayed volume only
     total_volume: Volume,
+    // iceberg reserves, matchable but not displayed
+    hidden_volume: Volume,
     quotes: Vec<Quote>,
     tombstone_count: u32,
 }
@@ -18,24 +25,34 @@ impl Level {
     pub(crate) fn total_volume(&self) -> Volume {
         self.total_volume
     }
+    // everything that can be matched at this level, displayed or not
+    fn depth(&self) -> Volume {
+        self.total_volume + self.hidden_volume
+    }
     fn iter_quotes(&self) -> impl Iterator<Item = &Quote> {
         self.quotes.iter().filter(|q| !q.is_tombstone())
     }
-    fn iter_quotes_mut(&mut self) -> impl Iterator<Item = &mut Quote> {
-        self.quotes.iter_mut().filter(|q| !q.is_tombstone())
-    }
-    fn compact(&mut self) {
+    fn compact(&mut self, index: &mut OrderIndex) {
         self.quotes.retain(|q| !q.is_tombstone());
         self.tombstone_count = 0;
+        for (slot, q) in self.quotes.iter().enumerate() {
+            if let Some(loc) = index.get_mut(&q.order_id) {
+                loc.1 = slot;
+            }
+        }
     }
 
-    fn maybe_compact(&mut self) {
+    fn maybe_compact(&mut self, index: &mut OrderIndex) {
         if self.tombstone_count >= TOMBSTONE_GC_LIMIT {
-            self.compact();
+            self.compact(index);
         }
     }
-    fn clear(&mut self) {
+    fn clear(&mut self, index: &mut OrderIndex) {
+        for q in self.iter_quotes() {
+            index.remove(&q.order_id);
+        }
         self.total_volume = Volume::new(0);
+        self.hidden_volume = Volume::new(0);
         self.quotes.clear();
         self.tombstone_count = 0;
     }
@@ -45,22 +62,38 @@ impl Default for Level {
     fn default() -> Self {
         Self {
             total_volume: Volume::new(0),
+            hidden_volume: Volume::new(0),
             quotes: Vec::with_capacity(LEVEL_QUOTE_INIT_CAPACITY),
             tombstone_count: 0,
         }
     }
 }
 
-#[derive(Copy, Clone, derive_more::Constructor)]
+#[derive(Copy, Clone)]
 pub struct Quote {
     order_id: OrderId,
+    // for self-trade prevention, `None` for nobody in particular
+    owner: Option<UserId>,
+    // displayed volume
     volume: Volume,
+    // iceberg orders only - hidden volume still to be displayed,
+    // and the size of each displayed slice
+    reserve: Volume,
+    peak: Volume,
+    // post-only orders stay post-only when amended
+    post_only: Option<PostOnly>,
 }
 
 impl std::fmt::Debug for Quote {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         if self.is_tombstone() {
             write!(f, "TOMBSTONE")
+        } else if self.is_iceberg() {
+            write!(
+                f,
+                "Q({:?} -> {:?} +{:?})",
+                self.order_id, self.volume, self.reserve
+            )
         } else {
             write!(f, "Q({:?} -> {:?})", self.order_id, self.volume)
         }
@@ -68,23 +101,102 @@ impl std::fmt::Debug for Quote {
 }
 
 impl Quote {
+    pub fn new(order_id: OrderId, volume: Volume) -> Quote {
+        Quote {
+            order_id,
+            owner: None,
+            volume,
+            reserve: Volume::new(0),
+            peak: Volume::new(0),
+            post_only: None,
+        }
+    }
+
+    pub fn with_owner(self, owner: impl Into<Option<UserId>>) -> Quote {
+        Quote {
+            owner: owner.into(),
+            ..self
+        }
+    }
+
+    /// An iceberg quote, displaying at most `peak` of its `volume` at a time.
+    /// A zero peak would never show anything, so gives a plain quote instead.
+    pub fn iceberg(order_id: OrderId, volume: Volume, peak: Volume) -> Quote {
+        if peak == Volume::new(0) {
+            return Quote::new(order_id, volume);
+        }
+        let shown = std::cmp::min(volume, peak);
+        Quote {
+            order_id,
+            owner: None,
+            volume: shown,
+            reserve: volume - shown,
+            peak,
+            post_only: None,
+        }
+    }
+
+    fn with_display(order_id: OrderId, volume: Volume, display_volume: Option<Volume>) -> Quote {
+        match display_volume {
+            Some(peak) => Quote::iceberg(order_id, volume, peak),
+            None => Quote::new(order_id, volume),
+        }
+    }
+
+    fn is_iceberg(&self) -> bool {
+        self.peak != Volume::new(0)
+    }
+
+    fn display_volume(&self) -> Option<Volume> {
+        self.is_iceberg().then_some(self.peak)
+    }
+
+    // displayed and hidden volume together
+    fn open_volume(&self) -> Volume {
+        self.volume + self.reserve
+    }
+
+    // the next displayed slice of an iceberg, once the current one is used up
+    fn replenish(&self) -> Quote {
+        Quote {
+            post_only: self.post_only,
+            ..Quote::iceberg(self.order_id, self.reserve, self.peak).with_owner(self.owner)
+        }
+    }
+
+    // Take `cut` off the open volume, hidden reserve first. Returns how much
+    // came off the displayed volume.
+    fn reduce(&mut self, cut: Volume) -> Volume {
+        let hidden_cut = std::cmp::min(cut, self.reserve);
+        self.reserve -= hidden_cut;
+        self.volume -= cut - hidden_cut;
+        cut - hidden_cut
+    }
+
     fn tombstone() -> Quote {
         Quote {
-            order_id: OrderId::new(u64::MAX),
+            order_id: OrderId::TOMBSTONE,
+            owner: None,
             volume: Volume::new(u64::MAX),
+            reserve: Volume::new(0),
+            peak: Volume::new(0),
+            post_only: None,
         }
     }
 
     fn is_tombstone(&self) -> bool {
-        self.order_id == OrderId::new(u64::MAX)
+        self.order_id == OrderId::TOMBSTONE
     }
 }
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub enum MatchType {
     MakerFilled,
     TakerFilled,
     BothFilled,
+    /// Only an iceberg's displayed slice was filled, and the taker wants more
+    NeitherFilled,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, derive_more::Constructor)]
@@ -102,6 +214,7 @@ impl std::fmt::Debug for Match {
             MatchType::MakerFilled => ("F", " "),
             MatchType::TakerFilled => (" ", "F"),
             MatchType::BothFilled => ("F", "F"),
+            MatchType::NeitherFilled => (" ", " "),
         };
         write!(
             f,
@@ -116,6 +229,13 @@ pub struct OrderBook {
     best_ask: Price,
     best_bid: Price,
     levels: BTreeMap<Price, Level>,
+    index: OrderIndex,
+    // volume removed by self-trade prevention, waiting to be reported
+    self_trades: Vec<SelfTrade>,
+    // the market's smallest price increment, which post-only orders slide by
+    tick_size: Price,
+    // the market's smallest volume increment, which quote orders round down to
+    lot_size: Volume,
 }
 
 impl Default for OrderBook {
@@ -126,27 +246,53 @@ impl Default for OrderBook {
 
 impl OrderBook {
     pub fn new() -> Self {
+        Self::with_increments(Price::new(1), Volume::new(1))
+    }
+
+    /// A book for a market whose prices move in steps of `tick_size`, and
+    /// volumes in steps of `lot_size`
+    pub fn with_increments(tick_size: Price, lot_size: Volume) -> Self {
         OrderBook {
             best_ask: Price::new(u64::MAX),
             best_bid: Price::new(u64::MIN),
             levels: BTreeMap::new(),
+            index: OrderIndex::new(),
+            self_trades: Vec::new(),
+            tick_size,
+            lot_size,
         }
     }
 
+    // Levels that have been traded out are left in place, so skip them
     pub(crate) fn ask_levels(&self) -> impl Iterator<Item = (&Price, &Level)> {
-        self.levels.range(self.best_ask..)
+        self.levels
+            .range(self.best_ask..)
+            .filter(|(_, level)| level.depth() != Volume::new(0))
     }
 
-    fn ask_levels_mut(&mut self) -> impl Iterator<Item = (&Price, &mut Level)> {
-        self.levels.range_mut(self.best_ask..)
+    pub(crate) fn bid_levels(&self) -> impl Iterator<Item = (&Price, &Level)> {
+        self.levels
+            .range(..=self.best_bid)
+            .rev()
+            .filter(|(_, level)| level.depth() != Volume::new(0))
     }
 
-    pub(crate) fn bid_levels(&self) -> impl Iterator<Item = (&Price, &Level)> {
-        self.levels.range(..=self.best_bid).rev()
+    /// What buying `volume` would cost as the book stands, as far as the
+    /// asks go. `None` if that's too much to account for.
+    pub fn cost_to_buy(&self, volume: Volume) -> Option<Balance> {
+        let (mut cost, mut remaining) = (0u64, volume);
+        for (price, level) in self.ask_levels() {
+            let take = std::cmp::min(remaining, level.depth());
+            cost = cost.checked_add(price.notional(take)?.inner())?;
+            remaining -= take;
+        }
+        Some(Balance::new(cost))
     }
 
-    fn bid_levels_mut(&mut self) -> impl Iterator<Item = (&Price, &mut Level)> {
-        self.levels.range_mut(..=self.best_bid).rev()
+    /// How much selling for `quote` would take as the book stands - what
+    /// `execute_market_sell_quote` would sell
+    pub fn volume_to_sell_for(&self, quote: Balance) -> Volume {
+        volume_for_quote(self.bid_levels(), quote, self.lot_size)
     }
 
     pub fn ask_volume(&self) -> Volume {
@@ -170,26 +316,7 @@ impl OrderBook {
         if self.best_ask <= price {
             return Outcome::CrossedSpread;
         }
-        let did_update;
-        match self.levels.entry(price) {
-            // new level
-            Entry::Vacant(v) => {
-                did_update = false;
-                let level = Level {
-                    total_volume: quote.volume,
-                    quotes: vec![quote],
-                    tombstone_count: 0,
-                };
-                v.insert(level);
-            }
-            // existing level
-            Entry::Occupied(mut o) => {
-                let level = o.get_mut();
-                did_update = level.total_volume != Volume::new(0);
-                level.total_volume += quote.volume;
-                level.quotes.push(quote);
-            }
-        }
+        let did_update = self.insert_quote(price, quote);
         if self.best_bid < price {
             self.best_bid = price;
             return Outcome::PlacedNewBest;
@@ -205,83 +332,262 @@ impl OrderBook {
         if self.best_bid >= price {
             return Outcome::CrossedSpread;
         }
+        let did_update = self.insert_quote(price, quote);
+        if self.best_ask > price {
+            self.best_ask = price;
+            return Outcome::PlacedNewBest;
+        }
+        if did_update {
+            Outcome::PlacedExisting
+        } else {
+            Outcome::PlacedNew
+        }
+    }
+
+    // Append the quote to its level (creating the level if need be) and index it.
+    // Returns whether the level already held some volume.
+    fn insert_quote(&mut self, price: Price, quote: Quote) -> bool {
+        debug_assert!(
+            !self.index.contains_key(&quote.order_id),
+            "duplicate order id {:?}",
+            quote.order_id
+        );
         let did_update;
+        let slot;
         match self.levels.entry(price) {
+            // new level
             Entry::Vacant(v) => {
                 did_update = false;
+                slot = 0;
                 let level = Level {
                     total_volume: quote.volume,
+                    hidden_volume: quote.reserve,
                     quotes: vec![quote],
                     tombstone_count: 0,
                 };
                 v.insert(level);
             }
+            // existing level
             Entry::Occupied(mut o) => {
                 let level = o.get_mut();
                 did_update = level.total_volume != Volume::new(0);
+                slot = level.quotes.len();
                 level.total_volume += quote.volume;
+                level.hidden_volume += quote.reserve;
                 level.quotes.push(quote);
             }
         }
-        if self.best_ask > price {
-            self.best_ask = price;
-            return Outcome::PlacedNewBest;
+        self.index.insert(quote.order_id, (price, slot));
+        did_update
+    }
+
+    /// Cancel a resting order by id alone. O(1) thanks to the order index.
+    pub fn cancel(&mut self, order_id: OrderId) -> Cancellation {
+        let Some((price, slot)) = self.index.remove(&order_id) else {
+            return Cancellation::NotFound;
+        };
+        let level = self
+            .levels
+            .get_mut(&price)
+            .expect("indexed order has no level");
+        let q = &mut level.quotes[slot];
+        debug_assert_eq!(q.order_id, order_id);
+        let remaining_volume = q.open_volume();
+        level.total_volume -= q.volume;
+        level.hidden_volume -= q.reserve;
+        *q = Quote::tombstone();
+        level.tombstone_count += 1;
+        level.maybe_compact(&mut self.index);
+        if level.depth() == Volume::new(0) {
+            // an empty level left behind could later be taken for the best
+            self.refresh_best(price);
+            self.levels.remove(&price);
         }
-        if did_update {
-            Outcome::PlacedExisting
+        Cancellation::Cancelled { remaining_volume }
+    }
+
+    /// Amend a resting order. Reducing the volume at the same price keeps the
+    /// order's place in the queue. Anything else (a new price or more volume)
+    /// loses priority - the order is pulled and re-entered as a fresh limit
+    /// order, which may cross the spread and match immediately. Post-only
+    /// orders are re-entered post-only, so they are repriced or cancelled
+    /// rather than cross.
+    /// `new_volume` is the new open volume; amending to zero cancels the
+    /// order. `stp` applies should the re-entered order trade.
+    pub fn amend(
+        &mut self,
+        order_id: OrderId,
+        new_price: Price,
+        new_volume: Volume,
+        stp: Option<SelfTradePrevention>,
+        fills: &mut Vec<Match>,
+    ) -> Amendment {
+        let Some(&(price, slot)) = self.index.get(&order_id) else {
+            return Amendment::NotFound;
+        };
+        if new_volume == Volume::new(0) {
+            let Cancellation::Cancelled { remaining_volume } = self.cancel(order_id) else {
+                unreachable!("indexed order can't be cancelled");
+            };
+            return Amendment::Cancelled {
+                remaining_volume,
+                reason: CancelReason::Requested,
+            };
+        }
+        // all resting bids are below the best ask, all resting asks at or above it
+        let is_bid = price < self.best_ask;
+        let level = self
+            .levels
+            .get_mut(&price)
+            .expect("indexed order has no level");
+        let quote = &mut level.quotes[slot];
+        let previous_volume = quote.open_volume();
+        let display_volume = quote.display_volume();
+        let post_only = quote.post_only;
+        let taker = Taker {
+            order_id,
+            owner: quote.owner,
+            stp,
+        };
+        if new_price == price && new_volume <= previous_volume {
+            // icebergs shrink their hidden reserve first
+            let cut = previous_volume - new_volume;
+            let shown_cut = quote.reduce(cut);
+            level.hidden_volume -= cut - shown_cut;
+            level.total_volume -= shown_cut;
+            return Amendment::Amended {
+                price,
+                previous_volume,
+                kept_priority: true,
+            };
+        }
+        self.cancel(order_id);
+        let (tif, disp) = (TimeInForce::GoodTillCancel, display_volume);
+        let outcome = if is_bid {
+            self.execute_limit_buy_order(taker, new_price, new_volume, tif, post_only, disp, fills)
         } else {
-            Outcome::PlacedNew
+            self.execute_limit_sell_order(taker, new_price, new_volume, tif, post_only, disp, fills)
+        };
+        let price = match outcome {
+            LimitOutcome::Repriced { price, .. } => price,
+            LimitOutcome::WouldCross => {
+                return Amendment::Cancelled {
+                    remaining_volume: new_volume,
+                    reason: CancelReason::PostOnly,
+                }
+            }
+            _ => new_price,
+        };
+        Amendment::Amended {
+            price,
+            previous_volume,
+            kept_priority: false,
         }
     }
 
-    fn cancel(&mut self, price: Price, order_id: OrderId) -> Cancellation {
-        let Some(level) = self.levels.get_mut(&price) else {
-            return Cancellation::NotFound;
-        };
-        for q in level.quotes.iter_mut() {
-            if q.order_id == order_id {
-                level.total_volume -= q.volume;
-                *q = Quote::tombstone();
-                level.tombstone_count += 1;
-                level.maybe_compact();
-                return Cancellation::WasCancelled;
+    fn order_price(&self, order_id: OrderId) -> Option<Price> {
+        self.index.get(&order_id).map(|&(price, _)| price)
+    }
+
+    // How the levels at `prices` stand now, given where the best prices
+    // stood before. A level that has gone, or moved to the other side of
+    // the book, is reported with no volume on the side it was on.
+    fn level_changes(&self, prices: &[Price], (bid, ask): (Price, Price)) -> Vec<LevelChange> {
+        let mut changes = Vec::new();
+        for &price in prices {
+            let volume = self
+                .levels
+                .get(&price)
+                .map_or(Volume::new(0), Level::total_volume);
+            let side_now = (volume != Volume::new(0)).then(|| {
+                if price <= self.best_bid {
+                    Side::Buy
+                } else {
+                    Side::Sell
+                }
+            });
+            let side_before = if price <= bid {
+                Some(Side::Buy)
+            } else if price >= ask {
+                Some(Side::Sell)
+            } else {
+                None
+            };
+            if let Some(side) = side_before.filter(|&side| Some(side) != side_now) {
+                changes.push(LevelChange {
+                    side,
+                    price,
+                    volume: Volume::new(0),
+                });
             }
+            if let Some(side) = side_now {
+                changes.push(LevelChange {
+                    side,
+                    price,
+                    volume,
+                });
+            }
+        }
+        changes
+    }
+
+    // The level at `price` has just been emptied - if it was the best
+    // price on its side, move the best price on to the next live level
+    fn refresh_best(&mut self, price: Price) {
+        if price == self.best_bid {
+            self.best_bid = self
+                .levels
+                .range(..price)
+                .rev()
+                .find(|(_, l)| l.total_volume != Volume::new(0))
+                .map(|(&p, _)| p)
+                .unwrap_or(Price::new(u64::MIN));
+        } else if price == self.best_ask {
+            self.best_ask = self
+                .levels
+                .range(price..)
+                .skip(1)
+                .find(|(_, l)| l.total_volume != Volume::new(0))
+                .map(|(&p, _)| p)
+                .unwrap_or(Price::new(u64::MAX));
         }
-        Cancellation::NotFound
     }
 
     pub fn execute_market_buy(
         &mut self,
-        order_id: OrderId,
+        taker: impl Into<Taker>,
         target_vol: Volume,
         available_quote_balance: Balance,
         fills: &mut Vec<Match>,
     ) -> TxnOutcome {
+        let taker = taker.into();
         {
             // first validate that the transaction is possible
             let mut rem_bal = available_quote_balance;
             let mut rem_vol = target_vol;
             for (price, level) in self.ask_levels() {
-                let vol = std::cmp::min(rem_vol, level.total_volume);
-                if price.inner() * vol.inner() > rem_bal.inner() {
+                let depth = level.depth();
+                let vol = std::cmp::min(rem_vol, depth);
+                let cost = match price.notional(vol) {
+                    Some(cost) if cost <= rem_bal => cost,
                     // oh dear, not enough funds to complete
-                    return TxnOutcome::FailedInsufficientFunds;
-                }
-                if rem_vol < level.total_volume {
+                    _ => return TxnOutcome::FailedInsufficientFunds,
+                };
+                if rem_vol < depth {
                     break;
                 }
-                rem_vol -= level.total_volume;
-                rem_bal -= Balance::new(price.inner() * level.total_volume.inner());
+                rem_vol -= depth;
+                rem_bal -= cost;
             }
         }
 
         let res = execute_market_txn(
-            self.ask_levels_mut(),
-            order_id,
+            self.levels.range_mut(self.best_ask..),
+            &mut self.index,
+            &mut self.self_trades,
+            taker,
             target_vol,
-            OrderTarget::MarketBuy {
-                available_quote_balance,
-            },
+            OrderTarget::MarketBuy,
             fills,
         );
         if let TxnOutcome::Filled { new_best_price } = res {
@@ -292,15 +598,50 @@ impl OrderBook {
         res
     }
 
+    /// Buy base until `target_quote` has been spent ("buy 1000USD-worth of GBP").
+    /// Quote amounts in the book are in price * volume units (so carry both
+    /// the price and the volume decimals), so at the final level we take as
+    /// much volume as the remaining quote pays for, rounded down to whole
+    /// lots. Whatever is too little for another lot goes unspent.
+    pub fn execute_market_buy_quote(
+        &mut self,
+        taker: impl Into<Taker>,
+        target_quote: Balance,
+        fills: &mut Vec<Match>,
+    ) -> TxnOutcome {
+        let target_vol = volume_for_quote(self.ask_levels(), target_quote, self.lot_size);
+        self.execute_market_buy(taker, target_vol, target_quote, fills)
+    }
+
+    /// Sell base until `target_quote` has been received ("sell 1000USD-worth of GBP"),
+    /// truncating at the final level as for `execute_market_buy_quote`. Fails
+    /// without matching if that would take more than `available_base_qty`.
+    pub fn execute_market_sell_quote(
+        &mut self,
+        taker: impl Into<Taker>,
+        target_quote: Balance,
+        available_base_qty: Volume,
+        fills: &mut Vec<Match>,
+    ) -> TxnOutcome {
+        let target_vol = volume_for_quote(self.bid_levels(), target_quote, self.lot_size);
+        if target_vol > available_base_qty {
+            return TxnOutcome::FailedInsufficientFunds;
+        }
+        self.execute_market_sell(taker, target_vol, fills)
+    }
+
     pub fn execute_market_sell(
         &mut self,
-        order_id: OrderId,
+        taker: impl Into<Taker>,
         target_vol: Volume,
         fills: &mut Vec<Match>,
     ) -> TxnOutcome {
+        let taker = taker.into();
         let res = execute_market_txn(
-            self.bid_levels_mut(),
-            order_id,
+            self.levels.range_mut(..=self.best_bid).rev(),
+            &mut self.index,
+            &mut self.self_trades,
+            taker,
             target_vol,
             OrderTarget::MarketSell,
             fills,
@@ -313,92 +654,368 @@ impl OrderBook {
         res
     }
 
+    /// Match what we can against the book, then deal with any remainder according
+    /// to `time_in_force`. With `display_volume` set, a resting remainder becomes
+    /// an iceberg showing at most that much at a time.
+    #[allow(clippy::too_many_arguments)]
     pub fn execute_limit_buy_order(
         &mut self,
-        order_id: OrderId,
+        taker: impl Into<Taker>,
         target_price: Price,
         target_vol: Volume,
+        time_in_force: TimeInForce,
+        post_only: Option<PostOnly>,
+        display_volume: Option<Volume>,
         fills: &mut Vec<Match>,
-    ) {
+    ) -> LimitOutcome {
+        let taker = taker.into();
+        if display_volume == Some(Volume::new(0)) {
+            return LimitOutcome::NothingDisplayed;
+        }
+        if let Some(post_only) = post_only {
+            let quote = Quote {
+                post_only: Some(post_only),
+                ..Quote::with_display(taker.order_id, target_vol, display_volume)
+                    .with_owner(taker.owner)
+            };
+            return self.post_bid(target_price, quote, post_only);
+        }
+        if time_in_force == TimeInForce::FillOrKill
+            && !can_fill(
+                self.ask_levels().take_while(|(&p, _)| p <= target_price),
+                &taker,
+                target_vol,
+            )
+        {
+            return LimitOutcome::Killed;
+        }
         // may fill or partially fill
         let res = execute_market_txn(
-            self.ask_levels_mut(),
-            order_id,
+            self.levels.range_mut(self.best_ask..),
+            &mut self.index,
+            &mut self.self_trades,
+            taker,
             target_vol,
             OrderTarget::LimitBuy(target_price),
             fills,
         );
-        match res {
+        let volume_transacted = match res {
             TxnOutcome::Filled { new_best_price } => {
                 self.best_ask = new_best_price;
+                return LimitOutcome::Filled;
             }
             TxnOutcome::PartiallyFilled {
                 volume_transacted,
                 new_best_price,
             } => {
                 self.best_ask = new_best_price;
-                self.add_bid(
-                    target_price,
-                    Quote::new(order_id, target_vol - volume_transacted),
-                )
-                .assert_placed();
+                volume_transacted
             }
             TxnOutcome::MarketVolumeExhausted { volume_transacted } => {
                 self.best_ask = Price::new(u64::MAX);
-                self.add_bid(
-                    target_price,
-                    Quote::new(order_id, target_vol - volume_transacted),
-                )
-                .assert_placed();
+                volume_transacted
             }
             TxnOutcome::FailedInsufficientFunds => unreachable!(),
+        };
+        let remaining_volume = target_vol - volume_transacted;
+        if remaining_volume == Volume::new(0) {
+            return LimitOutcome::Filled;
+        }
+        match time_in_force {
+            TimeInForce::GoodTillCancel => {
+                let quote = Quote::with_display(taker.order_id, remaining_volume, display_volume)
+                    .with_owner(taker.owner);
+                self.add_bid(target_price, quote).assert_placed();
+                LimitOutcome::Rested { remaining_volume }
+            }
+            TimeInForce::ImmediateOrCancel | TimeInForce::FillOrKill => {
+                LimitOutcome::Expired { remaining_volume }
+            }
         }
     }
+
+    /// Match what we can against the book, then deal with any remainder according
+    /// to `time_in_force`. With `display_volume` set, a resting remainder becomes
+    /// an iceberg showing at most that much at a time.
+    #[allow(clippy::too_many_arguments)]
     pub fn execute_limit_sell_order(
         &mut self,
-        order_id: OrderId,
+        taker: impl Into<Taker>,
         target_price: Price,
         target_vol: Volume,
+        time_in_force: TimeInForce,
+        post_only: Option<PostOnly>,
+        display_volume: Option<Volume>,
         fills: &mut Vec<Match>,
-    ) {
+    ) -> LimitOutcome {
+        let taker = taker.into();
+        if display_volume == Some(Volume::new(0)) {
+            return LimitOutcome::NothingDisplayed;
+        }
+        if let Some(post_only) = post_only {
+            let quote = Quote {
+                post_only: Some(post_only),
+                ..Quote::with_display(taker.order_id, target_vol, display_volume)
+                    .with_owner(taker.owner)
+            };
+            return self.post_ask(target_price, quote, post_only);
+        }
+        if time_in_force == TimeInForce::FillOrKill
+            && !can_fill(
+                self.bid_levels().take_while(|(&p, _)| p >= target_price),
+                &taker,
+                target_vol,
+            )
+        {
+            return LimitOutcome::Killed;
+        }
         // may fill or partially fill
         let res = execute_market_txn(
-            self.bid_levels_mut(),
-            order_id,
+            self.levels.range_mut(..=self.best_bid).rev(),
+            &mut self.index,
+            &mut self.self_trades,
+            taker,
             target_vol,
             OrderTarget::LimitSell(target_price),
             fills,
         );
-        match res {
+        let volume_transacted = match res {
             TxnOutcome::Filled { new_best_price } => {
                 self.best_bid = new_best_price;
+                return LimitOutcome::Filled;
             }
             TxnOutcome::PartiallyFilled {
                 volume_transacted,
                 new_best_price,
             } => {
                 self.best_bid = new_best_price;
-                self.add_ask(
-                    target_price,
-                    Quote::new(order_id, target_vol - volume_transacted),
-                )
-                .assert_placed();
+                volume_transacted
             }
             TxnOutcome::MarketVolumeExhausted { volume_transacted } => {
                 self.best_bid = Price::new(u64::MIN);
-                self.add_ask(
-                    target_price,
-                    Quote::new(order_id, target_vol - volume_transacted),
-                )
-                .assert_placed();
+                volume_transacted
             }
             TxnOutcome::FailedInsufficientFunds => unreachable!(),
+        };
+        let remaining_volume = target_vol - volume_transacted;
+        if remaining_volume == Volume::new(0) {
+            return LimitOutcome::Filled;
+        }
+        match time_in_force {
+            TimeInForce::GoodTillCancel => {
+                let quote = Quote::with_display(taker.order_id, remaining_volume, display_volume)
+                    .with_owner(taker.owner);
+                self.add_ask(target_price, quote).assert_placed();
+                LimitOutcome::Rested { remaining_volume }
+            }
+            TimeInForce::ImmediateOrCancel | TimeInForce::FillOrKill => {
+                LimitOutcome::Expired { remaining_volume }
+            }
+        }
+    }
+
+    // Post-only orders never take liquidity, so they're good-till-cancel
+    // (the account layer turns away any other time in force). If the order
+    // would cross the spread it is either rejected outright or slid to one
+    // tick behind the best ask - so long as there's a price there.
+    fn post_bid(&mut self, price: Price, quote: Quote, post_only: PostOnly) -> LimitOutcome {
+        let volume = quote.open_volume();
+        if !matches!(self.add_bid(price, quote), Outcome::CrossedSpread) {
+            return LimitOutcome::Rested {
+                remaining_volume: volume,
+            };
+        }
+        let behind =
+            (self.best_ask.inner().checked_sub(self.tick_size.inner())).filter(|&price| price != 0);
+        match (post_only, behind) {
+            (PostOnly::Reprice, Some(price)) => {
+                let price = Price::new(price);
+                self.add_bid(price, quote).assert_placed();
+                LimitOutcome::Repriced {
+                    price,
+                    remaining_volume: volume,
+                }
+            }
+            (PostOnly::Reprice | PostOnly::Reject, _) => LimitOutcome::WouldCross,
+        }
+    }
+
+    // As `post_bid`, sliding to one tick above the best bid
+    fn post_ask(&mut self, price: Price, quote: Quote, post_only: PostOnly) -> LimitOutcome {
+        let volume = quote.open_volume();
+        if !matches!(self.add_ask(price, quote), Outcome::CrossedSpread) {
+            return LimitOutcome::Rested {
+                remaining_volume: volume,
+            };
+        }
+        let behind = self.best_bid.inner().checked_add(self.tick_size.inner());
+        match (post_only, behind) {
+            (PostOnly::Reprice, Some(price)) => {
+                let price = Price::new(price);
+                self.add_ask(price, quote).assert_placed();
+                LimitOutcome::Repriced {
+                    price,
+                    remaining_volume: volume,
+                }
+            }
+            (PostOnly::Reprice | PostOnly::Reject, _) => LimitOutcome::WouldCross,
+        }
+    }
+}
+
+// How much volume `quote` buys (or sells for) walking through the levels, in
+// whole lots
+fn volume_for_quote<'a>(
+    levels: impl Iterator<Item = (&'a Price, &'a Level)>,
+    quote: Balance,
+    lot_size: Volume,
+) -> Volume {
+    let mut rem_quote = quote.inner();
+    let mut vol = 0;
+    for (price, level) in levels {
+        let depth = level.depth();
+        match price.notional(depth) {
+            Some(cost) if cost.inner() < rem_quote => {
+                rem_quote -= cost.inner();
+                vol += depth.inner();
+            }
+            _ => {
+                // final level - take what we can pay for
+                if price.inner() != 0 {
+                    vol += rem_quote / price.inner();
+                }
+                break;
+            }
+        }
+    }
+    Volume::new(vol - vol % lot_size.inner())
+}
+
+// Whether the levels hold at least `target_vol` the taker can trade with. Under
+// self-trade prevention the taker's own orders don't count: `CancelOldest`
+// skips past them, and anything else stops short (or loses volume) at the
+// first one - by which point icebergs ahead of it have only matched what they
+// were displaying.
+fn can_fill<'a>(
+    levels: impl Iterator<Item = (&'a Price, &'a Level)>,
+    taker: &Taker,
+    target_vol: Volume,
+) -> bool {
+    let mut depth = Volume::new(0);
+    for (_, level) in levels {
+        match taker.stp {
+            Some(stp) if level.iter_quotes().any(|q| taker.owns(q)) => {
+                for q in level.iter_quotes() {
+                    match (taker.owns(q), stp) {
+                        (false, SelfTradePrevention::CancelOldest) => depth += q.open_volume(),
+                        (false, _) => depth += q.volume,
+                        (true, SelfTradePrevention::CancelOldest) => {}
+                        (true, _) => return depth >= target_vol,
+                    }
+                }
+            }
+            _ => depth += level.depth(),
+        }
+        if depth >= target_vol {
+            return true;
+        }
+    }
+    depth >= target_vol
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum TimeInForce {
+    /// Rest any unfilled remainder on the book
+    #[default]
+    GoodTillCancel,
+    /// Match what we can, discard the remainder
+    ImmediateOrCancel,
+    /// Fill completely or not at all - the book is untouched if we cannot fill
+    FillOrKill,
+}
+
+/// What to do with a post-only (maker-only) order which would cross the spread
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PostOnly {
+    Reject,
+    /// Slide the order to one tick behind the opposite best price
+    Reprice,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub enum LimitOutcome {
+    Filled,
+    Rested {
+        remaining_volume: Volume,
+    },
+    /// Unfilled remainder discarded (IOC)
+    Expired {
+        remaining_volume: Volume,
+    },
+    /// Not enough depth to fill completely (FOK), nothing was matched
+    Killed,
+    /// Post-only order rested at a new price to avoid crossing the spread
+    Repriced {
+        price: Price,
+        remaining_volume: Volume,
+    },
+    /// Post-only order would have crossed the spread, nothing was matched
+    WouldCross,
+    /// Iceberg order with a zero display volume, nothing was matched
+    NothingDisplayed,
+}
+
+impl LimitOutcome {
+    // Orders which did not end up either filled or resting are reported as cancelled
+    fn report(self, order_id: OrderId, volume: Volume) -> Option<BookEvent> {
+        match self {
+            LimitOutcome::Filled | LimitOutcome::Rested { .. } => None,
+            LimitOutcome::Expired { remaining_volume } => Some(BookEvent::Cancelled {
+                order_id,
+                remaining_volume,
+                reason: CancelReason::Expired,
+            }),
+            LimitOutcome::Killed => Some(BookEvent::Cancelled {
+                order_id,
+                remaining_volume: volume,
+                reason: CancelReason::Killed,
+            }),
+            LimitOutcome::Repriced { price, .. } => Some(BookEvent::Repriced { order_id, price }),
+            LimitOutcome::WouldCross => Some(BookEvent::Cancelled {
+                order_id,
+                remaining_volume: volume,
+                reason: CancelReason::PostOnly,
+            }),
+            LimitOutcome::NothingDisplayed => Some(BookEvent::Cancelled {
+                order_id,
+                remaining_volume: volume,
+                reason: CancelReason::NothingDisplayed,
+            }),
         }
     }
 }
 
-enum Cancellation {
-    WasCancelled,
+#[derive(PartialEq, Eq, Debug)]
+pub enum Cancellation {
+    Cancelled { remaining_volume: Volume },
+    NotFound,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub enum Amendment {
+    Amended {
+        /// Where the order now rests - a post-only order may have been
+        /// repriced
+        price: Price,
+        previous_volume: Volume,
+        kept_priority: bool,
+    },
+    /// Amended to nothing, or a post-only order that would have crossed, so
+    /// pulled from the book
+    Cancelled {
+        remaining_volume: Volume,
+        reason: CancelReason,
+    },
     NotFound,
 }
 
@@ -450,24 +1067,116 @@ impl TxnOutcome {
         }
         panic!("expected FailedInsufficientFunds, got: {self:?}")
     }
+
+    // Market orders never rest, so unless a fill or self-trade prevention
+    // already finished the taker, say what happened to the rest of it. Quote
+    // denominated orders (`target_vol` of `None`) report no remaining volume.
+    fn report(
+        self,
+        order_id: OrderId,
+        target_vol: Option<Volume>,
+        taker_done: bool,
+    ) -> Option<BookEvent> {
+        if taker_done {
+            return None;
+        }
+        let (remaining_volume, reason) = match self {
+            TxnOutcome::Filled { .. } => (Volume::new(0), CancelReason::Expired),
+            TxnOutcome::PartiallyFilled {
+                volume_transacted, ..
+            }
+            | TxnOutcome::MarketVolumeExhausted { volume_transacted } => (
+                target_vol.map_or(Volume::new(0), |vol| vol - volume_transacted),
+                CancelReason::Expired,
+            ),
+            TxnOutcome::FailedInsufficientFunds => {
+                (target_vol.unwrap_or_default(), CancelReason::Killed)
+            }
+        };
+        Some(BookEvent::Cancelled {
+            order_id,
+            remaining_volume,
+            reason,
+        })
+    }
 }
 
 enum OrderTarget {
     LimitBuy(Price),
     LimitSell(Price),
-    MarketBuy { available_quote_balance: Balance },
+    // funds were checked before matching started
+    MarketBuy,
     MarketSell,
 }
 
+/// The incoming order doing the matching
+#[derive(Copy, Clone, Debug)]
+pub struct Taker {
+    pub order_id: OrderId,
+    /// Anonymous takers (`None`) never self-trade
+    pub owner: Option<UserId>,
+    pub stp: Option<SelfTradePrevention>,
+}
+
+impl Taker {
+    // whether `quote` is one of the taker's own resting orders
+    fn owns(&self, quote: &Quote) -> bool {
+        self.owner.is_some() && quote.owner == self.owner
+    }
+}
+
+impl From<OrderId> for Taker {
+    // an anonymous taker which never self-trades
+    fn from(order_id: OrderId) -> Self {
+        Taker {
+            order_id,
+            owner: None,
+            stp: None,
+        }
+    }
+}
+
+/// What to do when a taker would match a resting order with the same owner
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SelfTradePrevention {
+    /// Cancel the rest of the taker
+    CancelNewest,
+    /// Cancel the resting order and carry on matching
+    CancelOldest,
+    CancelBoth,
+    /// Take the smaller of the two volumes off both orders, cancelling
+    /// whichever is left with nothing
+    DecrementAndCancel,
+}
+
+// Volume taken off an order by self-trade prevention
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+struct SelfTrade {
+    order_id: OrderId,
+    price: Price,
+    volume: Volume,
+    // nothing left of the order
+    cancelled: bool,
+}
+
+// Note that taker volume removed by self-trade prevention counts towards
+// `volume_transacted` - as far as the book is concerned it is used up.
 fn execute_market_txn<'a>(
     price_levels: impl Iterator<Item = (&'a Price, &'a mut Level)>,
-    order_id: OrderId,
+    index: &mut OrderIndex,
+    self_trades: &mut Vec<SelfTrade>,
+    taker: Taker,
     target_vol: Volume,
     target_price: OrderTarget,
     matches: &mut Vec<Match>,
 ) -> TxnOutcome {
+    let order_id = taker.order_id;
     let mut remaining_txn_vol = target_vol;
     for (&price, level) in price_levels {
+        if level.depth() == Volume::new(0) {
+            // emptied earlier - nothing to match, and no best price
+            continue;
+        }
         match target_price {
             OrderTarget::LimitBuy(max_buy_price) => {
                 if max_buy_price < price {
@@ -487,7 +1196,7 @@ fn execute_market_txn<'a>(
                     };
                 }
             }
-            OrderTarget::MarketBuy { .. } | OrderTarget::MarketSell => {
+            OrderTarget::MarketBuy | OrderTarget::MarketSell => {
                 // no problem
             }
         }
@@ -496,68 +1205,129 @@ fn execute_market_txn<'a>(
             return TxnOutcome::Filled {
                 new_best_price: price,
             };
-        } else if remaining_txn_vol >= level.total_volume {
+        }
+        let may_self_trade = taker.stp.is_some() && level.iter_quotes().any(|q| taker.owns(q));
+        if remaining_txn_vol >= level.depth() && !may_self_trade {
             // will exhaust this level
-            remaining_txn_vol -= level.total_volume;
-            for q in level.iter_quotes() {
-                let matchty = if remaining_txn_vol == Volume::new(0) {
+            remaining_txn_vol -= level.depth();
+            let count = level.iter_quotes().count();
+            for (i, q) in level.iter_quotes().enumerate() {
+                // only the final maker can fill the taker too
+                let matchty = if i + 1 == count && remaining_txn_vol == Volume::new(0) {
                     MatchType::BothFilled
                 } else {
                     MatchType::MakerFilled
                 };
-                matches.push(Match::new(q.order_id, order_id, price, q.volume, matchty));
+                matches.push(Match::new(
+                    q.order_id,
+                    order_id,
+                    price,
+                    q.open_volume(),
+                    matchty,
+                ));
             }
-            level.clear();
+            level.clear(index);
             // continue to next price level
-        } else {
-            // will end at this level
-            level.total_volume -= remaining_txn_vol;
-
-            let mut tombstone_inc = 0;
-            for q in level.iter_quotes_mut() {
-                if remaining_txn_vol < q.volume {
-                    // taker filled (and we're done)
-                    q.volume -= remaining_txn_vol;
-                    matches.push(Match::new(
-                        q.order_id,
-                        order_id,
-                        price,
-                        remaining_txn_vol,
-                        MatchType::TakerFilled,
-                    ));
-                    break;
-                } else if remaining_txn_vol == q.volume {
-                    // both filled (and we're done)
-                    matches.push(Match::new(
-                        q.order_id,
-                        order_id,
+            continue;
+        }
+        // Work through the queue - icebergs which run out of displayed volume
+        // rejoin at the back, so we may well see the same order more than once
+        let mut slot = 0;
+        while remaining_txn_vol != Volume::new(0) && slot < level.quotes.len() {
+            let q = level.quotes[slot];
+            slot += 1;
+            if q.is_tombstone() {
+                continue;
+            }
+            if let Some(stp) = taker.stp.filter(|_| taker.owns(&q)) {
+                let (maker_cut, taker_cut) = match stp {
+                    SelfTradePrevention::CancelNewest => (Volume::new(0), remaining_txn_vol),
+                    SelfTradePrevention::CancelOldest => (q.open_volume(), Volume::new(0)),
+                    SelfTradePrevention::CancelBoth => (q.open_volume(), remaining_txn_vol),
+                    SelfTradePrevention::DecrementAndCancel => {
+                        let cut = std::cmp::min(q.open_volume(), remaining_txn_vol);
+                        (cut, cut)
+                    }
+                };
+                if maker_cut == q.open_volume() {
+                    level.total_volume -= q.volume;
+                    level.hidden_volume -= q.reserve;
+                    level.quotes[slot - 1] = Quote::tombstone();
+                    level.tombstone_count += 1;
+                    index.remove(&q.order_id);
+                } else if maker_cut != Volume::new(0) {
+                    let shown_cut = level.quotes[slot - 1].reduce(maker_cut);
+                    level.total_volume -= shown_cut;
+                    level.hidden_volume -= maker_cut - shown_cut;
+                }
+                if maker_cut != Volume::new(0) {
+                    self_trades.push(SelfTrade {
+                        order_id: q.order_id,
                         price,
-                        remaining_txn_vol,
-                        MatchType::BothFilled,
-                    ));
-                    *q = Quote::tombstone();
-                    break;
-                } else {
-                    // maker filled (and continue)
-                    remaining_txn_vol -= q.volume;
-                    matches.push(Match::new(
-                        q.order_id,
+                        volume: maker_cut,
+                        cancelled: maker_cut == q.open_volume(),
+                    });
+                }
+                if taker_cut != Volume::new(0) {
+                    remaining_txn_vol -= taker_cut;
+                    self_trades.push(SelfTrade {
                         order_id,
                         price,
-                        q.volume,
-                        MatchType::MakerFilled,
-                    ));
-                    *q = Quote::tombstone();
-                    tombstone_inc += 1;
+                        volume: taker_cut,
+                        cancelled: remaining_txn_vol == Volume::new(0),
+                    });
                 }
+                continue;
             }
-            level.tombstone_count += tombstone_inc;
-            level.maybe_compact();
-            // we're done
-            return TxnOutcome::Filled {
+            if remaining_txn_vol < q.volume {
+                // taker filled (and we're done)
+                level.quotes[slot - 1].volume -= remaining_txn_vol;
+                level.total_volume -= remaining_txn_vol;
+                matches.push(Match::new(
+                    q.order_id,
+                    order_id,
+                    price,
+                    remaining_txn_vol,
+                    MatchType::TakerFilled,
+                ));
+                remaining_txn_vol = Volume::new(0);
+                break;
+            }
+            // displayed volume used up
+            remaining_txn_vol -= q.volume;
+            level.total_volume -= q.volume;
+            level.quotes[slot - 1] = Quote::tombstone();
+            level.tombstone_count += 1;
+            let taker_filled = remaining_txn_vol == Volume::new(0);
+            let matchty = if q.reserve != Volume::new(0) {
+                let next = q.replenish();
+                level.total_volume += next.volume;
+                level.hidden_volume -= next.volume;
+                index.insert(q.order_id, (price, level.quotes.len()));
+                level.quotes.push(next);
+                if taker_filled {
+                    MatchType::TakerFilled
+                } else {
+                    MatchType::NeitherFilled
+                }
+            } else {
+                index.remove(&q.order_id);
+                if taker_filled {
+                    MatchType::BothFilled
+                } else {
+                    MatchType::MakerFilled
+                }
+            };
+            matches.push(Match::new(q.order_id, order_id, price, q.volume, matchty));
+        }
+        level.maybe_compact(index);
+        if remaining_txn_vol == Volume::new(0) && level.depth() != Volume::new(0) {
+            // we're done
+            return TxnOutcome::Filled {
                 new_best_price: price,
             };
         }
+        // otherwise self-trade prevention emptied the level, on to the next
     }
     // if we get here then we used up all the volume
     TxnOutcome::MarketVolumeExhausted {
@@ -607,15 +1377,47 @@ pub enum OrderType {
     LimitBuy {
         price: Price,
         volume: Volume,
+        time_in_force: TimeInForce,
+        post_only: Option<PostOnly>,
+        /// Make this an iceberg, displaying at most this much at a time
+        display_volume: Option<Volume>,
     },
     LimitSell {
         price: Price,
         volume: Volume,
+        time_in_force: TimeInForce,
+        post_only: Option<PostOnly>,
+        /// Make this an iceberg, displaying at most this much at a time
+        display_volume: Option<Volume>,
     },
     Cancel {
-        price: Price,
         order_id: OrderId,
     },
+    /// Change the price and/or open volume of a resting limit order. No
+    /// volume cancels it.
+    Amend {
+        order_id: OrderId,
+        new_price: Price,
+        new_volume: Volume,
+    },
+    /// Held back until the market trades through `stop_price`, then
+    /// released as a market order. A triggered buy only takes as much of
+    /// `volume` as `available_quote_balance` pays for at the prices then on
+    /// the book (sells ignore it).
+    StopMarket {
+        side: Side,
+        stop_price: Price,
+        volume: Volume,
+        available_quote_balance: Balance,
+    },
+    /// Held back until the market trades through `stop_price`, then
+    /// released as a (good-till-cancel) limit order at `limit_price`
+    StopLimit {
+        side: Side,
+        stop_price: Price,
+        limit_price: Price,
+        volume: Volume,
+    },
     /// Take a copy of the order book and send back
     /// along the snapshot channel
     SendSnapshot,
@@ -623,63 +1425,465 @@ pub enum OrderType {
 
 pub struct Order {
     pub id: OrderId,
+    /// `None` for orders nobody in particular placed, which never self-trade
+    pub owner: Option<UserId>,
+    /// How to handle matching against the owner's own resting orders. `None`
+    /// lets them trade.
+    pub stp: Option<SelfTradePrevention>,
     pub typ: OrderType,
 }
 
+struct StopOrder {
+    volume: Volume,
+    // what gets released into the book when the stop triggers
+    order: Order,
+}
+
+/// Stop orders waiting for the market to trade through them. A buy stop has
+/// to be placed above the last trade price and triggers once a trade prints
+/// at or above its stop price; a sell stop is placed below and triggers on a
+/// trade at or below. Stops the market has already reached are turned away
+/// rather than triggered at once, and before the first trade any stop is
+/// accepted. Triggered stops are released oldest-first, nearest stop first.
+#[derive(Default)]
+struct StopBook {
+    last_price: Option<Price>,
+    buys: BTreeMap<Price, VecDeque<StopOrder>>,
+    sells: BTreeMap<Price, VecDeque<StopOrder>>,
+    index: HashMap<OrderId, (Side, Price)>,
+}
+
+impl StopBook {
+    // whether the last trade is already at or through `stop_price`
+    fn reached(&self, side: Side, stop_price: Price) -> bool {
+        self.last_price.is_some_and(|last| match side {
+            Side::Buy => stop_price <= last,
+            Side::Sell => stop_price >= last,
+        })
+    }
+
+    fn insert(&mut self, side: Side, stop_price: Price, volume: Volume, order: Order) {
+        self.index.insert(order.id, (side, stop_price));
+        let stops = match side {
+            Side::Buy => &mut self.buys,
+            Side::Sell => &mut self.sells,
+        };
+        stops
+            .entry(stop_price)
+            .or_default()
+            .push_back(StopOrder { volume, order });
+    }
+
+    fn cancel(&mut self, order_id: OrderId) -> Option<Volume> {
+        let (side, stop_price) = self.index.remove(&order_id)?;
+        let stops = match side {
+            Side::Buy => &mut self.buys,
+            Side::Sell => &mut self.sells,
+        };
+        let level = stops
+            .get_mut(&stop_price)
+            .expect("indexed stop has no level");
+        let pos = level
+            .iter()
+            .position(|s| s.order.id == order_id)
+            .expect("indexed stop not found");
+        let stop = level.remove(pos).unwrap();
+        if level.is_empty() {
+            stops.remove(&stop_price);
+        }
+        Some(stop.volume)
+    }
+
+    // Take the next stop which the last trade price has triggered, if any
+    fn pop_triggered(&mut self) -> Option<(Order, Price)> {
+        let last_price = self.last_price?;
+        let mut level = match self.buys.first_entry() {
+            Some(e) if *e.key() <= last_price => e,
+            _ => match self.sells.last_entry() {
+                Some(e) if *e.key() >= last_price => e,
+                _ => return None,
+            },
+        };
+        let stop = level.get_mut().pop_front().expect("empty stop level");
+        if level.get().is_empty() {
+            level.remove();
+        }
+        self.index.remove(&stop.order.id);
+        Some((stop.order, last_price))
+    }
+}
+
+/// Everything the order book thread reports back, in the order it happened
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum BookEvent {
+    Match(Match),
+    Cancelled {
+        order_id: OrderId,
+        remaining_volume: Volume,
+        reason: CancelReason,
+    },
+    CancelRejected {
+        order_id: OrderId,
+    },
+    Amended {
+        order_id: OrderId,
+        price: Price,
+        volume: Volume,
+        previous_volume: Volume,
+        kept_priority: bool,
+    },
+    AmendRejected {
+        order_id: OrderId,
+    },
+    /// A post-only order was slid behind the spread and rests at `price`
+    Repriced {
+        order_id: OrderId,
+        price: Price,
+    },
+    /// A stop order was released into the book by a trade at `last_price`.
+    /// Its fills (if any) follow.
+    StopTriggered {
+        order_id: OrderId,
+        last_price: Price,
+    },
+    /// Self-trade prevention took `volume` off the order, which stays live
+    Decremented {
+        order_id: OrderId,
+        volume: Volume,
+    },
+}
+
+/// The displayed volume at a price once an order has been dealt with. No
+/// volume means the level has gone from that side.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct LevelChange {
+    pub side: Side,
+    pub price: Price,
+    pub volume: Volume,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CancelReason {
+    Requested,
+    /// IOC remainder that could not be matched
+    Expired,
+    /// FOK order that could not be filled in full
+    Killed,
+    /// Post-only order that would have taken liquidity
+    PostOnly,
+    /// Removed by self-trade prevention
+    SelfTrade,
+    /// Stop order the market had already traded through when it was placed
+    StopReached,
+    /// Iceberg order asked to display nothing
+    NothingDisplayed,
+}
+
+/// Run `book` (usually a new one, set up for the market's increments)
 pub fn run_orderbook_event_loop(
+    book: OrderBook,
     order_rx: Receiver<Order>,
-    match_tx: Sender<Match>,
+    event_tx: Sender<BookEvent>,
     snapshot_tx: Sender<OrderBook>,
+    depth_tx: Sender<Vec<LevelChange>>,
 ) {
-    let mut book = OrderBook::new();
+    let mut book = book;
+    let mut stops = StopBook::default();
     let mut matches_buffer = Vec::with_capacity(1000);
-    loop {
-        let order = order_rx.recv().unwrap();
-        match order.typ {
-            OrderType::MarketBuy {
-                target_base_qty,
-                available_quote_balance,
-            } => {
-                book.execute_market_buy(
-                    order.id,
-                    target_base_qty,
+    // runs until every sender has gone and all queued orders are processed
+    while let Ok(order) = order_rx.recv() {
+        let mut next = Some(order);
+        // keep going until this order and every stop it triggers (directly or
+        // otherwise) has been dealt with
+        while let Some(order) = next {
+            // the levels the order has changed, reported once it's dealt with
+            let best_before = (book.best_bid, book.best_ask);
+            let target = match order.typ {
+                OrderType::Cancel { order_id } | OrderType::Amend { order_id, .. } => order_id,
+                _ => order.id,
+            };
+            let mut touched: Vec<Price> = book.order_price(target).into_iter().collect();
+            // reported after any fills the order generated
+            let report = match order.typ {
+                OrderType::Cancel { order_id } => match book.cancel(order_id) {
+                    Cancellation::Cancelled { remaining_volume } => Some(BookEvent::Cancelled {
+                        order_id,
+                        remaining_volume,
+                        reason: CancelReason::Requested,
+                    }),
+                    Cancellation::NotFound => match stops.cancel(order_id) {
+                        Some(remaining_volume) => Some(BookEvent::Cancelled {
+                            order_id,
+                            remaining_volume,
+                            reason: CancelReason::Requested,
+                        }),
+                        None => Some(BookEvent::CancelRejected { order_id }),
+                    },
+                },
+                OrderType::Amend {
+                    order_id,
+                    new_price,
+                    new_volume,
+                } => {
+                    // report the amendment first, then any fills it caused
+                    let ev = match book.amend(
+                        order_id,
+                        new_price,
+                        new_volume,
+                        order.stp,
+                        &mut matches_buffer,
+                    ) {
+                        Amendment::Amended {
+                            price,
+                            previous_volume,
+                            kept_priority,
+                        } => BookEvent::Amended {
+                            order_id,
+                            price,
+                            volume: new_volume,
+                            previous_volume,
+                            kept_priority,
+                        },
+                        Amendment::Cancelled {
+                            remaining_volume,
+                            reason,
+                        } => BookEvent::Cancelled {
+                            order_id,
+                            remaining_volume,
+                            reason,
+                        },
+                        Amendment::NotFound => BookEvent::AmendRejected { order_id },
+                    };
+                    event_tx.send(ev).expect("event_tx send failed");
+                    None
+                }
+                OrderType::StopMarket {
+                    side,
+                    stop_price,
+                    volume,
+                    ..
+                }
+                | OrderType::StopLimit {
+                    side,
+                    stop_price,
+                    volume,
+                    ..
+                } if stops.reached(side, stop_price) => Some(BookEvent::Cancelled {
+                    order_id: order.id,
+                    remaining_volume: volume,
+                    reason: CancelReason::StopReached,
+                }),
+                OrderType::StopMarket {
+                    side,
+                    stop_price,
+                    volume,
                     available_quote_balance,
-                    &mut matches_buffer,
-                );
+                } => {
+                    let typ = match side {
+                        Side::Buy => OrderType::MarketBuy {
+                            target_base_qty: volume,
+                            available_quote_balance,
+                        },
+                        Side::Sell => OrderType::MarketSell { base_qty: volume },
+                    };
+                    let release = Order { typ, ..order };
+                    stops.insert(side, stop_price, volume, release);
+                    None
+                }
+                OrderType::StopLimit {
+                    side,
+                    stop_price,
+                    limit_price,
+                    volume,
+                } => {
+                    let (price, time_in_force) = (limit_price, TimeInForce::GoodTillCancel);
+                    let typ = match side {
+                        Side::Buy => OrderType::LimitBuy {
+                            price,
+                            volume,
+                            time_in_force,
+                            post_only: None,
+                            display_volume: None,
+                        },
+                        Side::Sell => OrderType::LimitSell {
+                            price,
+                            volume,
+                            time_in_force,
+                            post_only: None,
+                            display_volume: None,
+                        },
+                    };
+                    let release = Order { typ, ..order };
+                    stops.insert(side, stop_price, volume, release);
+                    None
+                }
+                OrderType::SendSnapshot => {
+                    snapshot_tx.send(book.clone()).unwrap();
+                    None
+                }
+                typ => {
+                    let taker = Taker {
+                        order_id: order.id,
+                        owner: order.owner,
+                        stp: order.stp,
+                    };
+                    execute_order(&mut book, taker, typ, &mut matches_buffer)
+                }
+            };
+            for &fill in matches_buffer.iter() {
+                touched.push(fill.price);
+                event_tx
+                    .send(BookEvent::Match(fill))
+                    .expect("event_tx send failed");
             }
-
-            OrderType::MarketSell { base_qty } => {
-                book.execute_market_sell(order.id, base_qty, &mut matches_buffer);
+            for st in book.self_trades.drain(..) {
+                touched.push(st.price);
+                let ev = if st.cancelled {
+                    BookEvent::Cancelled {
+                        order_id: st.order_id,
+                        remaining_volume: st.volume,
+                        reason: CancelReason::SelfTrade,
+                    }
+                } else {
+                    BookEvent::Decremented {
+                        order_id: st.order_id,
+                        volume: st.volume,
+                    }
+                };
+                event_tx.send(ev).expect("event_tx send failed");
             }
-            OrderType::MarketBuyQ {
-                target_quote_balance,
-            } => todo!(),
-            OrderType::MarketSellQ {
-                target_quote_balance,
-                available_base_qty,
-            } => todo!(),
-            OrderType::LimitBuy { price, volume } => {
-                book.execute_limit_buy_order(order.id, price, volume, &mut matches_buffer)
+            if let Some(last) = matches_buffer.last() {
+                stops.last_price = Some(last.price);
             }
-            OrderType::LimitSell { price, volume } => {
-                book.execute_limit_sell_order(order.id, price, volume, &mut matches_buffer)
+            matches_buffer.clear();
+            if let Some(ev) = report {
+                event_tx.send(ev).expect("event_tx send failed");
             }
-            OrderType::Cancel { price, order_id } => {
-                match book.cancel(price, order_id) {
-                    Cancellation::WasCancelled => {}
-                    Cancellation::NotFound => todo!(),
-                };
-                continue;
+            touched.extend(book.order_price(target));
+            touched.sort();
+            touched.dedup();
+            let changes = book.level_changes(&touched, best_before);
+            if !changes.is_empty() {
+                // nobody has to follow the depth
+                let _ = depth_tx.send(changes);
             }
-            OrderType::SendSnapshot => snapshot_tx.send(book.clone()).unwrap(),
-        }
-        for &fill in matches_buffer.iter() {
-            match_tx.send(fill).expect("tx_fill send failed");
+            next = stops.pop_triggered().map(|(mut order, last_price)| {
+                if let OrderType::MarketBuy {
+                    target_base_qty,
+                    available_quote_balance,
+                } = &mut order.typ
+                {
+                    let budget = *available_quote_balance;
+                    let affordable = volume_for_quote(book.ask_levels(), budget, book.lot_size);
+                    *target_base_qty = std::cmp::min(*target_base_qty, affordable);
+                }
+                event_tx
+                    .send(BookEvent::StopTriggered {
+                        order_id: order.id,
+                        last_price,
+                    })
+                    .expect("event_tx send failed");
+                order
+            });
         }
-        matches_buffer.clear();
     }
 }
 
+// Run an order which trades against the book
+fn execute_order(
+    book: &mut OrderBook,
+    taker: Taker,
+    typ: OrderType,
+    matches_buffer: &mut Vec<Match>,
+) -> Option<BookEvent> {
+    let order_id = taker.order_id;
+    let (res, target_vol) = match typ {
+        OrderType::MarketBuy {
+            target_base_qty,
+            available_quote_balance,
+        } => (
+            book.execute_market_buy(
+                taker,
+                target_base_qty,
+                available_quote_balance,
+                matches_buffer,
+            ),
+            Some(target_base_qty),
+        ),
+        OrderType::MarketSell { base_qty } => (
+            book.execute_market_sell(taker, base_qty, matches_buffer),
+            Some(base_qty),
+        ),
+        OrderType::MarketBuyQ {
+            target_quote_balance,
+        } => (
+            book.execute_market_buy_quote(taker, target_quote_balance, matches_buffer),
+            None,
+        ),
+        OrderType::MarketSellQ {
+            target_quote_balance,
+            available_base_qty,
+        } => (
+            book.execute_market_sell_quote(
+                taker,
+                target_quote_balance,
+                available_base_qty,
+                matches_buffer,
+            ),
+            None,
+        ),
+        OrderType::LimitBuy {
+            price,
+            volume,
+            time_in_force,
+            post_only,
+            display_volume,
+        } => {
+            let res = book.execute_limit_buy_order(
+                taker,
+                price,
+                volume,
+                time_in_force,
+                post_only,
+                display_volume,
+                matches_buffer,
+            );
+            return res.report(order_id, volume);
+        }
+        OrderType::LimitSell {
+            price,
+            volume,
+            time_in_force,
+            post_only,
+            display_volume,
+        } => {
+            let res = book.execute_limit_sell_order(
+                taker,
+                price,
+                volume,
+                time_in_force,
+                post_only,
+                display_volume,
+                matches_buffer,
+            );
+            return res.report(order_id, volume);
+        }
+        OrderType::Cancel { .. }
+        | OrderType::Amend { .. }
+        | OrderType::StopMarket { .. }
+        | OrderType::StopLimit { .. }
+        | OrderType::SendSnapshot => unreachable!("not a trading order"),
+    };
+    let taker_done = matches_buffer
+        .last()
+        .is_some_and(|m| matches!(m.typ, MatchType::TakerFilled | MatchType::BothFilled))
+        || book
+            .self_trades
+            .iter()
+            .any(|st| st.order_id == order_id && st.cancelled);
+    res.report(order_id, target_vol, taker_done)
+}
+
 #[cfg(test)]
 mod tests {
     use std::time::Duration;
@@ -706,33 +1910,51 @@ mod tests {
     fn mb(maker: u64, taker: u64, price: u64, vol: u64) -> Match {
         Match::new(o(maker), o(taker), p(price), v(vol), MatchType::BothFilled)
     }
+    fn mn(maker: u64, taker: u64, price: u64, vol: u64) -> Match {
+        Match::new(
+            o(maker),
+            o(taker),
+            p(price),
+            v(vol),
+            MatchType::NeitherFilled,
+        )
+    }
     fn q(q: u64, v: u64) -> Quote {
-        Quote {
-            order_id: OrderId::new(q),
-            volume: Volume::new(v),
-        }
+        Quote::new(OrderId::new(q), Volume::new(v))
     }
     fn olb(id: u64, price: u64, vol: u64) -> Order {
         Order {
             id: o(id),
+            owner: None,
+            stp: None,
             typ: OrderType::LimitBuy {
                 price: p(price),
                 volume: v(vol),
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: None,
+                display_volume: None,
             },
         }
     }
     fn ols(id: u64, price: u64, vol: u64) -> Order {
         Order {
             id: o(id),
+            owner: None,
+            stp: None,
             typ: OrderType::LimitSell {
                 price: p(price),
                 volume: v(vol),
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: None,
+                display_volume: None,
             },
         }
     }
     fn omb(id: u64, vol: u64) -> Order {
         Order {
             id: o(id),
+            owner: None,
+            stp: None,
             typ: OrderType::MarketBuy {
                 target_base_qty: v(vol),
                 available_quote_balance: b(1_000_000),
@@ -742,10 +1964,66 @@ mod tests {
     fn oms(id: u64, vol: u64) -> Order {
         Order {
             id: o(id),
+            owner: None,
+            stp: None,
             typ: OrderType::MarketSell { base_qty: v(vol) },
         }
     }
 
+    fn oc(id: u64, cancel_id: u64) -> Order {
+        Order {
+            id: o(id),
+            owner: None,
+            stp: None,
+            typ: OrderType::Cancel {
+                order_id: o(cancel_id),
+            },
+        }
+    }
+
+    fn ostop(id: u64, side: Side, stop: u64, vol: u64) -> Order {
+        Order {
+            id: o(id),
+            owner: None,
+            stp: None,
+            typ: OrderType::StopMarket {
+                side,
+                stop_price: p(stop),
+                volume: v(vol),
+                available_quote_balance: b(1_000_000),
+            },
+        }
+    }
+
+    // start an order book thread with the given orders, wait for it to
+    // process them and collect everything it reported
+    fn run_orders(orders: Vec<Order>) -> Vec<BookEvent> {
+        let (tx_order, rx_order) = crossbeam_channel::unbounded();
+        let (tx_event, rx_event) = crossbeam_channel::unbounded();
+        let (tx_snapshot, rx_snapshot) = crossbeam_channel::unbounded();
+        let (tx_depth, _rx_depth) = crossbeam_channel::unbounded();
+        std::thread::spawn(move || {
+            run_orderbook_event_loop(OrderBook::new(), rx_order, tx_event, tx_snapshot, tx_depth)
+        });
+        for order in orders {
+            tx_order.send(order).unwrap();
+        }
+        tx_order
+            .send(Order {
+                id: o(0),
+                owner: None,
+                stp: None,
+                typ: OrderType::SendSnapshot,
+            })
+            .unwrap();
+        rx_snapshot.recv_timeout(Duration::from_secs(1)).unwrap();
+        rx_event.try_iter().collect()
+    }
+
+    const GTC: TimeInForce = TimeInForce::GoodTillCancel;
+    const IOC: TimeInForce = TimeInForce::ImmediateOrCancel;
+    const FOK: TimeInForce = TimeInForce::FillOrKill;
+
     fn quick_book() -> OrderBook {
         let mut ob = OrderBook::new();
         ob.add_bid(p(10), q(1, 40));
@@ -867,6 +2145,16 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_exhaust_level_exactly() {
+        let mut book = OrderBook::new();
+        book.add_ask(p(10), q(1, 5));
+        book.add_ask(p(10), q(2, 5));
+        let mut fills = Vec::new();
+        book.execute_market_buy(o(100), v(10), b(1000), &mut fills);
+        assert_eq!(fills, &[mm(1, 100, 10, 5), mb(2, 100, 10, 5)]);
+    }
+
     #[test]
     fn test_execute_market_sell_simple() {
         let mut ob = quick_book();
@@ -911,7 +2199,7 @@ mod tests {
         }
         {
             book.add_ask(p(20), q(1, 10));
-            book.add_bid(p(10), q(1, 10));
+            book.add_bid(p(10), q(2, 10));
             book.execute_market_buy(o(104), v(0), b(10000), &mut matches)
                 .filled();
             book.execute_market_sell(o(105), v(0), &mut matches)
@@ -922,44 +2210,225 @@ mod tests {
     #[test]
     fn test_order_cancellation() {
         let mut book = quick_book();
-        assert!(matches!(
-            book.cancel(p(15), o(2)),
-            Cancellation::WasCancelled
-        ));
-        assert!(matches!(book.cancel(p(15), o(2)), Cancellation::NotFound));
-        assert!(matches!(book.cancel(p(20), o(222)), Cancellation::NotFound));
-        assert!(matches!(
-            book.cancel(p(35), o(5)),
-            Cancellation::WasCancelled
-        ));
+        assert_eq!(
+            book.cancel(o(2)),
+            Cancellation::Cancelled {
+                remaining_volume: v(30)
+            }
+        );
+        assert_eq!(book.cancel(o(2)), Cancellation::NotFound);
+        assert_eq!(book.cancel(o(222)), Cancellation::NotFound);
+        assert_eq!(
+            book.cancel(o(5)),
+            Cancellation::Cancelled {
+                remaining_volume: v(10)
+            }
+        );
         assert_eq!(book.ask_volume(), v(90));
+        assert_eq!(book.best_ask(), p(40));
         let mut fills = Vec::new();
         book.execute_market_buy(o(100), v(1), b(10000), &mut fills)
             .filled();
         assert_eq!(fills, &[mt(6, 100, 40, 1)]);
     }
 
+    #[test]
+    fn test_cancel_after_partial_fill() {
+        let mut book = OrderBook::new();
+        book.add_ask(p(10), q(1, 10));
+        book.add_ask(p(10), q(2, 10));
+        book.add_ask(p(11), q(3, 10));
+        let mut fills = Vec::new();
+        book.execute_market_buy(o(100), v(15), b(10000), &mut fills)
+            .filled();
+        // fully filled orders are gone from the index
+        assert_eq!(book.cancel(o(1)), Cancellation::NotFound);
+        assert_eq!(
+            book.cancel(o(2)),
+            Cancellation::Cancelled {
+                remaining_volume: v(5)
+            }
+        );
+        // level is now empty so best ask moves on
+        assert_eq!(book.best_ask(), p(11));
+        book.execute_market_buy(o(101), v(50), b(10000), &mut fills)
+            .exhausted();
+        assert_eq!(book.cancel(o(3)), Cancellation::NotFound);
+    }
+
+    #[test]
+    fn test_cancel_leaves_no_empty_level() {
+        let mut book = OrderBook::new();
+        book.add_ask(p(10), q(1, 5));
+        book.add_ask(p(11), q(2, 5));
+        book.add_ask(p(12), q(3, 5));
+        book.cancel(o(2));
+        assert!(book.ask_levels().all(|(&price, _)| price != p(11)));
+        let mut fills = Vec::new();
+        book.execute_market_buy(o(100), v(5), b(10000), &mut fills)
+            .filled();
+        // the best ask moves past where the cancelled order was
+        assert_eq!(book.best_ask(), p(12));
+        let outcome = book.execute_limit_buy_order(
+            o(101),
+            p(11),
+            v(1),
+            GTC,
+            Some(PostOnly::Reject),
+            None,
+            &mut fills,
+        );
+        assert_eq!(
+            outcome,
+            LimitOutcome::Rested {
+                remaining_volume: v(1)
+            }
+        );
+        assert_eq!(book.best_bid(), p(11));
+    }
+
+    #[test]
+    fn test_amend_reduce_keeps_priority() {
+        let mut book = OrderBook::new();
+        book.add_ask(p(10), q(1, 10));
+        book.add_ask(p(10), q(2, 10));
+        let mut fills = Vec::new();
+        assert_eq!(
+            book.amend(o(1), p(10), v(4), None, &mut fills),
+            Amendment::Amended {
+                price: p(10),
+                previous_volume: v(10),
+                kept_priority: true
+            }
+        );
+        assert!(fills.is_empty());
+        assert_eq!(book.ask_volume(), v(14));
+        book.execute_market_buy(o(100), v(5), b(10000), &mut fills)
+            .filled();
+        assert_eq!(fills, &[mm(1, 100, 10, 4), mt(2, 100, 10, 1)]);
+    }
+
+    #[test]
+    fn test_amend_loses_priority() {
+        let mut book = OrderBook::new();
+        book.add_bid(p(10), q(1, 10));
+        book.add_bid(p(10), q(2, 10));
+        let mut fills = Vec::new();
+        // increasing volume sends the order to the back of the queue
+        assert_eq!(
+            book.amend(o(1), p(10), v(15), None, &mut fills),
+            Amendment::Amended {
+                price: p(10),
+                previous_volume: v(10),
+                kept_priority: false
+            }
+        );
+        assert_eq!(book.levels.get(&p(10)).unwrap().total_volume, v(25));
+        book.execute_market_sell(o(100), v(12), &mut fills).filled();
+        assert_eq!(fills, &[mm(2, 100, 10, 10), mt(1, 100, 10, 2)]);
+
+        // moving price is a fresh order at the new level
+        fills.clear();
+        book.amend(o(1), p(12), v(13), None, &mut fills);
+        assert_eq!(book.best_bid(), p(12));
+        assert!(!book.levels.contains_key(&p(10)));
+        assert_eq!(
+            book.amend(o(222), p(12), v(13), None, &mut fills),
+            Amendment::NotFound
+        );
+    }
+
+    #[test]
+    fn test_amend_crosses_spread() {
+        let mut book = quick_book();
+        let mut fills = Vec::new();
+        // bid at 25 re-priced through the best ask
+        book.amend(o(4), p(40), v(20), None, &mut fills);
+        assert_eq!(fills, &[mm(5, 4, 35, 10), mt(6, 4, 40, 10)]);
+        assert_eq!(book.best_bid(), p(20));
+        assert_eq!(book.best_ask(), p(40));
+        // amending to zero cancels the order
+        fills.clear();
+        assert_eq!(
+            book.amend(o(6), p(40), v(0), None, &mut fills),
+            Amendment::Cancelled {
+                remaining_volume: v(10),
+                reason: CancelReason::Requested,
+            }
+        );
+        assert!(fills.is_empty());
+        assert_eq!(book.best_ask(), p(45));
+    }
+
+    #[test]
+    fn test_amend_keeps_post_only() {
+        let mut book = OrderBook::new();
+        book.add_ask(p(12), q(1, 10));
+        let mut fills = Vec::new();
+        for (id, post_only) in [(2, PostOnly::Reject), (3, PostOnly::Reprice)] {
+            let post = Some(post_only);
+            let outcome =
+                book.execute_limit_buy_order(o(id), p(10), v(5), GTC, post, None, &mut fills);
+            assert!(matches!(outcome, LimitOutcome::Rested { .. }));
+        }
+        // amending through the spread doesn't make either take liquidity
+        assert_eq!(
+            book.amend(o(2), p(12), v(5), None, &mut fills),
+            Amendment::Cancelled {
+                remaining_volume: v(5),
+                reason: CancelReason::PostOnly,
+            }
+        );
+        assert_eq!(
+            book.amend(o(3), p(12), v(5), None, &mut fills),
+            Amendment::Amended {
+                price: p(11),
+                previous_volume: v(5),
+                kept_priority: false,
+            }
+        );
+        assert!(fills.is_empty());
+        assert_eq!(book.best_bid(), p(11));
+        assert_eq!(book.ask_volume(), v(10));
+        assert_eq!(book.cancel(o(6)), Cancellation::NotFound);
+    }
+
     #[test]
     fn test_compactify() {
         let mut book = quick_book();
+        // keeps the level from emptying, which would remove it
+        book.add_ask(p(30), q(5000, 10));
         for id in 0..TOMBSTONE_GC_LIMIT - 1 {
             // build up a load of tombstones
             book.add_ask(p(30), q((id + 20) as u64, 10));
-            book.cancel(p(30), o((id + 20) as u64));
+            book.cancel(o((id + 20) as u64));
         }
         {
             let level = book.levels.get(&p(30)).unwrap();
-            assert_eq!(level.quotes.len() as u32, TOMBSTONE_GC_LIMIT - 1);
+            assert_eq!(level.quotes.len() as u32, TOMBSTONE_GC_LIMIT);
             assert_eq!(level.tombstone_count, TOMBSTONE_GC_LIMIT - 1);
         }
         // trigger a compactification
         book.add_ask(p(30), q(333333, 10));
-        book.cancel(p(30), o(333333));
+        book.cancel(o(333333));
         {
             let level = book.levels.get(&p(30)).unwrap();
-            assert_eq!(level.quotes.len(), 0);
+            assert_eq!(level.quotes.len(), 1);
             assert_eq!(level.tombstone_count, 0);
         }
+        // slots are re-indexed after compaction
+        book.add_ask(p(30), q(1002, 10));
+        for id in 0..TOMBSTONE_GC_LIMIT {
+            book.add_ask(p(30), q((id + 2000) as u64, 10));
+            book.cancel(o((id + 2000) as u64));
+        }
+        assert_eq!(
+            book.cancel(o(1002)),
+            Cancellation::Cancelled {
+                remaining_volume: v(10)
+            }
+        );
+        assert_eq!(book.levels.get(&p(30)).unwrap().total_volume, v(10));
     }
 
     #[test]
@@ -967,14 +2436,14 @@ mod tests {
         let mut book = quick_book();
         {
             let mut fills = Vec::new();
-            book.execute_limit_buy_order(o(100), p(38), v(50), &mut fills);
+            book.execute_limit_buy_order(o(100), p(38), v(50), GTC, None, None, &mut fills);
             assert_eq!(fills, &[mm(5, 100, 35, 10)]);
             assert_eq!(book.best_bid(), p(38));
             assert_eq!(book.best_ask(), p(40));
         }
         {
             let mut fills = Vec::new();
-            book.execute_limit_buy_order(o(101), p(40), v(1), &mut fills);
+            book.execute_limit_buy_order(o(101), p(40), v(1), GTC, None, None, &mut fills);
             assert_eq!(fills, &[mt(6, 101, 40, 1)])
         }
     }
@@ -984,22 +2453,263 @@ mod tests {
         let mut book = quick_book();
         {
             let mut matches = Vec::new();
-            book.execute_limit_sell_order(o(100), p(22), v(50), &mut matches);
+            book.execute_limit_sell_order(o(100), p(22), v(50), GTC, None, None, &mut matches);
             assert_eq!(matches, &[mm(4, 100, 25, 10)]);
             assert_eq!(book.best_bid(), p(20));
             assert_eq!(book.best_ask(), p(22));
         }
         {
             let mut matches = Vec::new();
-            book.execute_limit_sell_order(o(101), p(20), v(1), &mut matches);
+            book.execute_limit_sell_order(o(101), p(20), v(1), GTC, None, None, &mut matches);
             assert_eq!(matches, &[mt(3, 101, 20, 1)])
         }
     }
 
+    #[test]
+    fn test_limit_immediate_or_cancel() {
+        let mut book = quick_book();
+        let mut fills = Vec::new();
+        let res = book.execute_limit_buy_order(o(100), p(40), v(50), IOC, None, None, &mut fills);
+        assert_eq!(
+            res,
+            LimitOutcome::Expired {
+                remaining_volume: v(20)
+            }
+        );
+        assert_eq!(fills, &[mm(5, 100, 35, 10), mm(6, 100, 40, 20)]);
+        // nothing rests
+        assert_eq!(book.best_bid(), p(25));
+        assert_eq!(book.cancel(o(100)), Cancellation::NotFound);
+
+        fills.clear();
+        let res = book.execute_limit_sell_order(o(101), p(20), v(5), IOC, None, None, &mut fills);
+        assert_eq!(res, LimitOutcome::Filled);
+        assert_eq!(fills, &[mt(4, 101, 25, 5)]);
+    }
+
+    #[test]
+    fn test_limit_fill_or_kill() {
+        let mut book = quick_book();
+        let mut fills = Vec::new();
+        // only 30 available at or below 40
+        let res = book.execute_limit_buy_order(o(100), p(40), v(31), FOK, None, None, &mut fills);
+        assert_eq!(res, LimitOutcome::Killed);
+        assert!(fills.is_empty());
+        assert_eq!(book.ask_volume(), v(100));
+        assert_eq!(book.best_ask(), p(35));
+
+        let res = book.execute_limit_buy_order(o(101), p(40), v(30), FOK, None, None, &mut fills);
+        assert_eq!(res, LimitOutcome::Filled);
+        assert_eq!(fills, &[mm(5, 101, 35, 10), mb(6, 101, 40, 20)]);
+
+        fills.clear();
+        let res = book.execute_limit_sell_order(o(102), p(15), v(61), FOK, None, None, &mut fills);
+        assert_eq!(res, LimitOutcome::Killed);
+        assert!(fills.is_empty());
+        let res = book.execute_limit_sell_order(o(103), p(15), v(60), FOK, None, None, &mut fills);
+        assert_eq!(res, LimitOutcome::Filled);
+        assert_eq!(book.best_bid(), p(10));
+    }
+
+    #[test]
+    fn test_post_only() {
+        let mut book = quick_book();
+        let mut fills = Vec::new();
+        let reject = Some(PostOnly::Reject);
+        let reprice = Some(PostOnly::Reprice);
+        // resting without crossing is fine
+        let res = book.execute_limit_buy_order(o(100), p(30), v(5), GTC, reject, None, &mut fills);
+        assert_eq!(
+            res,
+            LimitOutcome::Rested {
+                remaining_volume: v(5)
+            }
+        );
+        assert_eq!(book.best_bid(), p(30));
+        let res = book.execute_limit_buy_order(o(101), p(35), v(5), GTC, reject, None, &mut fills);
+        assert_eq!(res, LimitOutcome::WouldCross);
+        let res = book.execute_limit_sell_order(o(102), p(20), v(5), GTC, reject, None, &mut fills);
+        assert_eq!(res, LimitOutcome::WouldCross);
+        assert!(fills.is_empty());
+        assert_eq!(book.cancel(o(101)), Cancellation::NotFound);
+
+        let res = book.execute_limit_buy_order(o(103), p(50), v(5), GTC, reprice, None, &mut fills);
+        assert_eq!(
+            res,
+            LimitOutcome::Repriced {
+                price: p(34),
+                remaining_volume: v(5)
+            }
+        );
+        let res =
+            book.execute_limit_sell_order(o(104), p(10), v(5), GTC, reprice, None, &mut fills);
+        assert_eq!(
+            res,
+            LimitOutcome::Repriced {
+                price: p(35),
+                remaining_volume: v(5)
+            }
+        );
+        assert!(fills.is_empty());
+        assert_eq!(book.best_bid(), p(34));
+        assert_eq!(book.best_ask(), p(35));
+        assert_eq!(book.ask_volume(), v(105));
+    }
+
+    #[test]
+    fn test_post_only_reprices_by_tick() {
+        let mut book = OrderBook::with_increments(p(5), v(1));
+        book.add_bid(p(20), q(1, 10));
+        book.add_ask(p(35), q(2, 10));
+        let mut fills = Vec::new();
+        let reprice = Some(PostOnly::Reprice);
+        let res = book.execute_limit_buy_order(o(3), p(50), v(5), GTC, reprice, None, &mut fills);
+        assert_eq!(
+            res,
+            LimitOutcome::Repriced {
+                price: p(30),
+                remaining_volume: v(5)
+            }
+        );
+        let res = book.execute_limit_sell_order(o(4), p(10), v(5), GTC, reprice, None, &mut fills);
+        assert_eq!(
+            res,
+            LimitOutcome::Repriced {
+                price: p(35),
+                remaining_volume: v(5)
+            }
+        );
+        assert!(fills.is_empty());
+
+        // no price one tick under the best ask
+        let mut book = OrderBook::with_increments(p(5), v(1));
+        book.add_ask(p(5), q(1, 10));
+        let res = book.execute_limit_buy_order(o(2), p(10), v(5), GTC, reprice, None, &mut fills);
+        assert_eq!(res, LimitOutcome::WouldCross);
+    }
+
+    #[test]
+    fn test_iceberg_matching() {
+        let mut book = OrderBook::new();
+        book.add_ask(p(10), Quote::iceberg(o(1), v(25), v(10)));
+        book.add_ask(p(10), q(2, 5));
+        book.add_ask(p(11), q(3, 5));
+        // only the displayed slice is visible
+        assert_eq!(book.ask_volume(), v(20));
+        let mut fills = Vec::new();
+        book.execute_market_buy(o(100), v(12), b(10000), &mut fills)
+            .filled();
+        // the iceberg replenishes and goes to the back of the queue
+        assert_eq!(fills, &[mn(1, 100, 10, 10), mt(2, 100, 10, 2)]);
+        assert_eq!(book.ask_volume(), v(18));
+
+        fills.clear();
+        let vol = book
+            .execute_market_buy(o(101), v(50), b(10000), &mut fills)
+            .exhausted();
+        assert_eq!(vol, v(23));
+        assert_eq!(
+            fills,
+            &[mm(2, 101, 10, 3), mm(1, 101, 10, 15), mm(3, 101, 11, 5)]
+        );
+        assert_eq!(book.cancel(o(1)), Cancellation::NotFound);
+    }
+
+    #[test]
+    fn test_iceberg_replenishes_within_level() {
+        let mut book = OrderBook::new();
+        let mut fills = Vec::new();
+        let res =
+            book.execute_limit_sell_order(o(1), p(10), v(30), GTC, None, Some(v(10)), &mut fills);
+        assert_eq!(
+            res,
+            LimitOutcome::Rested {
+                remaining_volume: v(30)
+            }
+        );
+        assert_eq!(book.ask_volume(), v(10));
+        // hidden volume counts towards FOK depth
+        let res = book.execute_limit_buy_order(o(100), p(10), v(25), FOK, None, None, &mut fills);
+        assert_eq!(res, LimitOutcome::Filled);
+        assert_eq!(
+            fills,
+            &[mn(1, 100, 10, 10), mn(1, 100, 10, 10), mt(1, 100, 10, 5)]
+        );
+        assert_eq!(book.ask_volume(), v(5));
+
+        // an iceberg has to show something
+        let res =
+            book.execute_limit_sell_order(o(3), p(10), v(30), GTC, None, Some(v(0)), &mut fills);
+        assert_eq!(res, LimitOutcome::NothingDisplayed);
+        assert_eq!(book.ask_volume(), v(5));
+        // and one built with a zero peak is just a plain quote
+        assert!(!Quote::iceberg(o(3), v(30), v(0)).is_iceberg());
+
+        // amending down eats into the hidden reserve first
+        book.add_bid(p(5), Quote::iceberg(o(2), v(30), v(10)));
+        book.amend(o(2), p(5), v(25), None, &mut fills);
+        assert_eq!(book.levels.get(&p(5)).unwrap().total_volume, v(10));
+        book.amend(o(2), p(5), v(8), None, &mut fills);
+        assert_eq!(book.levels.get(&p(5)).unwrap().total_volume, v(8));
+        assert_eq!(
+            book.cancel(o(1)),
+            Cancellation::Cancelled {
+                remaining_volume: v(5)
+            }
+        );
+    }
+
+    #[test]
+    fn test_market_buy_quote() {
+        let mut book = quick_book();
+        let mut fills = Vec::new();
+        // 10 @ 35 = 350, 20 @ 40 = 800, leaving 100 to spend at 45 -> 2 volume
+        book.execute_market_buy_quote(o(100), b(1250), &mut fills)
+            .filled();
+        assert_eq!(
+            fills,
+            &[mm(5, 100, 35, 10), mm(6, 100, 40, 20), mt(7, 100, 45, 2)]
+        );
+        fills.clear();
+        // not enough to buy a single unit
+        book.execute_market_buy_quote(o(101), b(44), &mut fills)
+            .filled();
+        assert!(fills.is_empty());
+        // spend more than the book holds
+        let vol = book
+            .execute_market_buy_quote(o(102), b(100_000), &mut fills)
+            .exhausted();
+        assert_eq!(vol, v(68));
+
+        // only whole lots are bought, so 100 at 45 gets just the one lot of 2
+        let mut book = OrderBook::with_increments(p(1), v(2));
+        book.add_ask(p(45), q(1, 10));
+        book.execute_market_buy_quote(o(100), b(100), &mut fills)
+            .filled();
+        assert_eq!(book.ask_volume(), v(8));
+    }
+
+    #[test]
+    fn test_market_sell_quote() {
+        let mut book = quick_book();
+        let mut fills = Vec::new();
+        // 10 @ 25 = 250, leaving 150 to receive at 20 -> 7 volume (140)
+        assert_eq!(book.volume_to_sell_for(b(400)), v(17));
+        book.execute_market_sell_quote(o(100), b(400), v(16), &mut fills)
+            .failed();
+        assert!(fills.is_empty());
+        book.execute_market_sell_quote(o(101), b(400), v(100), &mut fills)
+            .filled();
+        assert_eq!(fills, &[mm(4, 101, 25, 10), mt(3, 101, 20, 7)]);
+        assert_eq!(book.best_bid(), p(20));
+    }
+
     #[test]
     fn test_market_buy_balance_limited() {
         let mut book = quick_book();
         let mut matches = Vec::new();
+        assert_eq!(book.cost_to_buy(v(10)), Some(b(350)));
+        assert_eq!(book.cost_to_buy(v(25)), Some(b(950)));
         book.execute_market_buy(o(101), v(10), b(1), &mut matches)
             .failed();
         book.execute_market_buy(o(102), v(10), b(349), &mut matches)
@@ -1014,14 +2724,23 @@ mod tests {
         // finish rest
         book.execute_market_buy(o(106), v(500), b(10000), &mut matches)
             .exhausted();
+
+        // more than can be accounted for is more than anyone has
+        book.add_ask(p(u64::MAX / 2), q(107, 10));
+        assert_eq!(book.cost_to_buy(v(3)), None);
+        book.execute_market_buy(o(108), v(3), b(u64::MAX), &mut matches)
+            .failed();
     }
 
     #[test]
     fn test_run_order_book() {
         let (tx_order, rx_order) = crossbeam_channel::bounded(1000);
-        let (tx_match, rx_match) = crossbeam_channel::bounded(1000);
+        let (tx_event, rx_event) = crossbeam_channel::bounded(1000);
         let (tx_snapshot, _rx_snapshot) = crossbeam_channel::bounded(1000);
-        std::thread::spawn(move || run_orderbook_event_loop(rx_order, tx_match, tx_snapshot));
+        let (tx_depth, _rx_depth) = crossbeam_channel::unbounded();
+        std::thread::spawn(move || {
+            run_orderbook_event_loop(OrderBook::new(), rx_order, tx_event, tx_snapshot, tx_depth)
+        });
 
         // add three limit orders
         tx_order.send(olb(101, 10, 10)).unwrap();
@@ -1032,13 +2751,13 @@ mod tests {
             tx_order.send(oms(104, 31)).unwrap();
             std::thread::sleep(std::time::Duration::from_millis(30));
 
-            let f1 = rx_match.recv_timeout(Duration::from_secs(1)).unwrap();
-            let f2 = rx_match.try_recv().unwrap();
-            let f3 = rx_match.try_recv().unwrap();
-            assert_eq!(f1, mm(101, 104, 10, 10));
-            assert_eq!(f2, mm(102, 104, 9, 20));
-            assert_eq!(f3, mt(103, 104, 8, 1));
-            assert!(rx_match.try_recv().is_err());
+            let f1 = rx_event.recv_timeout(Duration::from_secs(1)).unwrap();
+            let f2 = rx_event.try_recv().unwrap();
+            let f3 = rx_event.try_recv().unwrap();
+            assert_eq!(f1, BookEvent::Match(mm(101, 104, 10, 10)));
+            assert_eq!(f2, BookEvent::Match(mm(102, 104, 9, 20)));
+            assert_eq!(f3, BookEvent::Match(mt(103, 104, 8, 1)));
+            assert!(rx_event.try_recv().is_err());
         }
 
         {
@@ -1046,9 +2765,9 @@ mod tests {
             tx_order.send(ols(201, 5, 100)).unwrap();
             std::thread::sleep(std::time::Duration::from_millis(30));
 
-            let f1 = rx_match.recv_timeout(Duration::from_secs(1)).unwrap();
-            assert_eq!(f1, mm(103, 201, 8, 29));
-            assert!(rx_match.try_recv().is_err());
+            let f1 = rx_event.recv_timeout(Duration::from_secs(1)).unwrap();
+            assert_eq!(f1, BookEvent::Match(mm(103, 201, 8, 29)));
+            assert!(rx_event.try_recv().is_err());
         }
 
         {
@@ -1056,9 +2775,380 @@ mod tests {
             tx_order.send(omb(301, 200)).unwrap();
             std::thread::sleep(std::time::Duration::from_millis(30));
 
-            let f1 = rx_match.recv_timeout(Duration::from_secs(1)).unwrap();
-            assert_eq!(f1, mm(201, 301, 5, 71));
-            assert!(rx_match.try_recv().is_err());
+            let f1 = rx_event.recv_timeout(Duration::from_secs(1)).unwrap();
+            assert_eq!(f1, BookEvent::Match(mm(201, 301, 5, 71)));
+            // the book ran out, so the rest of the order is reported
+            let e2 = rx_event.try_recv().unwrap();
+            assert_eq!(
+                e2,
+                BookEvent::Cancelled {
+                    order_id: o(301),
+                    remaining_volume: v(129),
+                    reason: CancelReason::Expired,
+                }
+            );
+            assert!(rx_event.try_recv().is_err());
+        }
+
+        {
+            // cancel
+            tx_order.send(olb(401, 4, 10)).unwrap();
+            tx_order.send(oc(402, 401)).unwrap();
+            tx_order.send(oc(403, 401)).unwrap();
+            let e1 = rx_event.recv_timeout(Duration::from_secs(1)).unwrap();
+            let e2 = rx_event.recv_timeout(Duration::from_secs(1)).unwrap();
+            assert_eq!(
+                e1,
+                BookEvent::Cancelled {
+                    order_id: o(401),
+                    remaining_volume: v(10),
+                    reason: CancelReason::Requested,
+                }
+            );
+            assert_eq!(e2, BookEvent::CancelRejected { order_id: o(401) });
         }
+
+        {
+            // amending to nothing is a cancel
+            tx_order.send(olb(501, 4, 10)).unwrap();
+            let amend = OrderType::Amend {
+                order_id: o(501),
+                new_price: p(4),
+                new_volume: v(0),
+            };
+            tx_order
+                .send(Order {
+                    id: o(502),
+                    owner: None,
+                    stp: None,
+                    typ: amend,
+                })
+                .unwrap();
+            let e1 = rx_event.recv_timeout(Duration::from_secs(1)).unwrap();
+            assert_eq!(
+                e1,
+                BookEvent::Cancelled {
+                    order_id: o(501),
+                    remaining_volume: v(10),
+                    reason: CancelReason::Requested,
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn test_depth_changes() {
+        let (tx_order, rx_order) = crossbeam_channel::unbounded();
+        let (tx_event, _rx_event) = crossbeam_channel::unbounded();
+        let (tx_snapshot, rx_snapshot) = crossbeam_channel::unbounded();
+        let (tx_depth, rx_depth) = crossbeam_channel::unbounded();
+        std::thread::spawn(move || {
+            run_orderbook_event_loop(OrderBook::new(), rx_order, tx_event, tx_snapshot, tx_depth)
+        });
+        for order in [
+            olb(1, 10, 10),
+            olb(2, 10, 5),
+            ols(3, 12, 4),
+            // takes the whole bid level
+            oms(4, 17),
+            // takes the ask level and rests the rest at the same price
+            olb(5, 12, 6),
+            oc(6, 5),
+            // nothing to cancel, nothing changes
+            oc(7, 99),
+        ] {
+            tx_order.send(order).unwrap();
+        }
+        tx_order
+            .send(Order {
+                id: o(0),
+                owner: None,
+                stp: None,
+                typ: OrderType::SendSnapshot,
+            })
+            .unwrap();
+        rx_snapshot.recv_timeout(Duration::from_secs(1)).unwrap();
+        let change = |side, price, volume| LevelChange {
+            side,
+            price: p(price),
+            volume: v(volume),
+        };
+        let (buy, sell) = (Side::Buy, Side::Sell);
+        assert_eq!(
+            rx_depth.try_iter().collect::<Vec<_>>(),
+            [
+                vec![change(buy, 10, 10)],
+                vec![change(buy, 10, 15)],
+                vec![change(sell, 12, 4)],
+                vec![change(buy, 10, 0)],
+                vec![change(sell, 12, 0), change(buy, 12, 2)],
+                vec![change(buy, 12, 0)],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stop_orders_cascade() {
+        let trig = |id, last| BookEvent::StopTriggered {
+            order_id: o(id),
+            last_price: p(last),
+        };
+        let m = |m| BookEvent::Match(m);
+        let events = run_orders(vec![
+            ols(1, 100, 10),
+            ols(2, 101, 10),
+            ols(3, 102, 10),
+            // no trade yet so nothing can trigger
+            ostop(50, Side::Buy, 101, 10),
+            ostop(51, Side::Buy, 102, 5),
+            ostop(52, Side::Buy, 110, 5),
+            omb(10, 10),
+            // trades at 101, triggering 50, which trades at 102, triggering 51
+            omb(11, 5),
+        ]);
+        assert_eq!(
+            events,
+            &[
+                m(mb(1, 10, 100, 10)),
+                m(mt(2, 11, 101, 5)),
+                trig(50, 101),
+                m(mm(2, 50, 101, 5)),
+                m(mt(3, 50, 102, 5)),
+                trig(51, 102),
+                m(mb(3, 51, 102, 5)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stop_orders_sell_and_cancel() {
+        let events = run_orders(vec![
+            olb(1, 100, 10),
+            olb(2, 99, 10),
+            ostop(50, Side::Sell, 99, 5),
+            Order {
+                id: o(51),
+                owner: None,
+                stp: None,
+                typ: OrderType::StopLimit {
+                    side: Side::Sell,
+                    stop_price: p(100),
+                    limit_price: p(101),
+                    volume: v(5),
+                },
+            },
+            ostop(52, Side::Sell, 100, 5),
+            oc(60, 52),
+            oms(10, 1),
+            // the market is already down at 100
+            ostop(53, Side::Sell, 100, 5),
+            ostop(54, Side::Buy, 99, 5),
+        ]);
+        assert_eq!(
+            events,
+            &[
+                BookEvent::Cancelled {
+                    order_id: o(52),
+                    remaining_volume: v(5),
+                    reason: CancelReason::Requested,
+                },
+                BookEvent::Match(mt(1, 10, 100, 1)),
+                // the stop limit rests without trading
+                BookEvent::StopTriggered {
+                    order_id: o(51),
+                    last_price: p(100),
+                },
+                BookEvent::Cancelled {
+                    order_id: o(53),
+                    remaining_volume: v(5),
+                    reason: CancelReason::StopReached,
+                },
+                BookEvent::Cancelled {
+                    order_id: o(54),
+                    remaining_volume: v(5),
+                    reason: CancelReason::StopReached,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_self_trade_prevention() {
+        let me = UserId::new(7);
+        let book_with_own_ask = || {
+            let mut book = OrderBook::new();
+            book.add_ask(p(10), q(1, 5).with_owner(me));
+            book.add_ask(p(10), q(2, 5));
+            book.add_ask(p(11), q(3, 5));
+            book
+        };
+        let taker = |stp| Taker {
+            order_id: o(100),
+            owner: Some(me),
+            stp: Some(stp),
+        };
+        let st = |id, vol, cancelled| SelfTrade {
+            order_id: o(id),
+            price: p(10),
+            volume: v(vol),
+            cancelled,
+        };
+        let mut fills = Vec::new();
+
+        let mut book = book_with_own_ask();
+        book.execute_market_buy(
+            taker(SelfTradePrevention::CancelNewest),
+            v(8),
+            b(1000),
+            &mut fills,
+        )
+        .filled();
+        assert!(fills.is_empty());
+        assert_eq!(book.self_trades, &[st(100, 8, true)]);
+        assert_eq!(book.ask_volume(), v(15));
+
+        let mut book = book_with_own_ask();
+        book.execute_market_buy(
+            taker(SelfTradePrevention::CancelOldest),
+            v(8),
+            b(1000),
+            &mut fills,
+        )
+        .filled();
+        assert_eq!(fills, &[mm(2, 100, 10, 5), mt(3, 100, 11, 3)]);
+        assert_eq!(book.self_trades, &[st(1, 5, true)]);
+        assert_eq!(book.cancel(o(1)), Cancellation::NotFound);
+        fills.clear();
+
+        let mut book = book_with_own_ask();
+        book.execute_market_buy(
+            taker(SelfTradePrevention::CancelBoth),
+            v(8),
+            b(1000),
+            &mut fills,
+        )
+        .filled();
+        assert!(fills.is_empty());
+        assert_eq!(book.self_trades, &[st(1, 5, true), st(100, 8, true)]);
+        assert_eq!(book.ask_volume(), v(10));
+
+        let mut book = book_with_own_ask();
+        let stp = taker(SelfTradePrevention::DecrementAndCancel);
+        book.execute_market_buy(stp, v(8), b(1000), &mut fills)
+            .filled();
+        assert_eq!(fills, &[mt(2, 100, 10, 3)]);
+        assert_eq!(book.self_trades, &[st(1, 5, true), st(100, 5, false)]);
+        fills.clear();
+        book.self_trades.clear();
+        // the smaller taker goes, the resting order is cut down
+        book.add_ask(p(11), q(4, 5).with_owner(me));
+        book.execute_limit_buy_order(stp, p(11), v(9), GTC, None, None, &mut fills);
+        assert_eq!(fills, &[mm(2, 100, 10, 2), mm(3, 100, 11, 5)]);
+        // both cut at the resting order's level
+        let at_11 = |st: SelfTrade| SelfTrade { price: p(11), ..st };
+        assert_eq!(
+            book.self_trades,
+            &[at_11(st(4, 2, false)), at_11(st(100, 2, true))]
+        );
+        assert_eq!(
+            book.cancel(o(4)),
+            Cancellation::Cancelled {
+                remaining_volume: v(3)
+            }
+        );
+
+        // other users' orders trade as normal
+        fills.clear();
+        let mut book = book_with_own_ask();
+        book.execute_market_buy(o(101), v(6), b(1000), &mut fills)
+            .filled();
+        assert_eq!(fills, &[mm(1, 101, 10, 5), mt(2, 101, 10, 1)]);
+        assert!(book.self_trades.is_empty());
+    }
+
+    #[test]
+    fn test_fill_or_kill_with_self_trade_prevention() {
+        let me = UserId::new(7);
+        let book_with_own_ask = || {
+            let mut book = OrderBook::new();
+            book.add_ask(p(10), q(1, 5));
+            book.add_ask(p(10), q(2, 5).with_owner(me));
+            book.add_ask(p(11), q(3, 5));
+            book
+        };
+        let taker = |stp| Taker {
+            order_id: o(100),
+            owner: Some(me),
+            stp: Some(stp),
+        };
+        let mut fills = Vec::new();
+
+        // there's 15 on the book, but matching would stop at our own order
+        // after only 5
+        for stp in [
+            SelfTradePrevention::CancelNewest,
+            SelfTradePrevention::CancelBoth,
+            SelfTradePrevention::DecrementAndCancel,
+        ] {
+            let mut book = book_with_own_ask();
+            let res =
+                book.execute_limit_buy_order(taker(stp), p(11), v(6), FOK, None, None, &mut fills);
+            assert_eq!(res, LimitOutcome::Killed);
+            assert!(fills.is_empty());
+            assert!(book.self_trades.is_empty());
+            let res =
+                book.execute_limit_buy_order(taker(stp), p(11), v(5), FOK, None, None, &mut fills);
+            assert_eq!(res, LimitOutcome::Filled);
+            fills.clear();
+        }
+
+        // skipping past our own order leaves 10
+        let stp = taker(SelfTradePrevention::CancelOldest);
+        let mut book = book_with_own_ask();
+        let res = book.execute_limit_buy_order(stp, p(11), v(11), FOK, None, None, &mut fills);
+        assert_eq!(res, LimitOutcome::Killed);
+        assert!(fills.is_empty());
+        let res = book.execute_limit_buy_order(stp, p(11), v(10), FOK, None, None, &mut fills);
+        assert_eq!(res, LimitOutcome::Filled);
+        assert_eq!(fills, &[mm(1, 100, 10, 5), mb(3, 100, 11, 5)]);
+    }
+
+    #[test]
+    fn test_self_trade_prevention_events() {
+        let own = |mut order: Order| {
+            order.owner = Some(UserId::new(7));
+            order.stp = Some(SelfTradePrevention::DecrementAndCancel);
+            order
+        };
+        let events = run_orders(vec![
+            own(ols(1, 10, 5)),
+            ols(2, 10, 5),
+            own(olb(3, 10, 3)),
+            own(olb(4, 10, 4)),
+        ]);
+        assert_eq!(
+            events,
+            &[
+                BookEvent::Decremented {
+                    order_id: o(1),
+                    volume: v(3),
+                },
+                BookEvent::Cancelled {
+                    order_id: o(3),
+                    remaining_volume: v(3),
+                    reason: CancelReason::SelfTrade,
+                },
+                BookEvent::Match(mt(2, 4, 10, 2)),
+                BookEvent::Cancelled {
+                    order_id: o(1),
+                    remaining_volume: v(2),
+                    reason: CancelReason::SelfTrade,
+                },
+                BookEvent::Decremented {
+                    order_id: o(4),
+                    volume: v(2),
+                },
+            ]
+        );
     }
 }
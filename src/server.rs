@@ -1,34 +1,66 @@
 use std::{
-    collections::{BTreeMap, HashMap},
-    sync::{Arc, Mutex},
+    collections::{hash_map::Entry, BTreeMap, HashMap},
+    fs::{File, OpenOptions},
+    future::IntoFuture,
+    io::{BufReader, ErrorKind, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use crate::{order_book, OrderId, Price, UserId, Volume};
+use crate::{
+    fees::FeeSchedule,
+    ledger::{read_journal, JournalEntry},
+    market::{MarketSpec, OrderRejection},
+    market_data::{CandleInterval, MarketData, Ticker},
+    order_book::{self, CancelReason},
+    run_account_event_loop,
+    transfers::{Transfer, TransferKind, TransferRejection, TransferStatus},
+    AccountEvent, AccountEventType, AccountOrderType, AccountOutcome, Balance, ClientOrderId,
+    CorrelationId, DecimalError, NewOrder, OpenOrderType, OrderId, Price, Side, Symbol, Trade,
+    TradeId, TransferId, UserId, Volume,
+};
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
-    routing::{get, post},
+    async_trait,
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        FromRequestParts, Path, Query, State,
+    },
+    http::{header::AUTHORIZATION, request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
     Json, Router,
 };
 use crossbeam_channel::{Receiver, Sender};
-use rust_decimal::{prelude::FromPrimitive, Decimal};
+use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
-
-fn start_new_markets(
-    symbols: impl Iterator<Item = TradingPair>,
-) -> BTreeMap<TradingPair, MarketState> {
-    symbols.map(|t| (t, start_market_in_thread())).collect()
-}
+use tokio::sync::{broadcast, mpsc, oneshot};
 
 pub async fn serve() {
-    let app = app(AppState::new());
+    let markets = [
+        TradingPair::new(Currency::USD, Currency::GBP),
+        TradingPair::new(Currency::USD, Currency::EUR),
+    ]
+    .map(|pair| (pair, MarketSpec::default()));
+    let journal = std::path::Path::new("journal.jsonl");
+    let state = Arc::new(AppState::new(markets, Some(journal)).expect("markets can't start"));
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    // only reachable from this host
+    let admin_listener = tokio::net::TcpListener::bind("127.0.0.1:3001")
+        .await
+        .unwrap();
+    let public = axum::serve(listener, app(state.clone())).into_future();
+    let admin = axum::serve(admin_listener, admin_app(state)).into_future();
+    tokio::try_join!(public, admin).unwrap();
 }
 
 #[derive(
     Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, PartialOrd, Ord, derive_more::FromStr,
 )]
+// ISO 4217 codes, as clients send them
+#[allow(clippy::upper_case_acronyms)]
 enum Currency {
     EUR,
     GBP,
@@ -53,6 +85,43 @@ struct TradingPair {
     ask: Currency,
 }
 
+impl From<Currency> for crate::Currency {
+    fn from(currency: Currency) -> Self {
+        crate::Currency::new(match currency {
+            Currency::EUR => "EUR",
+            Currency::GBP => "GBP",
+            Currency::JPY => "JPY",
+            Currency::USD => "USD",
+        })
+    }
+}
+
+// the engine only knows the currencies we gave it
+impl From<crate::Currency> for Currency {
+    fn from(currency: crate::Currency) -> Self {
+        currency
+            .to_string()
+            .parse()
+            .expect("not one of our currencies")
+    }
+}
+
+impl From<Symbol> for TradingPair {
+    fn from(symbol: Symbol) -> Self {
+        TradingPair::new(symbol.base.into(), symbol.quote.into())
+    }
+}
+
+// USD_GBP trades USD, priced in GBP
+impl From<TradingPair> for Symbol {
+    fn from(pair: TradingPair) -> Self {
+        Symbol {
+            base: pair.bid.into(),
+            quote: pair.ask.into(),
+        }
+    }
+}
+
 #[derive(Debug)]
 struct BadTradingPairError;
 
@@ -77,29 +146,23 @@ struct MarketSnapshot {
     book: order_book::OrderBook,
 }
 
-struct UserState {
-    open_orders: Vec<OrderId>,
-    balances: HashMap<Currency, Volume>,
-}
-
-#[derive(Default)]
-struct UserStates {
-    states: HashMap<UserId, UserState>,
-}
-
 struct MarketState {
-    volume_24h: f64,
+    spec: MarketSpec,
     // TODO - better to have a RWLock?
     latest_snapshot: Mutex<MarketSnapshot>,
+    // orders go through the account engine, this is just for snapshots
     order_tx: Sender<order_book::Order>,
     snapshot_rx: Receiver<order_book::OrderBook>,
+    feed: Arc<MarketFeed>,
 }
 
 impl MarketState {
     fn update_snapshot(&self) {
         self.order_tx
             .send(order_book::Order {
-                id: 0xbeef.into(),
+                id: OrderId::NONE,
+                owner: None,
+                stp: None,
                 typ: order_book::OrderType::SendSnapshot,
             })
             .unwrap();
@@ -107,53 +170,306 @@ impl MarketState {
         *self.latest_snapshot.lock().unwrap() = MarketSnapshot { book: snapshot };
     }
 
+    // The book as of everything sent to it so far
+    fn current_snapshot(&self) -> std::sync::MutexGuard<'_, MarketSnapshot> {
+        self.update_snapshot();
+        self.latest_snapshot.lock().unwrap()
+    }
+
+    fn ticker(&self, market: TradingPair, now: SystemTime) -> ApiTicker {
+        let ticker = self.feed.data.lock().unwrap().last_24h.ticker(now);
+        self.api_ticker(market, ticker)
+    }
+
+    fn api_ticker(&self, market: TradingPair, ticker: Option<Ticker>) -> ApiTicker {
+        let spec = &self.spec;
+        let price = |price: Price| price.to_decimal(spec.price_decimals);
+        let Some(ticker) = ticker else {
+            return ApiTicker {
+                market,
+                volume: Decimal::ZERO,
+                quote_volume: Decimal::ZERO,
+                trade_count: 0,
+                open: None,
+                high: None,
+                low: None,
+                last: None,
+                price_change_percent: None,
+            };
+        };
+        let (open, last) = (price(ticker.open), price(ticker.last));
+        ApiTicker {
+            market,
+            volume: ticker.volume.to_decimal(spec.volume_decimals),
+            quote_volume: ticker.quote_volume.to_decimal(spec.quote_decimals()),
+            trade_count: ticker.trade_count,
+            open: Some(open),
+            high: Some(price(ticker.high)),
+            low: Some(price(ticker.low)),
+            last: Some(last),
+            // prices can't be zero, the tick size sees to that
+            price_change_percent: Some(((last - open) / open * Decimal::ONE_HUNDRED).round_dp(2)),
+        }
+    }
+
+    fn api_trade(&self, trade: Trade) -> ApiTrade {
+        ApiTrade {
+            trade_id: trade.id,
+            price: trade.price.to_decimal(self.spec.price_decimals),
+            volume: trade.volume.to_decimal(self.spec.volume_decimals),
+            side: trade.taker_side.into(),
+            timestamp: unix_millis(trade.at),
+        }
+    }
+
     fn latest_snapshot(&self) -> ApiOrderbook {
         let guard = self.latest_snapshot.lock().unwrap();
-        ApiOrderbook::from_order_book(&guard.book)
+        ApiOrderbook::from_order_book(&guard.book, &self.spec)
     }
 
-    fn place_order(&self, order_type: ApiOrderType) -> Result<OrderId, ()> {
-        use order_book::OrderType as O;
+    // Check an order against the market's rules, in the book's units
+    fn account_order_type(
+        &self,
+        order_type: ApiOrderType,
+    ) -> Result<AccountOrderType, OrderRejection> {
+        use AccountOrderType as O;
         use ApiOrderType as A;
         let order_typ = match order_type {
-            A::LimitBuy { price, volume } => O::LimitBuy {
-                price: Price::try_from(price).unwrap(),
-                volume: Volume::try_from(volume).unwrap(),
-            },
-            A::LimitSell { price, volume } => todo!(),
-            A::MarketBuy { volume } => todo!(),
-            A::MarketSell { volume } => O::MarketSell {
-                base_qty: volume.try_into().unwrap(),
-            },
+            A::LimitBuy {
+                price,
+                volume,
+                time_in_force,
+                post_only,
+                display_volume,
+            } => {
+                let (price, volume) = (self.spec.price(price)?, self.spec.volume(volume)?);
+                self.spec.validate_limit(price, volume)?;
+                O::LimitBuy {
+                    price,
+                    volume,
+                    time_in_force: time_in_force.into(),
+                    post_only: post_only_gtc(time_in_force, post_only)?,
+                    display_volume: self.display_volume(display_volume, volume)?,
+                }
+            }
+            A::LimitSell {
+                price,
+                volume,
+                time_in_force,
+                post_only,
+                display_volume,
+            } => {
+                let (price, volume) = (self.spec.price(price)?, self.spec.volume(volume)?);
+                self.spec.validate_limit(price, volume)?;
+                O::LimitSell {
+                    price,
+                    volume,
+                    time_in_force: time_in_force.into(),
+                    post_only: post_only_gtc(time_in_force, post_only)?,
+                    display_volume: self.display_volume(display_volume, volume)?,
+                }
+            }
+            A::MarketBuy { volume } => {
+                let base_qty = self.spec.volume(volume)?;
+                self.spec.validate_volume(base_qty)?;
+                // hold what it costs as the book stands
+                let max_quote = self
+                    .current_snapshot()
+                    .book
+                    .cost_to_buy(base_qty)
+                    .ok_or(OrderRejection::NotionalTooLarge)?;
+                if max_quote == Balance::new(0) {
+                    return Err(OrderRejection::NoLiquidity);
+                }
+                O::MarketBuy {
+                    base_qty,
+                    max_quote,
+                }
+            }
+            A::MarketSell { volume } => {
+                let base_qty = self.spec.volume(volume)?;
+                self.spec.validate_volume(base_qty)?;
+                O::MarketSell { base_qty }
+            }
+            A::MarketBuyQ { amount } => {
+                let quote_qty = self.spec.amount(amount)?;
+                self.spec.validate_amount(quote_qty)?;
+                O::MarketBuyQ { quote_qty }
+            }
+            A::MarketSellQ { amount } => {
+                let quote_qty = self.spec.amount(amount)?;
+                self.spec.validate_amount(quote_qty)?;
+                let max_base = self.current_snapshot().book.volume_to_sell_for(quote_qty);
+                if max_base == Volume::new(0) {
+                    return Err(OrderRejection::NoLiquidity);
+                }
+                O::MarketSellQ {
+                    quote_qty,
+                    max_base,
+                }
+            }
+            A::StopMarket {
+                side,
+                stop_price,
+                volume,
+            } => {
+                let (stop_price, volume) =
+                    (self.spec.price(stop_price)?, self.spec.volume(volume)?);
+                self.spec.validate_limit(stop_price, volume)?;
+                O::StopMarket {
+                    side: side.into(),
+                    stop_price,
+                    volume,
+                }
+            }
+            A::StopLimit {
+                side,
+                stop_price,
+                limit_price,
+                volume,
+            } => {
+                let volume = self.spec.volume(volume)?;
+                let stop_price = self.spec.price(stop_price)?;
+                let limit_price = self.spec.price(limit_price)?;
+                self.spec.validate_limit(stop_price, volume)?;
+                self.spec.validate_limit(limit_price, volume)?;
+                O::StopLimit {
+                    side: side.into(),
+                    stop_price,
+                    limit_price,
+                    volume,
+                }
+            }
         };
-        // TODO lock user balance and get order_id
-        let order_id = 123.into();
+        Ok(order_typ)
+    }
 
-        let order = order_book::Order {
-            id: order_id,
-            typ: order_typ,
+    // icebergs have to show whole lots, and some but not more than all of
+    // `volume`
+    fn display_volume(
+        &self,
+        display_volume: Option<Decimal>,
+        volume: Volume,
+    ) -> Result<Option<Volume>, OrderRejection> {
+        let Some(display_volume) = display_volume else {
+            return Ok(None);
         };
-        self.order_tx.send(order).unwrap();
-        Ok(order_id)
+        let display_volume = self.spec.volume(display_volume)?;
+        if display_volume == Volume::new(0) || display_volume > volume {
+            return Err(OrderRejection::InvalidDisplayVolume);
+        }
+        if !display_volume
+            .inner()
+            .is_multiple_of(self.spec.lot_size.inner())
+        {
+            return Err(OrderRejection::VolumeNotOnLot);
+        }
+        Ok(Some(display_volume))
+    }
+}
+
+// post-only orders only ever rest, so they can't be immediate-or-cancel or
+// fill-or-kill
+fn post_only_gtc(
+    time_in_force: ApiTimeInForce,
+    post_only: Option<ApiPostOnly>,
+) -> Result<Option<order_book::PostOnly>, OrderRejection> {
+    match (time_in_force, post_only) {
+        (ApiTimeInForce::IOC | ApiTimeInForce::FOK, Some(_)) => {
+            Err(OrderRejection::InvalidTimeInForce)
+        }
+        (_, post_only) => Ok(post_only.map(Into::into)),
     }
 }
 
-fn start_market_in_thread() -> MarketState {
+fn start_market_in_thread(
+    spec: MarketSpec,
+    event_tx: Sender<order_book::BookEvent>,
+) -> MarketState {
     let (order_tx, order_rx) = crossbeam_channel::unbounded();
-    let (match_tx, match_rx) = crossbeam_channel::unbounded();
     let (snapshot_tx, snapshot_rx) = crossbeam_channel::unbounded();
+    let (depth_tx, depth_rx) = crossbeam_channel::unbounded();
 
+    let book = order_book::OrderBook::with_increments(spec.tick_size, spec.lot_size);
+    std::thread::spawn(move || {
+        order_book::run_orderbook_event_loop(book, order_rx, event_tx, snapshot_tx, depth_tx);
+    });
+    let feed = Arc::new(MarketFeed::new());
+    let depth_feed = feed.clone();
     std::thread::spawn(move || {
-        order_book::run_orderbook_event_loop(order_rx, match_tx, snapshot_tx);
+        for changes in depth_rx {
+            depth_feed.apply_depth(changes);
+        }
     });
 
     MarketState {
-        volume_24h: 0.0,
+        spec,
         latest_snapshot: Mutex::new(MarketSnapshot {
             book: order_book::OrderBook::default(),
         }),
         order_tx,
         snapshot_rx,
+        feed,
+    }
+}
+
+/// How many updates a market feed holds for subscribers that are behind
+const FEED_CAPACITY: usize = 1024;
+
+/// A market's public data, and a feed of the changes to it. Changes are made
+/// and sent with the data locked, so a subscriber that looks at the data
+/// while subscribing gets every change after what it saw.
+struct MarketFeed {
+    data: Mutex<MarketData>,
+    updates: broadcast::Sender<MarketUpdate>,
+}
+
+#[derive(Clone, Debug)]
+enum MarketUpdate {
+    /// Numbered by the depth's `seq`
+    Depth {
+        seq: u64,
+        changes: Arc<[order_book::LevelChange]>,
+    },
+    /// Numbered by the market data's `trade_seq`
+    Trade { seq: u64, trade: Trade },
+    /// As of the latest trade
+    Ticker(Option<Ticker>),
+}
+
+impl MarketFeed {
+    fn new() -> Self {
+        Self {
+            data: Mutex::default(),
+            updates: broadcast::channel(FEED_CAPACITY).0,
+        }
+    }
+
+    fn record_trade(&self, trade: Trade) {
+        let mut data = self.data.lock().unwrap();
+        data.record(trade);
+        let ticker = data.last_24h.ticker(trade.at);
+        // there may well be no subscribers
+        let seq = data.trade_seq;
+        let _ = self.updates.send(MarketUpdate::Trade { seq, trade });
+        let _ = self.updates.send(MarketUpdate::Ticker(ticker));
+    }
+
+    fn apply_depth(&self, changes: Vec<order_book::LevelChange>) {
+        let mut data = self.data.lock().unwrap();
+        data.depth.apply(&changes);
+        let seq = data.depth.seq;
+        let changes = changes.into();
+        let _ = self.updates.send(MarketUpdate::Depth { seq, changes });
+    }
+
+    /// Look at the data and subscribe to changes to it in one go
+    fn subscribe<T>(
+        &self,
+        look: impl FnOnce(&mut MarketData) -> T,
+    ) -> (T, broadcast::Receiver<MarketUpdate>) {
+        let mut data = self.data.lock().unwrap();
+        (look(&mut data), self.updates.subscribe())
     }
 }
 
@@ -165,49 +481,341 @@ struct ApiOrderbook {
 }
 
 impl ApiOrderbook {
-    fn from_order_book(book: &order_book::OrderBook) -> Self {
-        let bid = book
-            .bid_levels()
-            .map(|(p, l)| {
-                (
-                    Decimal::from_u64(p.inner()).unwrap(),
-                    Decimal::from_u64(l.total_volume().inner()).unwrap(),
-                )
-            })
-            .collect();
-        let ask = book
-            .ask_levels()
-            .map(|(p, l)| {
-                (
-                    Decimal::from_u64(p.inner()).unwrap(),
-                    Decimal::from_u64(l.total_volume().inner()).unwrap(),
-                )
+    fn from_order_book(book: &order_book::OrderBook, spec: &MarketSpec) -> Self {
+        let level = |(p, l): (&Price, &order_book::Level)| {
+            (
+                p.to_decimal(spec.price_decimals),
+                l.total_volume().to_decimal(spec.volume_decimals),
+            )
+        };
+        Self {
+            bid: book.bid_levels().map(level).collect(),
+            ask: book.ask_levels().map(level).collect(),
+        }
+    }
+}
+
+// Requests still waiting on the account engine's verdict
+type Pending = Mutex<HashMap<CorrelationId, oneshot::Sender<AccountOutcome>>>;
+
+/// What a user hears about their own orders and balances
+#[derive(Clone, Debug)]
+enum AccountUpdate {
+    /// Accepted, Fill, Cancelled, Triggered or Balance
+    Outcome(AccountOutcome),
+    /// Orders are refused before the engine as well as by it, so rejections
+    /// come from where the order was placed
+    Rejected {
+        market: TradingPair,
+        client_order_id: Option<ClientOrderId>,
+        reason: OrderRejection,
+    },
+}
+
+/// A feed of updates for each user with someone listening
+#[derive(Default)]
+struct AccountFeeds {
+    feeds: Mutex<HashMap<UserId, broadcast::Sender<AccountUpdate>>>,
+}
+
+impl AccountFeeds {
+    fn subscribe(&self, user_id: UserId) -> broadcast::Receiver<AccountUpdate> {
+        let mut feeds = self.feeds.lock().unwrap();
+        let feed = feeds
+            .entry(user_id)
+            .or_insert_with(|| broadcast::channel(FEED_CAPACITY).0);
+        feed.subscribe()
+    }
+
+    fn publish(&self, user_id: UserId, update: AccountUpdate) {
+        let mut feeds = self.feeds.lock().unwrap();
+        if let Some(feed) = feeds.get(&user_id) {
+            // the last listener has gone away
+            if feed.send(update).is_err() {
+                feeds.remove(&user_id);
+            }
+        }
+    }
+}
+
+/// The HTTP side of the account engine, which runs in its own thread
+struct Engine {
+    tx_acct_event: Sender<AccountEvent>,
+    pending: Arc<Pending>,
+    account_feeds: Arc<AccountFeeds>,
+    next_correlation_id: AtomicU64,
+}
+
+impl Engine {
+    /// Start the engine from `journal`, writing what it posts from there on
+    /// to `journal_out`. The journal's trades go into the market feeds first,
+    /// so their data comes out the same as before the restart.
+    fn start(
+        journal: Vec<JournalEntry>,
+        journal_out: Box<dyn Write + Send>,
+        fee_schedules: HashMap<Symbol, FeeSchedule>,
+        rx_book_events: Receiver<order_book::BookEvent>,
+        tx_orders: HashMap<Symbol, Sender<order_book::Order>>,
+        feeds: HashMap<Symbol, Arc<MarketFeed>>,
+    ) -> Self {
+        for trade in journal.iter().filter_map(|entry| entry.trade) {
+            feeds[&trade.symbol].record_trade(trade);
+        }
+        let (tx_acct_event, rx_acct_event) = crossbeam_channel::unbounded();
+        let (tx_outcome, rx_outcome) = crossbeam_channel::unbounded();
+        std::thread::spawn(move || {
+            run_account_event_loop(
+                journal,
+                journal_out,
+                fee_schedules,
+                rx_acct_event,
+                rx_book_events,
+                tx_orders,
+                tx_outcome,
+            )
+            .expect("can't write the journal");
+        });
+        let pending = Arc::new(Pending::default());
+        let account_feeds = Arc::new(AccountFeeds::default());
+        let waiting = pending.clone();
+        let accounts = account_feeds.clone();
+        std::thread::spawn(move || route_outcomes(rx_outcome, &waiting, &feeds, &accounts));
+        Self {
+            tx_acct_event,
+            pending,
+            account_feeds,
+            next_correlation_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Send `event` to the engine and wait for its verdict
+    async fn request(&self, user_id: UserId, event: AccountEventType) -> AccountOutcome {
+        let correlation_id =
+            CorrelationId::new(self.next_correlation_id.fetch_add(1, Ordering::Relaxed));
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(correlation_id, tx);
+        self.tx_acct_event
+            .send(AccountEvent {
+                correlation_id,
+                user_id,
+                event,
             })
-            .collect();
-        Self { bid, ask }
+            .expect("account engine has shut down");
+        rx.await.expect("account engine has shut down")
+    }
+}
+
+// The first outcome for a request is the engine's verdict on it. Later ones
+// (fills and cancels for an order) have nobody waiting on them. Trades also
+// go to their market's feed, and what happened to a user's orders and
+// balances to theirs - before any verdict, so the user hears of an order
+// before its placement returns.
+fn route_outcomes(
+    rx_outcome: Receiver<AccountOutcome>,
+    pending: &Pending,
+    feeds: &HashMap<Symbol, Arc<MarketFeed>>,
+    accounts: &AccountFeeds,
+) {
+    for outcome in rx_outcome {
+        match outcome {
+            AccountOutcome::Trade { trade, .. } => feeds[&trade.symbol].record_trade(trade),
+            AccountOutcome::Accepted { .. }
+            | AccountOutcome::Fill { .. }
+            | AccountOutcome::Cancelled { .. }
+            | AccountOutcome::Triggered { .. }
+            | AccountOutcome::Balance { .. } => {
+                let update = AccountUpdate::Outcome(outcome.clone());
+                accounts.publish(outcome.user_id(), update);
+            }
+            _ => {}
+        }
+        let waiting = pending.lock().unwrap().remove(&outcome.correlation_id());
+        if let Some(tx) = waiting {
+            // the client may have gone away
+            let _ = tx.send(outcome);
+        }
     }
 }
 
 struct AppState {
-    users: UserStates,
     markets: BTreeMap<TradingPair, MarketState>,
+    // Balances are kept in each currency's smallest unit, which has to be
+    // the same in every market the currency trades in
+    currency_decimals: BTreeMap<Currency, u32>,
+    engine: Engine,
+    // API keys, and the users they act for
+    api_keys: Mutex<HashMap<String, UserId>>,
+}
+
+/// Why the server can't run the markets it was given
+#[derive(Debug, PartialEq, Eq)]
+enum ConfigError {
+    /// The currency has different units in different markets
+    MismatchedDecimals(Currency),
+    /// The journal can't be read back or written to
+    Journal(ErrorKind),
+}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(error: std::io::Error) -> Self {
+        ConfigError::Journal(error.kind())
+    }
 }
 
 impl AppState {
-    fn new() -> Self {
-        Self {
-            users: UserStates::default(),
-            markets: Default::default(),
+    /// Run `markets`, picking up from the journal file at `journal` and
+    /// appending to it from there on. Without one nothing survives a
+    /// restart.
+    fn new(
+        markets: impl IntoIterator<Item = (TradingPair, MarketSpec)>,
+        journal: Option<&std::path::Path>,
+    ) -> Result<Self, ConfigError> {
+        let markets: Vec<_> = markets.into_iter().collect();
+        let mut currency_decimals = BTreeMap::new();
+        for (pair, spec) in &markets {
+            for (currency, decimals) in [
+                (pair.bid, spec.volume_decimals),
+                (pair.ask, spec.quote_decimals()),
+            ] {
+                if *currency_decimals.entry(currency).or_insert(decimals) != decimals {
+                    return Err(ConfigError::MismatchedDecimals(currency));
+                }
+            }
+        }
+        let (journal, journal_out): (_, Box<dyn Write + Send>) = match journal {
+            Some(path) => {
+                let entries = match File::open(path) {
+                    Ok(file) => read_journal(BufReader::new(file))?,
+                    // the first start
+                    Err(error) if error.kind() == ErrorKind::NotFound => Vec::new(),
+                    Err(error) => return Err(error.into()),
+                };
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                (entries, Box::new(file))
+            }
+            None => (Vec::new(), Box::new(std::io::sink())),
+        };
+        // every book reports to the one engine
+        let (event_tx, event_rx) = crossbeam_channel::unbounded();
+        let markets: BTreeMap<_, _> = markets
+            .into_iter()
+            .map(|(pair, spec)| (pair, start_market_in_thread(spec, event_tx.clone())))
+            .collect();
+        let tx_orders = markets
+            .iter()
+            .map(|(&pair, market)| (pair.into(), market.order_tx.clone()))
+            .collect();
+        let feeds = markets
+            .iter()
+            .map(|(&pair, market)| (pair.into(), market.feed.clone()))
+            .collect();
+        let fee_schedules = markets
+            .iter()
+            .map(|(&pair, market)| (pair.into(), market.spec.fees.clone()))
+            .collect();
+        Ok(Self {
+            markets,
+            currency_decimals,
+            engine: Engine::start(
+                journal,
+                journal_out,
+                fee_schedules,
+                event_rx,
+                tx_orders,
+                feeds,
+            ),
+            api_keys: Mutex::default(),
+        })
+    }
+
+    fn api_transfer(&self, transfer: Transfer) -> ApiTransfer {
+        let currency = transfer.currency.into();
+        ApiTransfer {
+            transfer_id: transfer.id,
+            kind: transfer.kind,
+            currency,
+            amount: transfer
+                .amount
+                .to_decimal(self.currency_decimals[&currency]),
+            status: transfer.status,
         }
     }
 }
 
-fn app(state: AppState) -> Router {
+// Stands in for the user on requests custody makes, which aren't for anyone
+const CUSTODY: UserId = UserId::new(0);
+
+// Short keys are too easy to guess
+const MIN_API_KEY_LEN: usize = 32;
+
+impl AppState {
+    /// Let `key` act for `user_id`. A key already granted to someone else
+    /// stays theirs.
+    fn grant_api_key(&self, key: String, user_id: UserId) -> bool {
+        match self.api_keys.lock().unwrap().entry(key) {
+            Entry::Occupied(granted) => *granted.get() == user_id,
+            Entry::Vacant(vacant) => {
+                vacant.insert(user_id);
+                true
+            }
+        }
+    }
+}
+
+/// Who is making the request, by the API key it carries as an
+/// `Authorization: Bearer` token
+struct User(UserId);
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for User {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let key = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok()?.strip_prefix("Bearer "));
+        key.and_then(|key| state.api_keys.lock().unwrap().get(key).copied())
+            .map(User)
+            .ok_or(StatusCode::UNAUTHORIZED)
+    }
+}
+
+fn app(state: Arc<AppState>) -> Router {
     Router::new()
         .route("/markets", get(get_markets))
         .route("/market/:symbol/orderbook", get(get_market_orderbook))
         .route("/market/:symbol/order", post(place_order))
-        .with_state(Arc::new(state))
+        .route(
+            "/market/:symbol/order/:id",
+            delete(cancel_order).patch(amend_order),
+        )
+        .route("/market/:symbol/trades", get(get_market_trades))
+        .route("/market/:symbol/candles", get(get_market_candles))
+        .route("/market/:symbol/ticker", get(get_market_ticker))
+        .route("/tickers", get(get_tickers))
+        .route("/ws/market", get(market_ws))
+        .route("/account/balances", get(get_balances))
+        .route("/account/orders", get(get_open_orders))
+        .route("/account/deposits", post(request_deposit))
+        .route("/account/withdrawals", post(request_withdrawal))
+        .route("/ws/account", get(account_ws))
+        .with_state(state)
+}
+
+/// Endpoints for custody and the exchange's operators. Nothing here checks
+/// who's calling, so it has to be served apart from `app`, somewhere only
+/// they can reach.
+fn admin_app(state: Arc<AppState>) -> Router {
+    Router::new()
+        // a stand-in for a custody backend
+        .route("/admin/transfers/:id", post(update_transfer))
+        .route("/admin/audit", get(get_audit))
+        .route("/admin/api_keys", post(grant_api_key))
+        .with_state(state)
 }
 
 async fn get_markets(state: State<Arc<AppState>>) -> Json<Vec<TradingPair>> {
@@ -230,13 +838,307 @@ async fn get_market_orderbook(
     Ok(Json(book))
 }
 
+/// Trades are paged by id: `after` pages forward from a trade already seen,
+/// `before` pages back through older ones
+#[derive(Serialize, Deserialize, Default)]
+struct ApiTradesQuery {
+    after: Option<u64>,
+    before: Option<u64>,
+    limit: Option<usize>,
+}
+
+const DEFAULT_TRADES_PAGE: usize = 100;
+const MAX_TRADES_PAGE: usize = 1000;
+
+#[serde_with::serde_as]
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct ApiTrade {
+    #[serde_as(as = "serde_with::FromInto<u64>")]
+    trade_id: TradeId,
+    price: Decimal,
+    volume: Decimal,
+    /// The side that took liquidity
+    side: ApiSide,
+    /// Milliseconds since the Unix epoch
+    timestamp: u64,
+}
+
+async fn get_market_trades(
+    state: State<Arc<AppState>>,
+    path: Path<String>,
+    Query(query): Query<ApiTradesQuery>,
+) -> Result<Json<Vec<ApiTrade>>, StatusCode> {
+    let Ok(pair) = path.as_str().parse::<TradingPair>() else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let Some(market) = state.markets.get(&pair) else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_TRADES_PAGE)
+        .min(MAX_TRADES_PAGE);
+    let trades = market.feed.data.lock().unwrap().recent_trades.page(
+        query.after.map(TradeId::new),
+        query.before.map(TradeId::new),
+        limit,
+    );
+    let trades = trades
+        .into_iter()
+        .map(|trade| market.api_trade(trade))
+        .collect();
+    Ok(Json(trades))
+}
+
+/// Trading over the last 24 hours. Prices are missing if there wasn't any.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct ApiTicker {
+    market: TradingPair,
+    volume: Decimal,
+    quote_volume: Decimal,
+    trade_count: usize,
+    open: Option<Decimal>,
+    high: Option<Decimal>,
+    low: Option<Decimal>,
+    last: Option<Decimal>,
+    /// From open to last
+    price_change_percent: Option<Decimal>,
+}
+
+async fn get_market_ticker(
+    state: State<Arc<AppState>>,
+    path: Path<String>,
+) -> Result<Json<ApiTicker>, StatusCode> {
+    let Ok(pair) = path.as_str().parse::<TradingPair>() else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let Some(market) = state.markets.get(&pair) else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    Ok(Json(market.ticker(pair, SystemTime::now())))
+}
+
+async fn get_tickers(state: State<Arc<AppState>>) -> Json<Vec<ApiTicker>> {
+    let now = SystemTime::now();
+    let tickers = state
+        .markets
+        .iter()
+        .map(|(&pair, market)| market.ticker(pair, now))
+        .collect();
+    Json(tickers)
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+enum ApiCandleInterval {
+    #[serde(rename = "1m")]
+    Minute,
+    #[serde(rename = "5m")]
+    FiveMinutes,
+    #[serde(rename = "1h")]
+    Hour,
+    #[serde(rename = "1d")]
+    Day,
+}
+
+impl From<ApiCandleInterval> for CandleInterval {
+    fn from(interval: ApiCandleInterval) -> Self {
+        match interval {
+            ApiCandleInterval::Minute => Self::Minute,
+            ApiCandleInterval::FiveMinutes => Self::FiveMinutes,
+            ApiCandleInterval::Hour => Self::Hour,
+            ApiCandleInterval::Day => Self::Day,
+        }
+    }
+}
+
+/// Candles starting from `from` up to (but not including) `to`, both in
+/// milliseconds since the Unix epoch
+#[derive(Serialize, Deserialize)]
+struct ApiCandlesQuery {
+    interval: ApiCandleInterval,
+    from: Option<u64>,
+    to: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct ApiCandle {
+    /// Milliseconds since the Unix epoch
+    start: u64,
+    open: Decimal,
+    high: Decimal,
+    low: Decimal,
+    close: Decimal,
+    volume: Decimal,
+}
+
+fn from_unix_millis(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+async fn get_market_candles(
+    state: State<Arc<AppState>>,
+    path: Path<String>,
+    Query(query): Query<ApiCandlesQuery>,
+) -> Result<Json<Vec<ApiCandle>>, StatusCode> {
+    let Ok(pair) = path.as_str().parse::<TradingPair>() else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let Some(market) = state.markets.get(&pair) else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let interval = CandleInterval::from(query.interval);
+    let candles = market.feed.data.lock().unwrap().candles[&interval].range(
+        query.from.map(from_unix_millis),
+        query.to.map(from_unix_millis),
+    );
+    let spec = &market.spec;
+    let price = |price: Price| price.to_decimal(spec.price_decimals);
+    let candles = candles
+        .into_iter()
+        .map(|candle| ApiCandle {
+            start: unix_millis(candle.start),
+            open: price(candle.open),
+            high: price(candle.high),
+            low: price(candle.low),
+            close: price(candle.close),
+            volume: candle.volume.to_decimal(spec.volume_decimals),
+        })
+        .collect();
+    Ok(Json(candles))
+}
+
+#[serde_with::serde_as]
+#[derive(Serialize, Deserialize)]
+struct ApiOrder {
+    #[serde(flatten)]
+    typ: ApiOrderType,
+    /// Resubmitting an order with the same id returns the original instead
+    /// of placing it twice
+    #[serde_as(as = "Option<serde_with::FromInto<u64>>")]
+    #[serde(default)]
+    client_order_id: Option<ClientOrderId>,
+    /// Without one the order trades with the user's own orders like anyone
+    /// else's
+    #[serde(default)]
+    self_trade_prevention: Option<ApiSelfTradePrevention>,
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "type")]
 enum ApiOrderType {
-    LimitBuy { price: Decimal, volume: Decimal },
-    LimitSell { price: Decimal, volume: Decimal },
-    MarketBuy { volume: Decimal },
-    MarketSell { volume: Decimal },
+    LimitBuy {
+        price: Decimal,
+        volume: Decimal,
+        #[serde(default)]
+        time_in_force: ApiTimeInForce,
+        post_only: Option<ApiPostOnly>,
+        /// Iceberg orders only show this much of their volume at a time
+        display_volume: Option<Decimal>,
+    },
+    LimitSell {
+        price: Decimal,
+        volume: Decimal,
+        #[serde(default)]
+        time_in_force: ApiTimeInForce,
+        post_only: Option<ApiPostOnly>,
+        /// Iceberg orders only show this much of their volume at a time
+        display_volume: Option<Decimal>,
+    },
+    MarketBuy {
+        volume: Decimal,
+    },
+    MarketSell {
+        volume: Decimal,
+    },
+    /// Spend `amount` of the quote currency on as much as it buys
+    MarketBuyQ {
+        amount: Decimal,
+    },
+    /// Sell as much as it takes to receive `amount` of the quote currency
+    MarketSellQ {
+        amount: Decimal,
+    },
+    /// Goes into the book as a market order once the market trades through
+    /// `stop_price`. Stops the market is already through are cancelled.
+    StopMarket {
+        side: ApiSide,
+        stop_price: Decimal,
+        volume: Decimal,
+    },
+    /// Goes into the book as a limit order at `limit_price` once the market
+    /// trades through `stop_price`
+    StopLimit {
+        side: ApiSide,
+        stop_price: Decimal,
+        limit_price: Decimal,
+        volume: Decimal,
+    },
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug)]
+enum ApiTimeInForce {
+    #[default]
+    GTC,
+    IOC,
+    FOK,
+}
+
+/// What to do if a post-only order would cross the spread
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+enum ApiPostOnly {
+    Reject,
+    Reprice,
+}
+
+/// What to do if an order would trade with one of the user's own resting
+/// orders
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+enum ApiSelfTradePrevention {
+    /// Cancel what's left of the incoming order
+    CancelNewest,
+    /// Cancel the resting order and carry on matching
+    CancelOldest,
+    CancelBoth,
+    /// Take the smaller volume off both, cancelling whichever is left with
+    /// nothing
+    DecrementAndCancel,
+}
+
+impl From<ApiSelfTradePrevention> for order_book::SelfTradePrevention {
+    fn from(stp: ApiSelfTradePrevention) -> Self {
+        match stp {
+            ApiSelfTradePrevention::CancelNewest => Self::CancelNewest,
+            ApiSelfTradePrevention::CancelOldest => Self::CancelOldest,
+            ApiSelfTradePrevention::CancelBoth => Self::CancelBoth,
+            ApiSelfTradePrevention::DecrementAndCancel => Self::DecrementAndCancel,
+        }
+    }
+}
+
+impl From<ApiPostOnly> for order_book::PostOnly {
+    fn from(post_only: ApiPostOnly) -> Self {
+        match post_only {
+            ApiPostOnly::Reject => Self::Reject,
+            ApiPostOnly::Reprice => Self::Reprice,
+        }
+    }
+}
+
+impl From<ApiTimeInForce> for order_book::TimeInForce {
+    fn from(tif: ApiTimeInForce) -> Self {
+        match tif {
+            ApiTimeInForce::GTC => Self::GoodTillCancel,
+            ApiTimeInForce::IOC => Self::ImmediateOrCancel,
+            ApiTimeInForce::FOK => Self::FillOrKill,
+        }
+    }
+}
+
+impl IntoResponse for OrderRejection {
+    fn into_response(self) -> Response {
+        (StatusCode::UNPROCESSABLE_ENTITY, Json(self)).into_response()
+    }
 }
 
 #[serde_with::serde_as]
@@ -249,97 +1151,2028 @@ struct PlacedOrder {
 
 async fn place_order(
     state: State<Arc<AppState>>,
+    User(user_id): User,
     path: Path<String>,
-    Json(order_type): Json<ApiOrderType>,
-) -> Result<Json<PlacedOrder>, StatusCode> {
+    Json(order): Json<ApiOrder>,
+) -> Result<Json<PlacedOrder>, Response> {
     let Ok(pair) = path.as_str().parse::<TradingPair>() else {
-        return Err(StatusCode::NOT_FOUND);
+        return Err(StatusCode::NOT_FOUND.into_response());
     };
     let Some(market) = state.markets.get(&pair) else {
-        return Err(StatusCode::NOT_FOUND);
+        return Err(StatusCode::NOT_FOUND.into_response());
     };
-    let order_id = market.place_order(order_type).unwrap();
-    Ok(Json(PlacedOrder { order_id }))
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use axum_test::TestServer;
-    use Currency::*;
-
-    fn populate_order_book(order_tx: &Sender<order_book::Order>) {
-        use order_book::{Order, OrderType};
-        let order1 = Order {
-            id: 1.into(),
-            typ: OrderType::LimitBuy {
-                price: 99.into(),
-                volume: 10.into(),
-            },
-        };
-        let order2 = Order {
-            id: 2.into(),
-            typ: OrderType::LimitSell {
-                price: 101.into(),
-                volume: 10.into(),
-            },
+    let client_order_id = order.client_order_id;
+    let reject = |reason: OrderRejection| {
+        let update = AccountUpdate::Rejected {
+            market: pair,
+            client_order_id,
+            reason,
         };
-        order_tx.send(order1).unwrap();
-        order_tx.send(order2).unwrap();
+        state.engine.account_feeds.publish(user_id, update);
+        reason.into_response()
+    };
+    let typ = market.account_order_type(order.typ).map_err(reject)?;
+    let order = NewOrder {
+        client_order_id,
+        symbol: pair.into(),
+        typ,
+        stp: order.self_trade_prevention.map(Into::into),
+    };
+    match state
+        .engine
+        .request(user_id, AccountEventType::PlaceOrder(order))
+        .await
+    {
+        AccountOutcome::Accepted { order_id, .. } => Ok(Json(PlacedOrder { order_id })),
+        AccountOutcome::Rejected { reason, .. } => Err(reject(reason.into())),
+        outcome => panic!("unexpected verdict on an order: {outcome:?}"),
     }
+}
 
-    fn server() -> TestServer {
-        let app = app(AppState::new());
-        TestServer::new(app).unwrap()
-    }
+/// Where a resting limit order should move to, and how much of it should be
+/// left open there. No volume cancels it.
+#[derive(Serialize, Deserialize)]
+struct ApiAmendment {
+    price: Decimal,
+    volume: Decimal,
+}
 
-    fn populated_server() -> TestServer {
-        let symbols = ["USD_GBP", "USD_EUR"]
-            .into_iter()
-            .map(|s| s.parse().unwrap());
-        let markets = start_new_markets(symbols);
-        let market = markets.get(&TradingPair::new(USD, GBP)).unwrap();
-        populate_order_book(&market.order_tx);
-        let app = app(AppState::new());
-        TestServer::new(app).unwrap()
+// The book decides what comes of a cancel or amendment, and reports it over
+// the account feed. Here it's only accepted for the book.
+async fn cancel_order(
+    state: State<Arc<AppState>>,
+    User(user_id): User,
+    Path((symbol, order_id)): Path<(String, u64)>,
+) -> Result<Response, Response> {
+    let Ok(pair) = symbol.parse::<TradingPair>() else {
+        return Err(StatusCode::NOT_FOUND.into_response());
+    };
+    if !state.markets.contains_key(&pair) {
+        return Err(StatusCode::NOT_FOUND.into_response());
     }
+    let cancel = AccountEventType::CancelOrder {
+        symbol: pair.into(),
+        order_id: OrderId::new(order_id),
+    };
+    Ok(forwarded(state.engine.request(user_id, cancel).await))
+}
 
-    #[tokio::test]
-    async fn test_get_markets() {
-        let server = server();
-        let pairs: Vec<TradingPair> = server.get("/markets").await.json();
-        assert_eq!(pairs, vec![TradingPair::new(USD, GBP)]);
+async fn amend_order(
+    state: State<Arc<AppState>>,
+    User(user_id): User,
+    Path((symbol, order_id)): Path<(String, u64)>,
+    Json(amendment): Json<ApiAmendment>,
+) -> Result<Response, Response> {
+    let Ok(pair) = symbol.parse::<TradingPair>() else {
+        return Err(StatusCode::NOT_FOUND.into_response());
+    };
+    let Some(market) = state.markets.get(&pair) else {
+        return Err(StatusCode::NOT_FOUND.into_response());
+    };
+    let spec = &market.spec;
+    let new_price = spec.price(amendment.price).map_err(|r| r.into_response())?;
+    let new_volume = spec
+        .volume(amendment.volume)
+        .map_err(|r| r.into_response())?;
+    if new_volume != Volume::new(0) {
+        spec.validate_limit(new_price, new_volume)
+            .map_err(|r| r.into_response())?;
     }
+    let amend = AccountEventType::AmendOrder {
+        symbol: pair.into(),
+        order_id: OrderId::new(order_id),
+        new_price,
+        new_volume,
+    };
+    Ok(forwarded(state.engine.request(user_id, amend).await))
+}
 
-    #[tokio::test]
-    async fn test_get_missing_market_404() {
-        let server = server();
-        let code = server.get("/market/USDXYZ/orderbook").await.status_code();
-        assert_eq!(code, StatusCode::NOT_FOUND);
-        let code = server.get("/market/USD_GBP/orderbook").await.status_code();
-        assert_eq!(code, StatusCode::NOT_FOUND);
+fn forwarded(verdict: AccountOutcome) -> Response {
+    match verdict {
+        AccountOutcome::Forwarded { .. } => StatusCode::ACCEPTED.into_response(),
+        AccountOutcome::Rejected { reason, .. } => match OrderRejection::from(reason) {
+            OrderRejection::UnknownOrder => StatusCode::NOT_FOUND.into_response(),
+            rejection => rejection.into_response(),
+        },
+        outcome => panic!("unexpected verdict on a change to an order: {outcome:?}"),
     }
+}
 
-    #[tokio::test]
-    async fn test_get_market_orderbook() {
-        let server = populated_server();
-        let book: ApiOrderbook = server.get("/market/USD_GBP/orderbook").await.json();
-        assert_eq!(book.bid.len(), 1);
-        assert_eq!(book.ask.len(), 1);
-    }
+#[derive(Serialize, Deserialize)]
+struct ApiTransferRequest {
+    currency: Currency,
+    amount: Decimal,
+}
 
-    #[tokio::test]
-    async fn test_place_order() {
-        let server = server();
-        let order = ApiOrderType::LimitBuy {
-            price: Decimal::from(100),
-            volume: Decimal::from(500),
+#[serde_with::serde_as]
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct ApiTransfer {
+    #[serde_as(as = "serde_with::FromInto<u64>")]
+    transfer_id: TransferId,
+    kind: TransferKind,
+    currency: Currency,
+    amount: Decimal,
+    status: TransferStatus,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ApiTransferUpdate {
+    status: TransferStatus,
+}
+
+impl IntoResponse for TransferRejection {
+    fn into_response(self) -> Response {
+        let code = match self {
+            TransferRejection::UnknownTransfer => StatusCode::NOT_FOUND,
+            TransferRejection::InvalidTransferUpdate => StatusCode::CONFLICT,
+            _ => StatusCode::UNPROCESSABLE_ENTITY,
+        };
+        (code, Json(self)).into_response()
+    }
+}
+
+async fn request_deposit(
+    state: State<Arc<AppState>>,
+    User(user_id): User,
+    Json(request): Json<ApiTransferRequest>,
+) -> Result<Json<ApiTransfer>, TransferRejection> {
+    request_transfer(&state, user_id, TransferKind::Deposit, request).await
+}
+
+async fn request_withdrawal(
+    state: State<Arc<AppState>>,
+    User(user_id): User,
+    Json(request): Json<ApiTransferRequest>,
+) -> Result<Json<ApiTransfer>, TransferRejection> {
+    request_transfer(&state, user_id, TransferKind::Withdrawal, request).await
+}
+
+async fn request_transfer(
+    state: &AppState,
+    user_id: UserId,
+    kind: TransferKind,
+    request: ApiTransferRequest,
+) -> Result<Json<ApiTransfer>, TransferRejection> {
+    let Some(&decimals) = state.currency_decimals.get(&request.currency) else {
+        return Err(TransferRejection::UnknownCurrency);
+    };
+    let balance = Balance::from_decimal(request.amount, decimals)
+        .map_err(|error| TransferRejection::InvalidAmount { error })?;
+    if balance == Balance::new(0) {
+        let error = DecimalError::Zero;
+        return Err(TransferRejection::InvalidAmount { error });
+    }
+    let currency = request.currency.into();
+    let event = match kind {
+        TransferKind::Deposit => AccountEventType::Deposit { currency, balance },
+        TransferKind::Withdrawal => AccountEventType::Withdraw { currency, balance },
+    };
+    let outcome = state.engine.request(user_id, event).await;
+    transfer_verdict(state, outcome)
+}
+
+async fn update_transfer(
+    state: State<Arc<AppState>>,
+    Path(transfer_id): Path<u64>,
+    Json(update): Json<ApiTransferUpdate>,
+) -> Result<Json<ApiTransfer>, TransferRejection> {
+    let event = AccountEventType::UpdateTransfer {
+        transfer_id: TransferId::new(transfer_id),
+        status: update.status,
+    };
+    let outcome = state.engine.request(CUSTODY, event).await;
+    transfer_verdict(&state, outcome)
+}
+
+/// An API key for a user. Whoever issues keys generates them, and grants
+/// them again after a restart - they aren't kept anywhere else.
+#[serde_with::serde_as]
+#[derive(Serialize, Deserialize)]
+struct ApiKeyGrant {
+    key: String,
+    #[serde_as(as = "serde_with::FromInto<u64>")]
+    user_id: UserId,
+}
+
+async fn grant_api_key(state: State<Arc<AppState>>, Json(grant): Json<ApiKeyGrant>) -> StatusCode {
+    // custody doesn't act through keys
+    if grant.key.len() < MIN_API_KEY_LEN || grant.user_id == CUSTODY {
+        return StatusCode::UNPROCESSABLE_ENTITY;
+    }
+    if state.grant_api_key(grant.key, grant.user_id) {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::CONFLICT
+    }
+}
+
+/// Whether the ledger checks out, and what the engine couldn't settle
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct ApiAudit {
+    /// Why the ledger doesn't check out, if it doesn't
+    ledger_error: Option<String>,
+    faults: Vec<String>,
+}
+
+async fn get_audit(state: State<Arc<AppState>>) -> Json<ApiAudit> {
+    let outcome = state.engine.request(CUSTODY, AccountEventType::Audit).await;
+    let AccountOutcome::Audit { ledger, faults, .. } = outcome else {
+        panic!("unexpected answer for an audit: {outcome:?}");
+    };
+    Json(ApiAudit {
+        ledger_error: ledger.err().map(|error| error.to_string()),
+        faults: faults.iter().map(ToString::to_string).collect(),
+    })
+}
+
+fn transfer_verdict(
+    state: &AppState,
+    outcome: AccountOutcome,
+) -> Result<Json<ApiTransfer>, TransferRejection> {
+    match outcome {
+        AccountOutcome::Transfer { transfer, .. } => Ok(Json(state.api_transfer(transfer))),
+        AccountOutcome::Rejected { reason, .. } => Err(reason.into()),
+        outcome => panic!("unexpected verdict on a transfer: {outcome:?}"),
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct ApiBalance {
+    currency: Currency,
+    available: Decimal,
+    /// Set aside for open orders and unfinished withdrawals
+    held: Decimal,
+}
+
+async fn get_balances(state: State<Arc<AppState>>, User(user_id): User) -> Json<Vec<ApiBalance>> {
+    let outcome = state
+        .engine
+        .request(user_id, AccountEventType::GetBalances)
+        .await;
+    let AccountOutcome::Balances { balances, .. } = outcome else {
+        panic!("unexpected answer for balances: {outcome:?}");
+    };
+    let balances = balances
+        .into_iter()
+        .map(|balance| {
+            let currency = balance.currency.into();
+            let decimals = state.currency_decimals[&currency];
+            ApiBalance {
+                currency,
+                available: balance.available.to_decimal(decimals),
+                held: balance.held.to_decimal(decimals),
+            }
+        })
+        .collect();
+    Json(balances)
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+enum ApiSide {
+    Buy,
+    Sell,
+}
+
+impl From<ApiSide> for Side {
+    fn from(side: ApiSide) -> Self {
+        match side {
+            ApiSide::Buy => Self::Buy,
+            ApiSide::Sell => Self::Sell,
+        }
+    }
+}
+
+impl From<Side> for ApiSide {
+    fn from(side: Side) -> Self {
+        match side {
+            Side::Buy => Self::Buy,
+            Side::Sell => Self::Sell,
+        }
+    }
+}
+
+#[serde_with::serde_as]
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct ApiOpenOrder {
+    #[serde_as(as = "serde_with::FromInto<u64>")]
+    order_id: OrderId,
+    market: TradingPair,
+    side: ApiSide,
+    order_type: ApiOpenOrderType,
+    /// The limit price, for orders that have one
+    price: Option<Decimal>,
+    /// For stop orders, what the market has to trade through to trigger them
+    stop_price: Option<Decimal>,
+    volume: Decimal,
+    remaining_volume: Decimal,
+    /// Milliseconds since the Unix epoch
+    created_at: u64,
+    /// Milliseconds since the Unix epoch
+    updated_at: u64,
+}
+
+/// Stop orders are only listed until they trigger. A triggered stop-limit
+/// order is a limit order from then on.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+enum ApiOpenOrderType {
+    Limit,
+    StopMarket,
+    StopLimit,
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    since_epoch.as_millis().try_into().unwrap()
+}
+
+async fn get_open_orders(
+    state: State<Arc<AppState>>,
+    User(user_id): User,
+) -> Json<Vec<ApiOpenOrder>> {
+    let outcome = state
+        .engine
+        .request(user_id, AccountEventType::GetOpenOrders)
+        .await;
+    let AccountOutcome::OpenOrders { orders, .. } = outcome else {
+        panic!("unexpected answer for open orders: {outcome:?}");
+    };
+    let orders = orders
+        .into_iter()
+        .map(|order| {
+            let market = TradingPair::from(order.symbol);
+            let spec = &state.markets[&market].spec;
+            let (order_type, price, stop_price) = match order.typ {
+                OpenOrderType::Limit { price } => (ApiOpenOrderType::Limit, Some(price), None),
+                OpenOrderType::StopMarket { stop_price } => {
+                    (ApiOpenOrderType::StopMarket, None, Some(stop_price))
+                }
+                OpenOrderType::StopLimit {
+                    stop_price,
+                    limit_price,
+                } => (
+                    ApiOpenOrderType::StopLimit,
+                    Some(limit_price),
+                    Some(stop_price),
+                ),
+            };
+            let decimal = |price: Price| price.to_decimal(spec.price_decimals);
+            ApiOpenOrder {
+                order_id: order.order_id,
+                market,
+                side: order.side.into(),
+                order_type,
+                price: price.map(decimal),
+                stop_price: stop_price.map(decimal),
+                volume: order.volume.to_decimal(spec.volume_decimals),
+                remaining_volume: order.remaining_volume.to_decimal(spec.volume_decimals),
+                created_at: unix_millis(order.placed_at),
+                updated_at: unix_millis(order.updated_at),
+            }
+        })
+        .collect();
+    Json(orders)
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum ApiChannel {
+    Depth,
+    Trades,
+    Ticker,
+}
+
+/// What market data clients ask for over the websocket
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "op")]
+enum ApiMarketRequest {
+    Subscribe {
+        market: TradingPair,
+        channel: ApiChannel,
+    },
+    Unsubscribe {
+        market: TradingPair,
+        channel: ApiChannel,
+    },
+}
+
+/// What the market data websocket sends. Each subscription starts with a
+/// snapshot. Depth and trade updates are numbered one after another from
+/// the snapshot's `seq`, so a gap means one was missed and it's time to
+/// subscribe again. A subscriber that falls too far behind is sent a fresh
+/// snapshot to start over from.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[serde(tag = "type")]
+enum ApiMarketMessage {
+    DepthSnapshot {
+        market: TradingPair,
+        seq: u64,
+        bid: BTreeMap<Decimal, Decimal>,
+        ask: BTreeMap<Decimal, Decimal>,
+    },
+    /// The new volume at each level that changed - zero if it's gone
+    DepthUpdate {
+        market: TradingPair,
+        seq: u64,
+        bid: BTreeMap<Decimal, Decimal>,
+        ask: BTreeMap<Decimal, Decimal>,
+    },
+    /// The latest trades, oldest first
+    TradesSnapshot {
+        market: TradingPair,
+        seq: u64,
+        trades: Vec<ApiTrade>,
+    },
+    Trade {
+        market: TradingPair,
+        seq: u64,
+        trade: ApiTrade,
+    },
+    /// The whole ticker, every time it changes
+    Ticker {
+        ticker: ApiTicker,
+    },
+    Error {
+        message: String,
+    },
+}
+
+impl MarketState {
+    fn snapshot_message(
+        &self,
+        market: TradingPair,
+        channel: ApiChannel,
+        data: &mut MarketData,
+    ) -> ApiMarketMessage {
+        match channel {
+            ApiChannel::Depth => {
+                let levels = |levels: &BTreeMap<Price, Volume>| {
+                    levels
+                        .iter()
+                        .map(|(&price, &volume)| self.api_level(price, volume))
+                        .collect()
+                };
+                ApiMarketMessage::DepthSnapshot {
+                    market,
+                    seq: data.depth.seq,
+                    bid: levels(&data.depth.bids),
+                    ask: levels(&data.depth.asks),
+                }
+            }
+            ApiChannel::Trades => ApiMarketMessage::TradesSnapshot {
+                market,
+                seq: data.trade_seq,
+                trades: data
+                    .recent_trades
+                    .page(None, None, DEFAULT_TRADES_PAGE)
+                    .into_iter()
+                    .map(|trade| self.api_trade(trade))
+                    .collect(),
+            },
+            ApiChannel::Ticker => ApiMarketMessage::Ticker {
+                ticker: self.api_ticker(market, data.last_24h.ticker(SystemTime::now())),
+            },
+        }
+    }
+
+    fn update_message(
+        &self,
+        market: TradingPair,
+        channel: ApiChannel,
+        update: MarketUpdate,
+    ) -> Option<ApiMarketMessage> {
+        let message = match (channel, update) {
+            (ApiChannel::Depth, MarketUpdate::Depth { seq, changes }) => {
+                let (mut bid, mut ask) = (BTreeMap::new(), BTreeMap::new());
+                for change in changes.iter() {
+                    let levels = match change.side {
+                        Side::Buy => &mut bid,
+                        Side::Sell => &mut ask,
+                    };
+                    let (price, volume) = self.api_level(change.price, change.volume);
+                    levels.insert(price, volume);
+                }
+                ApiMarketMessage::DepthUpdate {
+                    market,
+                    seq,
+                    bid,
+                    ask,
+                }
+            }
+            (ApiChannel::Trades, MarketUpdate::Trade { seq, trade }) => ApiMarketMessage::Trade {
+                market,
+                seq,
+                trade: self.api_trade(trade),
+            },
+            (ApiChannel::Ticker, MarketUpdate::Ticker(ticker)) => ApiMarketMessage::Ticker {
+                ticker: self.api_ticker(market, ticker),
+            },
+            _ => return None,
+        };
+        Some(message)
+    }
+
+    fn api_level(&self, price: Price, volume: Volume) -> (Decimal, Decimal) {
+        (
+            price.to_decimal(self.spec.price_decimals),
+            volume.to_decimal(self.spec.volume_decimals),
+        )
+    }
+}
+
+async fn market_ws(state: State<Arc<AppState>>, ws: WebSocketUpgrade) -> Response {
+    let state = state.0;
+    ws.on_upgrade(|socket| stream_market_data(state, socket))
+}
+
+// Each subscription runs as its own task, sending what it has for the
+// client to the one task that owns the socket
+async fn stream_market_data(state: Arc<AppState>, mut socket: WebSocket) {
+    let (tx, mut rx) = mpsc::channel(FEED_CAPACITY);
+    let mut subscriptions = BTreeMap::new();
+    loop {
+        let message = tokio::select! {
+            request = socket.recv() => {
+                let Some(Ok(request)) = request else {
+                    break;
+                };
+                let Message::Text(request) = request else {
+                    continue;
+                };
+                match serde_json::from_str(&request) {
+                    Ok(ApiMarketRequest::Subscribe { market, channel }) => {
+                        if !state.markets.contains_key(&market) {
+                            ApiMarketMessage::Error {
+                                message: format!("no market {market:?}"),
+                            }
+                        } else {
+                            subscriptions.entry((market, channel)).or_insert_with(|| {
+                                let follow = follow_market(state.clone(), market, channel, tx.clone());
+                                tokio::spawn(follow)
+                            });
+                            continue;
+                        }
+                    }
+                    Ok(ApiMarketRequest::Unsubscribe { market, channel }) => {
+                        if let Some(task) = subscriptions.remove(&(market, channel)) {
+                            task.abort();
+                        }
+                        continue;
+                    }
+                    Err(error) => ApiMarketMessage::Error {
+                        message: error.to_string(),
+                    },
+                }
+            }
+            Some(message) = rx.recv() => message,
         };
+        let message = serde_json::to_string(&message).unwrap();
+        if socket.send(Message::Text(message)).await.is_err() {
+            break;
+        }
+    }
+    for task in subscriptions.values() {
+        task.abort();
+    }
+}
+
+async fn follow_market(
+    state: Arc<AppState>,
+    pair: TradingPair,
+    channel: ApiChannel,
+    tx: mpsc::Sender<ApiMarketMessage>,
+) {
+    let market = &state.markets[&pair];
+    loop {
+        let (snapshot, mut updates) = market
+            .feed
+            .subscribe(|data| market.snapshot_message(pair, channel, data));
+        if tx.send(snapshot).await.is_err() {
+            return;
+        }
+        loop {
+            match updates.recv().await {
+                Ok(update) => {
+                    let Some(message) = market.update_message(pair, channel, update) else {
+                        continue;
+                    };
+                    if tx.send(message).await.is_err() {
+                        return;
+                    }
+                }
+                // fallen behind, so start again from a fresh snapshot
+                Err(broadcast::error::RecvError::Lagged(_)) => break,
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+enum ApiCancelReason {
+    Requested,
+    Expired,
+    Killed,
+    PostOnly,
+    SelfTrade,
+    StopReached,
+    NothingDisplayed,
+}
+
+impl From<CancelReason> for ApiCancelReason {
+    fn from(reason: CancelReason) -> Self {
+        match reason {
+            CancelReason::Requested => Self::Requested,
+            CancelReason::Expired => Self::Expired,
+            CancelReason::Killed => Self::Killed,
+            CancelReason::PostOnly => Self::PostOnly,
+            CancelReason::SelfTrade => Self::SelfTrade,
+            CancelReason::StopReached => Self::StopReached,
+            CancelReason::NothingDisplayed => Self::NothingDisplayed,
+        }
+    }
+}
+
+#[serde_with::serde_as]
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct ApiFill {
+    #[serde_as(as = "serde_with::FromInto<u64>")]
+    order_id: OrderId,
+    market: TradingPair,
+    price: Decimal,
+    volume: Decimal,
+    maker: bool,
+    /// Negative for a rebate
+    fee: Decimal,
+    fee_currency: Currency,
+}
+
+/// What the account websocket sends about the user's own orders and
+/// balances, in the order it happened. Nothing is sent on connecting, so
+/// connect first and then fetch `/account/balances` and `/account/orders`
+/// to have missed nothing. A client that falls too far behind is sent an
+/// error and disconnected.
+#[serde_with::serde_as]
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[serde(tag = "type")]
+enum ApiAccountMessage {
+    Accepted {
+        #[serde_as(as = "serde_with::FromInto<u64>")]
+        order_id: OrderId,
+    },
+    Rejected {
+        market: TradingPair,
+        #[serde_as(as = "Option<serde_with::FromInto<u64>>")]
+        client_order_id: Option<ClientOrderId>,
+        #[serde(flatten)]
+        reason: OrderRejection,
+    },
+    PartiallyFilled(ApiFill),
+    Filled(ApiFill),
+    Cancelled {
+        #[serde_as(as = "serde_with::FromInto<u64>")]
+        order_id: OrderId,
+        market: TradingPair,
+        remaining_volume: Decimal,
+        reason: ApiCancelReason,
+    },
+    /// A stop order has gone into the book
+    Triggered {
+        #[serde_as(as = "serde_with::FromInto<u64>")]
+        order_id: OrderId,
+        market: TradingPair,
+        last_price: Decimal,
+    },
+    /// The whole balance in a currency, every time it changes
+    Balance(ApiBalance),
+    Error {
+        message: String,
+    },
+}
+
+impl AppState {
+    fn api_account_message(&self, update: AccountUpdate) -> ApiAccountMessage {
+        let outcome = match update {
+            AccountUpdate::Outcome(outcome) => outcome,
+            AccountUpdate::Rejected {
+                market,
+                client_order_id,
+                reason,
+            } => {
+                return ApiAccountMessage::Rejected {
+                    market,
+                    client_order_id,
+                    reason,
+                }
+            }
+        };
+        match outcome {
+            AccountOutcome::Accepted { order_id, .. } => ApiAccountMessage::Accepted { order_id },
+            AccountOutcome::Fill {
+                order_id,
+                symbol,
+                price,
+                volume,
+                maker,
+                filled,
+                fee,
+                fee_currency,
+                ..
+            } => {
+                let market = TradingPair::from(symbol);
+                let spec = &self.markets[&market].spec;
+                let fee_currency = fee_currency.into();
+                let fee_decimals = self.currency_decimals[&fee_currency];
+                let fill = ApiFill {
+                    order_id,
+                    market,
+                    price: price.to_decimal(spec.price_decimals),
+                    volume: volume.to_decimal(spec.volume_decimals),
+                    maker,
+                    fee: Decimal::from_i128_with_scale(fee, fee_decimals),
+                    fee_currency,
+                };
+                if filled {
+                    ApiAccountMessage::Filled(fill)
+                } else {
+                    ApiAccountMessage::PartiallyFilled(fill)
+                }
+            }
+            AccountOutcome::Cancelled {
+                order_id,
+                symbol,
+                remaining_volume,
+                reason,
+                ..
+            } => {
+                let market = TradingPair::from(symbol);
+                let spec = &self.markets[&market].spec;
+                ApiAccountMessage::Cancelled {
+                    order_id,
+                    market,
+                    remaining_volume: remaining_volume.to_decimal(spec.volume_decimals),
+                    reason: reason.into(),
+                }
+            }
+            AccountOutcome::Triggered {
+                order_id,
+                symbol,
+                last_price,
+                ..
+            } => {
+                let market = TradingPair::from(symbol);
+                let spec = &self.markets[&market].spec;
+                ApiAccountMessage::Triggered {
+                    order_id,
+                    market,
+                    last_price: last_price.to_decimal(spec.price_decimals),
+                }
+            }
+            AccountOutcome::Balance { balance, .. } => {
+                let currency = balance.currency.into();
+                let decimals = self.currency_decimals[&currency];
+                ApiAccountMessage::Balance(ApiBalance {
+                    currency,
+                    available: balance.available.to_decimal(decimals),
+                    held: balance.held.to_decimal(decimals),
+                })
+            }
+            outcome => panic!("not an account update: {outcome:?}"),
+        }
+    }
+}
+
+async fn account_ws(
+    state: State<Arc<AppState>>,
+    User(user_id): User,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let state = state.0;
+    ws.on_upgrade(move |socket| stream_account(state, user_id, socket))
+}
+
+async fn stream_account(state: Arc<AppState>, user_id: UserId, mut socket: WebSocket) {
+    let mut updates = state.engine.account_feeds.subscribe(user_id);
+    loop {
+        let (message, lagged) = tokio::select! {
+            request = socket.recv() => match request {
+                // there's nothing to ask for, only to listen to
+                Some(Ok(_)) => continue,
+                _ => break,
+            },
+            update = updates.recv() => match update {
+                Ok(update) => (state.api_account_message(update), false),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    let message = ApiAccountMessage::Error {
+                        message: format!("fell {missed} updates behind"),
+                    };
+                    (message, true)
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+        };
+        let message = serde_json::to_string(&message).unwrap();
+        if socket.send(Message::Text(message)).await.is_err() || lagged {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fees::FeeTier;
+    use axum::http::{HeaderName, HeaderValue};
+    use axum_test::TestServer;
+    use std::future::Future;
+    use Currency::*;
+
+    fn server_with(
+        markets: impl IntoIterator<Item = (TradingPair, MarketSpec)>,
+    ) -> (TestServer, Arc<AppState>) {
+        let state = Arc::new(AppState::new(markets, None).unwrap());
+        grant_api_keys(&state);
+        (TestServer::new(app(state.clone())).unwrap(), state)
+    }
+
+    fn api_key(user_id: u64) -> String {
+        format!("test-key-for-user-{user_id:014}")
+    }
+
+    // keys for the users tests act as
+    fn grant_api_keys(state: &AppState) {
+        for user_id in 1..=4 {
+            assert!(state.grant_api_key(api_key(user_id), UserId::new(user_id)));
+        }
+    }
+
+    fn server() -> (TestServer, Arc<AppState>) {
+        server_with([(TradingPair::new(USD, GBP), MarketSpec::default())])
+    }
+
+    // deposit straight through the engine, and have custody complete it
+    async fn deposit(state: &AppState, user_id: u64, currency: Currency, balance: u64) {
+        let deposit = AccountEventType::Deposit {
+            currency: currency.into(),
+            balance: Balance::new(balance),
+        };
+        let outcome = state.engine.request(UserId::new(user_id), deposit).await;
+        let AccountOutcome::Transfer { transfer, .. } = outcome else {
+            panic!("deposit refused: {outcome:?}");
+        };
+        let complete = AccountEventType::UpdateTransfer {
+            transfer_id: transfer.id,
+            status: TransferStatus::Completed,
+        };
+        let outcome = state.engine.request(CUSTODY, complete).await;
+        assert!(matches!(outcome, AccountOutcome::Transfer { .. }));
+    }
+
+    fn user(user_id: u64) -> (HeaderName, HeaderValue) {
+        let token = format!("Bearer {}", api_key(user_id));
+        (AUTHORIZATION, token.parse().unwrap())
+    }
+
+    fn limit_buy(price: &str, volume: &str) -> ApiOrderType {
+        ApiOrderType::LimitBuy {
+            price: price.parse().unwrap(),
+            volume: volume.parse().unwrap(),
+            time_in_force: ApiTimeInForce::GTC,
+            post_only: None,
+            display_volume: None,
+        }
+    }
+
+    fn limit_sell(price: &str, volume: &str) -> ApiOrderType {
+        ApiOrderType::LimitSell {
+            price: price.parse().unwrap(),
+            volume: volume.parse().unwrap(),
+            time_in_force: ApiTimeInForce::GTC,
+            post_only: None,
+            display_volume: None,
+        }
+    }
+
+    async fn place_as(server: &TestServer, user_id: u64, order: &ApiOrderType) -> OrderId {
+        let (name, value) = user(user_id);
+        let response = server
+            .post("/market/USD_GBP/order")
+            .add_header(name, value)
+            .json(order)
+            .await;
+        response.assert_status_ok();
+        response.json::<PlacedOrder>().order_id
+    }
+
+    // a buyer with 1000 GBP and a seller with 1000 USD
+    async fn fund_traders(state: &AppState) {
+        deposit(state, 1, GBP, 1_000_000_000).await;
+        deposit(state, 2, USD, 1_000_000).await;
+    }
+
+    // Funds the traders, has user 2 offer each of `asks` on USD/GBP, then
+    // has user 1 market-buy `volume` from them
+    async fn trade(server: &TestServer, state: &AppState, asks: &[(&str, &str)], volume: &str) {
+        fund_traders(state).await;
+        for &(price, ask) in asks {
+            place_as(server, 2, &limit_sell(price, ask)).await;
+        }
+        let buy = ApiOrderType::MarketBuy {
+            volume: volume.parse().unwrap(),
+        };
+        place_as(server, 1, &buy).await;
+    }
+
+    // Fetch until `done` is happy with what comes back, for what the engine
+    // gets round to in its own time. Gives up after a second and returns the
+    // last fetch, for the test to fail on.
+    async fn wait_for<T, F: Future<Output = T>>(
+        mut fetch: impl FnMut() -> F,
+        done: impl Fn(&T) -> bool,
+    ) -> T {
+        for _ in 0..100 {
+            let fetched = fetch().await;
+            if done(&fetched) {
+                return fetched;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        fetch().await
+    }
+
+    async fn populated_server() -> TestServer {
+        let symbols = ["USD_GBP", "USD_EUR"]
+            .into_iter()
+            .map(|s| (s.parse().unwrap(), MarketSpec::default()));
+        let (server, state) = server_with(symbols);
+        fund_traders(&state).await;
+        place_as(&server, 1, &limit_buy("99", "10")).await;
+        place_as(&server, 2, &limit_sell("101", "10")).await;
+        server
+    }
+
+    #[tokio::test]
+    async fn test_get_markets() {
+        let (server, _) = server();
+        let pairs: Vec<TradingPair> = server.get("/markets").await.json();
+        assert_eq!(pairs, vec![TradingPair::new(USD, GBP)]);
+    }
+
+    #[test]
+    fn test_mismatched_decimals() {
+        let coarse = MarketSpec {
+            volume_decimals: 2,
+            ..MarketSpec::default()
+        };
+        let markets = [
+            (TradingPair::new(USD, GBP), MarketSpec::default()),
+            (TradingPair::new(USD, EUR), coarse),
+        ];
+        assert_eq!(
+            AppState::new(markets, None).err(),
+            Some(ConfigError::MismatchedDecimals(USD))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_missing_market_404() {
+        let (server, _) = server();
+        let code = server.get("/market/USDXYZ/orderbook").await.status_code();
+        assert_eq!(code, StatusCode::NOT_FOUND);
+        let code = server.get("/market/USD_JPY/orderbook").await.status_code();
+        assert_eq!(code, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_get_market_orderbook() {
+        let server = populated_server().await;
+        let book: ApiOrderbook = server.get("/market/USD_GBP/orderbook").await.json();
+        assert_eq!(book.bid.len(), 1);
+        assert_eq!(book.ask.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_place_order() {
+        let (mut server, state) = server();
+        // 100 * 500 at three decimal places each
+        deposit(&state, 1, GBP, 50_000_000_000).await;
+        let (name, value) = user(1);
+        server.add_header(name, value);
         let order: PlacedOrder = server
             .post("market/USD_GBP/order")
-            .json(&order)
+            .json(&limit_buy("100", "500"))
             .await
             .json();
         assert_eq!(order.order_id, 1.into());
+
+        // everything is held by the first order
+        let res = server
+            .post("market/USD_GBP/order")
+            .json(&limit_buy("100", "500"))
+            .await;
+        assert_eq!(res.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            res.json::<OrderRejection>(),
+            OrderRejection::InsufficientBalance
+        );
+    }
+
+    #[tokio::test]
+    async fn test_place_order_retried() {
+        let (mut server, state) = server();
+        deposit(&state, 1, GBP, 50_000_000_000).await;
+        let (name, value) = user(1);
+        server.add_header(name, value);
+        let order = ApiOrder {
+            typ: limit_buy("100", "100"),
+            client_order_id: Some(ClientOrderId::new(42)),
+            self_trade_prevention: None,
+        };
+        let mut placed = Vec::new();
+        for _ in 0..2 {
+            let order: PlacedOrder = server
+                .post("market/USD_GBP/order")
+                .json(&order)
+                .await
+                .json();
+            placed.push(order.order_id);
+        }
+        assert_eq!(placed, [OrderId::new(1), OrderId::new(1)]);
+    }
+
+    #[tokio::test]
+    async fn test_restart() {
+        let path = std::env::temp_dir().join(format!("cambiare-{}.jsonl", std::process::id()));
+        let markets = || [(TradingPair::new(USD, GBP), MarketSpec::default())];
+        let start = || {
+            let state = Arc::new(AppState::new(markets(), Some(&path)).unwrap());
+            grant_api_keys(&state);
+            let mut server = TestServer::new(app(state.clone())).unwrap();
+            let (name, value) = user(1);
+            server.add_header(name, value);
+            (server, state)
+        };
+        let order = ApiOrder {
+            typ: limit_buy("100", "100"),
+            client_order_id: Some(ClientOrderId::new(42)),
+            self_trade_prevention: None,
+        };
+        let (server, state) = start();
+        deposit(&state, 1, GBP, 50_000_000_000).await;
+        let placed: PlacedOrder = server
+            .post("market/USD_GBP/order")
+            .json(&order)
+            .await
+            .json();
+        assert_eq!(placed.order_id, OrderId::new(1));
+
+        // the order is gone with its book, but the deposit and the client
+        // order id are still there
+        let (server, _) = start();
+        let balances: Vec<ApiBalance> = server.get("/account/balances").await.json();
+        let gbp = ApiBalance {
+            currency: GBP,
+            available: "50000".parse().unwrap(),
+            held: Decimal::ZERO,
+        };
+        assert_eq!(balances, [gbp]);
+        let retried: PlacedOrder = server
+            .post("market/USD_GBP/order")
+            .json(&order)
+            .await
+            .json();
+        assert_eq!(retried.order_id, OrderId::new(1));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_place_order_needs_user() {
+        let (server, _) = server();
+        let code = server
+            .post("market/USD_GBP/order")
+            .json(&limit_buy("100", "500"))
+            .await
+            .status_code();
+        assert_eq!(code, StatusCode::UNAUTHORIZED);
+        // naming a user isn't enough, it takes their key
+        let code = server
+            .post("market/USD_GBP/order")
+            .add_header(AUTHORIZATION, HeaderValue::from_static("Bearer 1"))
+            .json(&limit_buy("100", "500"))
+            .await
+            .status_code();
+        assert_eq!(code, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_grant_api_key() {
+        let (server, state) = server();
+        let admin = TestServer::new(admin_app(state)).unwrap();
+        let grant = |key: &str, user_id| ApiKeyGrant {
+            key: key.to_string(),
+            user_id: UserId::new(user_id),
+        };
+        let key = "a-key-nobody-would-guess-for-user-5";
+        admin
+            .post("/admin/api_keys")
+            .json(&grant(key, 5))
+            .await
+            .assert_status(StatusCode::NO_CONTENT);
+        let balances: Vec<ApiBalance> = server
+            .get("/account/balances")
+            .add_header(AUTHORIZATION, format!("Bearer {key}").parse().unwrap())
+            .await
+            .json();
+        assert!(balances.is_empty());
+
+        // keys stay with whoever has them, and have to be long
+        for (grant, code) in [
+            (grant(key, 6), StatusCode::CONFLICT),
+            (grant(&api_key(1), 5), StatusCode::CONFLICT),
+            (grant("short", 5), StatusCode::UNPROCESSABLE_ENTITY),
+            (grant(&api_key(9), 0), StatusCode::UNPROCESSABLE_ENTITY),
+        ] {
+            admin
+                .post("/admin/api_keys")
+                .json(&grant)
+                .await
+                .assert_status(code);
+        }
+        // and custody's endpoints can't grant them publicly
+        server
+            .post("/admin/api_keys")
+            .json(&grant(&api_key(9), 9))
+            .await
+            .assert_status_not_found();
+    }
+
+    #[tokio::test]
+    async fn test_place_order_rejected() {
+        let spec = MarketSpec {
+            // 0.01
+            tick_size: 10.into(),
+            ..MarketSpec::default()
+        };
+        let (mut server, state) = server_with([(TradingPair::new(USD, GBP), spec)]);
+        deposit(&state, 1, GBP, 1_000_000_000).await;
+        let (name, value) = user(1);
+        server.add_header(name, value);
+        let order = |price| limit_buy(price, "5");
+        for (price, reason) in [
+            ("100.005", OrderRejection::PriceNotOnTick),
+            (
+                "100.0001",
+                OrderRejection::InvalidPrice {
+                    error: DecimalError::TooPrecise,
+                },
+            ),
+            (
+                "-1",
+                OrderRejection::InvalidPrice {
+                    error: DecimalError::Negative,
+                },
+            ),
+        ] {
+            let res = server
+                .post("market/USD_GBP/order")
+                .json(&order(price))
+                .await;
+            assert_eq!(res.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+            assert_eq!(res.json::<OrderRejection>(), reason);
+        }
+        for display_volume in ["0", "5.01"] {
+            let iceberg = ApiOrderType::LimitBuy {
+                price: "100.01".parse().unwrap(),
+                volume: "5".parse().unwrap(),
+                time_in_force: ApiTimeInForce::GTC,
+                post_only: None,
+                display_volume: Some(display_volume.parse().unwrap()),
+            };
+            let res = server.post("market/USD_GBP/order").json(&iceberg).await;
+            assert_eq!(res.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+            assert_eq!(
+                res.json::<OrderRejection>(),
+                OrderRejection::InvalidDisplayVolume
+            );
+        }
+        let post_only_ioc = ApiOrderType::LimitBuy {
+            price: "100.01".parse().unwrap(),
+            volume: "5".parse().unwrap(),
+            time_in_force: ApiTimeInForce::IOC,
+            post_only: Some(ApiPostOnly::Reject),
+            display_volume: None,
+        };
+        let res = server
+            .post("market/USD_GBP/order")
+            .json(&post_only_ioc)
+            .await;
+        assert_eq!(res.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            res.json::<OrderRejection>(),
+            OrderRejection::InvalidTimeInForce
+        );
+        // nothing to buy from yet
+        let market_buy = ApiOrderType::MarketBuy {
+            volume: "5".parse().unwrap(),
+        };
+        let res = server.post("market/USD_GBP/order").json(&market_buy).await;
+        assert_eq!(res.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(res.json::<OrderRejection>(), OrderRejection::NoLiquidity);
+        let code = server
+            .post("market/USD_GBP/order")
+            .json(&order("100.01"))
+            .await
+            .status_code();
+        assert_eq!(code, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_place_quote_orders() {
+        let (mut server, state) = server();
+        deposit(&state, 1, GBP, 1_000_000_000).await;
+        let (name, value) = user(1);
+        server.add_header(name, value);
+        let buy = |amount: &str| ApiOrderType::MarketBuyQ {
+            amount: amount.parse().unwrap(),
+        };
+        // quote amounts carry both the price and volume decimals
+        for (amount, reason) in [
+            ("0", OrderRejection::NotionalTooSmall),
+            (
+                "0.0000001",
+                OrderRejection::InvalidAmount {
+                    error: DecimalError::TooPrecise,
+                },
+            ),
+        ] {
+            let res = server.post("market/USD_GBP/order").json(&buy(amount)).await;
+            assert_eq!(res.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+            assert_eq!(res.json::<OrderRejection>(), reason);
+        }
+        server
+            .post("market/USD_GBP/order")
+            .json(&buy("0.000001"))
+            .await
+            .assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn test_transfers() {
+        let (mut server, state) = server();
+        let admin = TestServer::new(admin_app(state)).unwrap();
+        let (name, value) = user(1);
+        server.add_header(name, value);
+        let request = |currency, amount: &str| ApiTransferRequest {
+            currency,
+            amount: amount.parse().unwrap(),
+        };
+        let update = |status| ApiTransferUpdate { status };
+
+        let transfer: ApiTransfer = server
+            .post("/account/deposits")
+            .json(&request(GBP, "12.5"))
+            .await
+            .json();
+        assert_eq!(
+            transfer,
+            ApiTransfer {
+                transfer_id: TransferId::new(1),
+                kind: TransferKind::Deposit,
+                currency: GBP,
+                amount: "12.5".parse().unwrap(),
+                status: TransferStatus::Requested,
+            }
+        );
+        // custody's endpoints aren't public
+        let code = server
+            .post("/admin/transfers/1")
+            .json(&update(TransferStatus::Completed))
+            .await
+            .status_code();
+        assert_eq!(code, StatusCode::NOT_FOUND);
+        for status in [TransferStatus::Pending, TransferStatus::Completed] {
+            let transfer: ApiTransfer = admin
+                .post("/admin/transfers/1")
+                .json(&update(status))
+                .await
+                .json();
+            assert_eq!(transfer.status, status);
+        }
+
+        let res = server
+            .post("/account/withdrawals")
+            .json(&request(GBP, "20"))
+            .await;
+        assert_eq!(res.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            res.json::<TransferRejection>(),
+            TransferRejection::InsufficientBalance
+        );
+        let transfer: ApiTransfer = server
+            .post("/account/withdrawals")
+            .json(&request(GBP, "10"))
+            .await
+            .json();
+        assert_eq!(transfer.status, TransferStatus::Requested);
+        // the withdrawal has 10 locked
+        let code = server
+            .post("/account/withdrawals")
+            .json(&request(GBP, "5"))
+            .await
+            .status_code();
+        assert_eq!(code, StatusCode::UNPROCESSABLE_ENTITY);
+
+        let path = format!("/admin/transfers/{}", transfer.transfer_id);
+        let rejected = admin.post(&path).json(&update(TransferStatus::Rejected));
+        assert_eq!(rejected.await.status_code(), StatusCode::OK);
+        let rejected = admin.post(&path).json(&update(TransferStatus::Rejected));
+        assert_eq!(rejected.await.status_code(), StatusCode::CONFLICT);
+        let missing = admin
+            .post("/admin/transfers/99")
+            .json(&update(TransferStatus::Completed));
+        assert_eq!(missing.await.status_code(), StatusCode::NOT_FOUND);
+
+        for (request, rejection) in [
+            (request(JPY, "1"), TransferRejection::UnknownCurrency),
+            (
+                request(GBP, "0"),
+                TransferRejection::InvalidAmount {
+                    error: DecimalError::Zero,
+                },
+            ),
+            (
+                request(GBP, "0.0000001"),
+                TransferRejection::InvalidAmount {
+                    error: DecimalError::TooPrecise,
+                },
+            ),
+        ] {
+            let res = server.post("/account/deposits").json(&request).await;
+            assert_eq!(res.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+            assert_eq!(res.json::<TransferRejection>(), rejection);
+        }
+
+        // and the books still balance
+        let audit: ApiAudit = admin.get("/admin/audit").await.json();
+        assert_eq!(
+            audit,
+            ApiAudit {
+                ledger_error: None,
+                faults: Vec::new(),
+            }
+        );
+        let code = server.get("/admin/audit").await.status_code();
+        assert_eq!(code, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_and_amend_order() {
+        let (server, state) = server();
+        fund_traders(&state).await;
+        let order_id = place_as(&server, 1, &limit_buy("99", "10")).await;
+        let path = format!("/market/USD_GBP/order/{order_id}");
+
+        let (name, value) = user(1);
+        let get_balances = || {
+            let request = server
+                .get("/account/balances")
+                .add_header(name.clone(), value.clone());
+            async { request.await.json::<Vec<ApiBalance>>() }
+        };
+        let gbp = |available: &str, held: &str| ApiBalance {
+            currency: GBP,
+            available: available.parse().unwrap(),
+            held: held.parse().unwrap(),
+        };
+        assert_eq!(get_balances().await, [gbp("10", "990")]);
+
+        // nobody else can touch it
+        let (other, other_value) = user(2);
+        server
+            .delete(&path)
+            .add_header(other, other_value)
+            .await
+            .assert_status_not_found();
+
+        // moving it down holds less
+        let amendment = ApiAmendment {
+            price: "98".parse().unwrap(),
+            volume: "10".parse().unwrap(),
+        };
+        server
+            .patch(&path)
+            .add_header(name.clone(), value.clone())
+            .json(&amendment)
+            .await
+            .assert_status(StatusCode::ACCEPTED);
+        let balances = wait_for(get_balances, |b| b == &[gbp("20", "980")]).await;
+        assert_eq!(balances, [gbp("20", "980")]);
+
+        // and it can't grow past what the user has
+        let amendment = ApiAmendment {
+            price: "98".parse().unwrap(),
+            volume: "20".parse().unwrap(),
+        };
+        let response = server
+            .patch(&path)
+            .add_header(name.clone(), value.clone())
+            .json(&amendment)
+            .await;
+        response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        response.assert_json(&OrderRejection::InsufficientBalance);
+
+        // cancelling it releases the hold
+        server
+            .delete(&path)
+            .add_header(name.clone(), value.clone())
+            .await
+            .assert_status(StatusCode::ACCEPTED);
+        let balances = wait_for(get_balances, |b| b == &[gbp("1000", "0")]).await;
+        assert_eq!(balances, [gbp("1000", "0")]);
+        server
+            .delete(&path)
+            .add_header(name, value)
+            .await
+            .assert_status_not_found();
+    }
+
+    #[tokio::test]
+    async fn test_self_trade_prevention() {
+        let (server, state) = server();
+        deposit(&state, 1, GBP, 1_000_000_000).await;
+        deposit(&state, 1, USD, 1_000_000).await;
+        place_as(&server, 1, &limit_sell("100", "10")).await;
+
+        // the user's buy runs into their own sell, and goes instead of trading
+        let (name, value) = user(1);
+        let order = ApiOrder {
+            typ: limit_buy("100", "10"),
+            client_order_id: None,
+            self_trade_prevention: Some(ApiSelfTradePrevention::CancelNewest),
+        };
+        server
+            .post("/market/USD_GBP/order")
+            .add_header(name.clone(), value.clone())
+            .json(&order)
+            .await
+            .assert_status_ok();
+
+        let get_orders = || {
+            let request = server
+                .get("/account/orders")
+                .add_header(name.clone(), value.clone());
+            async { request.await.json::<Vec<ApiOpenOrder>>() }
+        };
+        let orders = wait_for(get_orders, |orders| orders.len() == 1).await;
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].side, ApiSide::Sell);
+        assert_eq!(orders[0].remaining_volume, Decimal::from(10));
+        let balances: Vec<ApiBalance> = server
+            .get("/account/balances")
+            .add_header(name, value)
+            .await
+            .json();
+        let balance = |currency, available: &str, held: &str| ApiBalance {
+            currency,
+            available: available.parse().unwrap(),
+            held: held.parse().unwrap(),
+        };
+        assert_eq!(
+            balances,
+            [balance(GBP, "1000", "0"), balance(USD, "990", "10")]
+        );
+        let trades: Vec<ApiTrade> = server.get("/market/USD_GBP/trades").await.json();
+        assert!(trades.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_account_endpoints() {
+        let (server, state) = server();
+        trade(&server, &state, &[("101.5", "10")], "4").await;
+
+        // the fill reaches the engine some time after the order was accepted
+        let (name, value) = user(2);
+        let get_balances = || {
+            let request = server
+                .get("/account/balances")
+                .add_header(name.clone(), value.clone());
+            async { request.await.json::<Vec<ApiBalance>>() }
+        };
+        let balances = wait_for(get_balances, |balances| balances.len() == 2).await;
+        let balance = |currency, available: &str, held: &str| ApiBalance {
+            currency,
+            available: available.parse().unwrap(),
+            held: held.parse().unwrap(),
+        };
+        assert_eq!(
+            balances,
+            [balance(GBP, "406", "0"), balance(USD, "990", "6")]
+        );
+
+        let orders: Vec<ApiOpenOrder> = server
+            .get("/account/orders")
+            .add_header(name, value)
+            .await
+            .json();
+        assert_eq!(orders.len(), 1);
+        let order = &orders[0];
+        assert_eq!(order.market, TradingPair::new(USD, GBP));
+        assert_eq!(order.side, ApiSide::Sell);
+        assert_eq!(order.order_type, ApiOpenOrderType::Limit);
+        assert_eq!(order.price, Some("101.5".parse().unwrap()));
+        assert_eq!(order.stop_price, None);
+        assert_eq!(order.volume, Decimal::from(10));
+        assert_eq!(order.remaining_volume, Decimal::from(6));
+        assert!(order.created_at <= order.updated_at);
+
+        // stops are listed while they wait for the market
+        let (buyer, buyer_value) = user(1);
+        let stops = [
+            ApiOrderType::StopMarket {
+                side: ApiSide::Buy,
+                stop_price: "105".parse().unwrap(),
+                volume: "1".parse().unwrap(),
+            },
+            ApiOrderType::StopLimit {
+                side: ApiSide::Buy,
+                stop_price: "105".parse().unwrap(),
+                limit_price: "106".parse().unwrap(),
+                volume: "2".parse().unwrap(),
+            },
+        ];
+        for stop in &stops {
+            place_as(&server, 1, stop).await;
+        }
+        let orders: Vec<ApiOpenOrder> = server
+            .get("/account/orders")
+            .add_header(buyer, buyer_value)
+            .await
+            .json();
+        let listed: Vec<_> = orders
+            .iter()
+            .map(|order| (order.order_type, order.price, order.stop_price))
+            .collect();
+        let stop = Some("105".parse().unwrap());
+        assert_eq!(
+            listed,
+            [
+                (ApiOpenOrderType::StopMarket, None, stop),
+                (
+                    ApiOpenOrderType::StopLimit,
+                    Some("106".parse().unwrap()),
+                    stop
+                ),
+            ]
+        );
+        assert_eq!(orders[1].side, ApiSide::Buy);
+        assert_eq!(orders[1].remaining_volume, Decimal::from(2));
+
+        // nothing for someone who's never deposited
+        let (name, value) = user(3);
+        let balances: Vec<ApiBalance> = server
+            .get("/account/balances")
+            .add_header(name, value)
+            .await
+            .json();
+        assert!(balances.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_market_trades() {
+        let (server, state) = server();
+        trade(&server, &state, &[("101.5", "10")], "4").await;
+        for (buyer, volume) in [(3, 1), (4, 2)] {
+            deposit(&state, buyer, GBP, 1_000_000_000).await;
+            let buy = ApiOrderType::MarketBuy {
+                volume: Decimal::from(volume),
+            };
+            place_as(&server, buyer, &buy).await;
+        }
+
+        // trades reach the market some time after the orders were accepted
+        let get_trades = || async {
+            server
+                .get("/market/USD_GBP/trades")
+                .await
+                .json::<Vec<ApiTrade>>()
+        };
+        let trades = wait_for(get_trades, |trades| trades.len() == 3).await;
+        let volumes: Vec<_> = trades.iter().map(|t| t.volume).collect();
+        assert_eq!(volumes, [4, 1, 2].map(Decimal::from));
+        assert!(trades.iter().all(|t| t.side == ApiSide::Buy));
+        assert_eq!(trades[0].price, "101.5".parse().unwrap());
+
+        let ids: Vec<_> = trades.iter().map(|t| t.trade_id).collect();
+        let page: Vec<ApiTrade> = server
+            .get("/market/USD_GBP/trades")
+            .add_query_params(ApiTradesQuery {
+                after: Some(ids[0].inner()),
+                limit: Some(1),
+                ..Default::default()
+            })
+            .await
+            .json();
+        assert_eq!(page, &trades[1..2]);
+        let page: Vec<ApiTrade> = server
+            .get("/market/USD_GBP/trades")
+            .add_query_params(ApiTradesQuery {
+                before: Some(ids[2].inner()),
+                ..Default::default()
+            })
+            .await
+            .json();
+        assert_eq!(page, &trades[..2]);
+
+        server
+            .get("/market/USD_JPY/trades")
+            .await
+            .assert_status_not_found();
+    }
+
+    #[tokio::test]
+    async fn test_market_candles() {
+        let (server, state) = server();
+        let market = TradingPair::new(USD, GBP);
+        // trades either side of a midnight, as the engine would report them,
+        // so candles don't depend on when the test runs
+        const DAY_MILLIS: u64 = 24 * 60 * 60 * 1000;
+        let midnight = 19_723 * DAY_MILLIS;
+        let trades = [
+            (midnight - 30_000, 99_000, 3_000),
+            (midnight - 10_000, 101_500, 2_000),
+            (midnight + 10_000, 100_000, 1_000),
+        ];
+        for (id, (at, price, volume)) in (1..).zip(trades) {
+            state.markets[&market].feed.record_trade(Trade {
+                id: TradeId::new(id),
+                symbol: market.into(),
+                price: Price::new(price),
+                volume: Volume::new(volume),
+                taker_side: Side::Buy,
+                at: from_unix_millis(at),
+            });
+        }
+
+        let candles: Vec<ApiCandle> = server
+            .get("/market/USD_GBP/candles")
+            .add_query_param("interval", "1d")
+            .await
+            .json();
+        let price = |price: &str| price.parse().unwrap();
+        assert_eq!(
+            candles,
+            [
+                ApiCandle {
+                    start: midnight - DAY_MILLIS,
+                    open: price("99"),
+                    high: price("101.5"),
+                    low: price("99"),
+                    close: price("101.5"),
+                    volume: Decimal::from(5),
+                },
+                ApiCandle {
+                    start: midnight,
+                    open: price("100"),
+                    high: price("100"),
+                    low: price("100"),
+                    close: price("100"),
+                    volume: Decimal::from(1),
+                },
+            ]
+        );
+
+        // only the last minute before midnight
+        let candles: Vec<ApiCandle> = server
+            .get("/market/USD_GBP/candles")
+            .add_query_param("interval", "1m")
+            .add_query_param("to", midnight)
+            .await
+            .json();
+        let starts: Vec<_> = candles.iter().map(|c| c.start).collect();
+        assert_eq!(starts, [midnight - 60_000]);
+
+        server
+            .get("/market/USD_GBP/candles")
+            .add_query_param("interval", "2m")
+            .await
+            .assert_status_bad_request();
+    }
+
+    #[tokio::test]
+    async fn test_tickers() {
+        let (server, state) = server_with([
+            (TradingPair::new(USD, GBP), MarketSpec::default()),
+            (TradingPair::new(USD, EUR), MarketSpec::default()),
+        ]);
+        trade(&server, &state, &[("100", "2"), ("110", "3")], "5").await;
+
+        let get_ticker = || async {
+            server
+                .get("/market/USD_GBP/ticker")
+                .await
+                .json::<ApiTicker>()
+        };
+        let ticker = wait_for(get_ticker, |ticker| ticker.trade_count == 2).await;
+        let price = |price: &str| Some(price.parse().unwrap());
+        assert_eq!(
+            ticker,
+            ApiTicker {
+                market: TradingPair::new(USD, GBP),
+                volume: Decimal::from(5),
+                quote_volume: Decimal::from(530),
+                trade_count: 2,
+                open: price("100"),
+                high: price("110"),
+                low: price("100"),
+                last: price("110"),
+                price_change_percent: price("10"),
+            }
+        );
+
+        let tickers: Vec<ApiTicker> = server.get("/tickers").await.json();
+        assert_eq!(tickers.len(), 2);
+        assert_eq!(tickers[0].market, TradingPair::new(USD, EUR));
+        assert_eq!(tickers[0].trade_count, 0);
+        assert_eq!(tickers[0].last, None);
+        assert_eq!(tickers[1], ticker);
+
+        server
+            .get("/market/USD_JPY/ticker")
+            .await
+            .assert_status_not_found();
+    }
+
+    type Socket = tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >;
+
+    // websockets need a real connection
+    async fn connect_as(
+        state: Arc<AppState>,
+        path: &str,
+        user_id: Option<u64>,
+    ) -> tokio_tungstenite::tungstenite::Result<Socket> {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app(state)).await.unwrap() });
+        let mut request = format!("ws://{addr}{path}").into_client_request().unwrap();
+        if let Some(user_id) = user_id {
+            let (name, value) = user(user_id);
+            request.headers_mut().insert(name, value);
+        }
+        let (socket, _) = tokio_tungstenite::connect_async(request).await?;
+        Ok(socket)
+    }
+
+    async fn connect(state: Arc<AppState>, path: &str) -> Socket {
+        connect_as(state, path, None).await.unwrap()
+    }
+
+    async fn send<T: Serialize>(socket: &mut Socket, request: &T) {
+        use futures_util::SinkExt;
+        let request = serde_json::to_string(request).unwrap();
+        socket
+            .send(tokio_tungstenite::tungstenite::Message::Text(request))
+            .await
+            .unwrap();
+    }
+
+    async fn receive<T: serde::de::DeserializeOwned>(socket: &mut Socket, count: usize) -> Vec<T> {
+        use futures_util::StreamExt;
+        let mut messages = Vec::new();
+        while messages.len() < count {
+            let message = tokio::time::timeout(std::time::Duration::from_secs(1), socket.next())
+                .await
+                .expect("nothing sent")
+                .unwrap()
+                .unwrap();
+            messages.push(serde_json::from_str(message.to_text().unwrap()).unwrap());
+        }
+        messages
+    }
+
+    async fn place(state: &AppState, user_id: u64, typ: AccountOrderType) {
+        let order = NewOrder {
+            client_order_id: None,
+            symbol: TradingPair::new(USD, GBP).into(),
+            typ,
+            stp: None,
+        };
+        let outcome = state
+            .engine
+            .request(UserId::new(user_id), AccountEventType::PlaceOrder(order))
+            .await;
+        assert!(matches!(outcome, AccountOutcome::Accepted { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_market_ws() {
+        let (_, state) = server();
+        let market = TradingPair::new(USD, GBP);
+        let mut socket = connect(state.clone(), "/ws/market").await;
+        let subscribe = |channel| ApiMarketRequest::Subscribe { market, channel };
+        send(&mut socket, &subscribe(ApiChannel::Depth)).await;
+        let levels = |levels: &[(&str, u64)]| -> BTreeMap<Decimal, Decimal> {
+            levels
+                .iter()
+                .map(|&(price, volume)| (price.parse().unwrap(), Decimal::from(volume)))
+                .collect()
+        };
+        assert_eq!(
+            receive::<ApiMarketMessage>(&mut socket, 1).await,
+            [ApiMarketMessage::DepthSnapshot {
+                market,
+                seq: 0,
+                bid: levels(&[]),
+                ask: levels(&[]),
+            }]
+        );
+
+        fund_traders(&state).await;
+        let sell = AccountOrderType::LimitSell {
+            volume: Volume::new(10_000),
+            price: Price::new(101_500),
+            time_in_force: order_book::TimeInForce::GoodTillCancel,
+            post_only: None,
+            display_volume: None,
+        };
+        place(&state, 2, sell).await;
+        assert_eq!(
+            receive::<ApiMarketMessage>(&mut socket, 1).await,
+            [ApiMarketMessage::DepthUpdate {
+                market,
+                seq: 1,
+                bid: levels(&[]),
+                ask: levels(&[("101.5", 10)]),
+            }]
+        );
+
+        send(&mut socket, &subscribe(ApiChannel::Trades)).await;
+        send(&mut socket, &subscribe(ApiChannel::Ticker)).await;
+        let snapshots: Vec<ApiMarketMessage> = receive(&mut socket, 2).await;
+        assert!(snapshots.contains(&ApiMarketMessage::TradesSnapshot {
+            market,
+            seq: 0,
+            trades: Vec::new(),
+        }));
+        assert!(snapshots
+            .iter()
+            .any(|m| matches!(m, ApiMarketMessage::Ticker { ticker } if ticker.trade_count == 0)));
+
+        let buy = AccountOrderType::MarketBuy {
+            base_qty: Volume::new(4_000),
+            max_quote: Balance::new(500_000_000),
+        };
+        place(&state, 1, buy).await;
+        let updates: Vec<ApiMarketMessage> = receive(&mut socket, 3).await;
+        assert!(updates.contains(&ApiMarketMessage::DepthUpdate {
+            market,
+            seq: 2,
+            bid: levels(&[]),
+            ask: levels(&[("101.5", 6)]),
+        }));
+        assert!(updates.iter().any(|m| matches!(
+            m,
+            ApiMarketMessage::Trade { seq: 1, trade, .. }
+                if trade.volume == Decimal::from(4) && trade.side == ApiSide::Buy
+        )));
+        assert!(updates
+            .iter()
+            .any(|m| matches!(m, ApiMarketMessage::Ticker { ticker } if ticker.trade_count == 1)));
+
+        send(&mut socket, &subscribe(ApiChannel::Depth)).await;
+        let unknown = ApiMarketRequest::Subscribe {
+            market: TradingPair::new(USD, JPY),
+            channel: ApiChannel::Depth,
+        };
+        send(&mut socket, &unknown).await;
+        // already subscribed to the depth, so the error is all that comes back
+        let errors: Vec<ApiMarketMessage> = receive(&mut socket, 1).await;
+        assert!(matches!(errors[0], ApiMarketMessage::Error { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_account_ws() {
+        let market = TradingPair::new(USD, GBP);
+        let tier = FeeTier {
+            min_volume: Balance::new(0),
+            maker_bps: 10,
+            taker_bps: 25,
+        };
+        let spec = MarketSpec {
+            fees: FeeSchedule::new(vec![tier]).unwrap(),
+            ..MarketSpec::default()
+        };
+        let (server, state) = server_with([(market, spec)]);
+        assert!(connect_as(state.clone(), "/ws/account", None)
+            .await
+            .is_err());
+        let mut socket = connect_as(state.clone(), "/ws/account", Some(1))
+            .await
+            .unwrap();
+        let balance = |currency, available: &str, held: &str| {
+            ApiAccountMessage::Balance(ApiBalance {
+                currency,
+                available: available.parse().unwrap(),
+                held: held.parse().unwrap(),
+            })
+        };
+        deposit(&state, 1, GBP, 1_000_000_000).await;
+        assert_eq!(
+            receive::<ApiAccountMessage>(&mut socket, 1).await,
+            [balance(GBP, "1000", "0")]
+        );
+
+        // the other side of the trade is nothing to do with user 1
+        deposit(&state, 2, USD, 1_000_000).await;
+        let sell = AccountOrderType::LimitSell {
+            volume: Volume::new(10_000),
+            price: Price::new(101_500),
+            time_in_force: order_book::TimeInForce::GoodTillCancel,
+            post_only: None,
+            display_volume: None,
+        };
+        place(&state, 2, sell).await;
+        let buy = AccountOrderType::MarketBuy {
+            base_qty: Volume::new(4_000),
+            max_quote: Balance::new(500_000_000),
+        };
+        place(&state, 1, buy).await;
+        let messages: Vec<ApiAccountMessage> = receive(&mut socket, 5).await;
+        let ApiAccountMessage::Accepted { order_id } = messages[0] else {
+            panic!("not accepted first: {messages:?}");
+        };
+        // a market buy holds what it might cost until it knows what it does
+        assert_eq!(messages[1], balance(GBP, "500", "500"));
+        let ApiAccountMessage::Filled(fill) = &messages[2] else {
+            panic!("not filled: {messages:?}");
+        };
+        assert_eq!(fill.order_id, order_id);
+        assert_eq!(fill.market, market);
+        assert_eq!(fill.price, "101.5".parse().unwrap());
+        assert_eq!(fill.volume, Decimal::from(4));
+        assert!(!fill.maker);
+        // the taker pays 25bps of the 4 it bought
+        assert_eq!(fill.fee, "0.01".parse().unwrap());
+        assert_eq!(fill.fee_currency, USD);
+        assert_eq!(
+            messages[3..],
+            [balance(USD, "3.99", "0"), balance(GBP, "594", "0")]
+        );
+
+        let (name, value) = user(1);
+        server
+            .post("/market/USD_GBP/order")
+            .add_header(name, value)
+            .json(&ApiOrder {
+                typ: limit_buy("100", "100"),
+                client_order_id: Some(ClientOrderId::new(7)),
+                self_trade_prevention: None,
+            })
+            .await
+            .assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            receive::<ApiAccountMessage>(&mut socket, 1).await,
+            [ApiAccountMessage::Rejected {
+                market,
+                client_order_id: Some(ClientOrderId::new(7)),
+                reason: OrderRejection::InsufficientBalance,
+            }]
+        );
+
+        // a stop goes into the book once the market trades through it
+        let stop = AccountOrderType::StopLimit {
+            side: Side::Buy,
+            stop_price: Price::new(101_600),
+            limit_price: Price::new(100_000),
+            volume: Volume::new(1_000),
+        };
+        place(&state, 1, stop).await;
+        let messages: Vec<ApiAccountMessage> = receive(&mut socket, 2).await;
+        let ApiAccountMessage::Accepted { order_id } = messages[0] else {
+            panic!("not accepted first: {messages:?}");
+        };
+        assert_eq!(messages[1], balance(GBP, "494", "100"));
+        let sell = AccountOrderType::LimitSell {
+            volume: Volume::new(1_000),
+            price: Price::new(102_000),
+            time_in_force: order_book::TimeInForce::GoodTillCancel,
+            post_only: None,
+            display_volume: None,
+        };
+        place(&state, 2, sell).await;
+        deposit(&state, 3, GBP, 1_000_000_000).await;
+        let buy = AccountOrderType::MarketBuy {
+            base_qty: Volume::new(7_000),
+            max_quote: Balance::new(1_000_000_000),
+        };
+        place(&state, 3, buy).await;
+        assert_eq!(
+            receive::<ApiAccountMessage>(&mut socket, 1).await,
+            [ApiAccountMessage::Triggered {
+                order_id,
+                market,
+                last_price: Decimal::from(102),
+            }]
+        );
     }
 }
@@ -0,0 +1,88 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{Balance, Currency, DecimalError, RejectReason, TransferId, UserId};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TransferKind {
+    Deposit,
+    Withdrawal,
+}
+
+/// Where a deposit or withdrawal has got to. Users request them, custody
+/// moves them along from there.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TransferStatus {
+    /// Asked for by the user. Funds being withdrawn are locked from here on.
+    Requested,
+    /// Custody has picked it up, but it isn't final yet
+    Pending,
+    Completed,
+    Rejected,
+}
+
+impl TransferStatus {
+    pub fn can_become(self, next: TransferStatus) -> bool {
+        use TransferStatus::*;
+        matches!(
+            (self, next),
+            (Requested, Pending) | (Requested | Pending, Completed | Rejected)
+        )
+    }
+}
+
+/// Money moving between a user's account and the outside world
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Transfer {
+    pub id: TransferId,
+    pub user_id: UserId,
+    pub kind: TransferKind,
+    pub currency: Currency,
+    pub amount: Balance,
+    pub status: TransferStatus,
+}
+
+/// Why a transfer couldn't be requested or moved along
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(tag = "reason")]
+pub enum TransferRejection {
+    UnknownCurrency,
+    InvalidAmount {
+        error: DecimalError,
+    },
+    InsufficientBalance,
+    UnknownTransfer,
+    /// The transfer can't go from its current status to the one asked for
+    InvalidTransferUpdate,
+}
+
+impl From<RejectReason> for TransferRejection {
+    fn from(reason: RejectReason) -> Self {
+        match reason {
+            RejectReason::InsufficientBalance => Self::InsufficientBalance,
+            RejectReason::UnknownTransfer => Self::UnknownTransfer,
+            RejectReason::InvalidTransferUpdate => Self::InvalidTransferUpdate,
+            RejectReason::UnknownMarket
+            | RejectReason::NotionalTooLarge
+            | RejectReason::UnknownOrder
+            | RejectReason::NotAmendable => {
+                unreachable!("transfers aren't made on a market")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_transitions() {
+        use TransferStatus::*;
+        assert!(Requested.can_become(Pending));
+        assert!(Requested.can_become(Completed));
+        assert!(Pending.can_become(Rejected));
+        assert!(!Pending.can_become(Requested));
+        assert!(!Completed.can_become(Rejected));
+        assert!(!Rejected.can_become(Rejected));
+    }
+}